@@ -1,6 +1,8 @@
 use ppaass_common::config::{
-    ConnectionPoolConfig, RetrieveConnectionConfig, RetrieveConnectionPoolConfig,
-    RetrieveServerConfig,
+    CompressionConfig, ConnectionPoolConfig, DeadlineConfig, HappyEyeballsConfig,
+    ObfuscationConfig, ProxySelectorConfig, RekeyConfig, RetrieveConnectionConfig,
+    RetrieveConnectionPoolConfig, RetrieveRelayConfig, RetrieveServerConfig, RsaPoolConfig,
+    ServerListenAddress,
 };
 use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
@@ -18,8 +20,34 @@ pub struct AgentConfig {
     pub proxy_to_agent_data_relay_buffer_size: usize,
     pub proxy_frame_buffer_size: usize,
     pub proxy_connect_timeout: u64,
+    /// The seconds without data in both directions before a relay is closed, 0 to disable
+    #[serde(default = "default_relay_idle_timeout")]
+    pub relay_idle_timeout: u64,
     pub user_info_repository_refresh_interval: u64,
     pub connection_pool: Option<ConnectionPoolConfig>,
+    #[serde(default)]
+    pub proxy_selector: ProxySelectorConfig,
+    #[serde(default)]
+    pub happy_eyeballs: HappyEyeballsConfig,
+    /// The deadlines of the handshake and the tunnel init with the proxy
+    #[serde(default)]
+    pub deadline: DeadlineConfig,
+    /// Request the obfuscation to the proxy in the handshake
+    #[serde(default)]
+    pub obfuscation: ObfuscationConfig,
+    /// Request the compression to the proxy in the handshake
+    #[serde(default)]
+    pub compression: CompressionConfig,
+    /// Request the rekey to the proxy in the handshake
+    #[serde(default)]
+    pub rekey: RekeyConfig,
+    /// Run the RSA operations of the handshakes with the proxy on the blocking threads
+    #[serde(default)]
+    pub rsa_pool: RsaPoolConfig,
+}
+
+fn default_relay_idle_timeout() -> u64 {
+    300
 }
 
 impl RetrieveConnectionConfig for AgentConfig {
@@ -29,15 +57,48 @@ impl RetrieveConnectionConfig for AgentConfig {
     fn connect_timeout(&self) -> u64 {
         self.proxy_connect_timeout
     }
+    fn happy_eyeballs(&self) -> &HappyEyeballsConfig {
+        &self.happy_eyeballs
+    }
+    fn deadline(&self) -> &DeadlineConfig {
+        &self.deadline
+    }
+    fn obfuscation(&self) -> &ObfuscationConfig {
+        &self.obfuscation
+    }
+    fn compression(&self) -> &CompressionConfig {
+        &self.compression
+    }
+    fn rekey(&self) -> &RekeyConfig {
+        &self.rekey
+    }
+}
+
+impl RetrieveRelayConfig for AgentConfig {
+    fn relay_idle_timeout(&self) -> u64 {
+        self.relay_idle_timeout
+    }
 }
 
 impl RetrieveConnectionPoolConfig for AgentConfig {
-    fn max_pool_size(&self) -> usize {
+    fn min_idle(&self) -> usize {
+        match self.connection_pool {
+            Some(ref pool) => pool.min_idle(),
+            None => 0,
+        }
+    }
+    fn max_idle(&self) -> usize {
         match self.connection_pool {
-            Some(ref pool) => pool.max_pool_size(),
+            Some(ref pool) => pool.max_idle(),
             None => 0,
         }
     }
+    fn target_hit_rate(&self) -> f64 {
+        match self.connection_pool {
+            Some(ref pool) => pool.target_hit_rate(),
+            None => 0f64,
+        }
+    }
     fn fill_interval(&self) -> u64 {
         match self.connection_pool {
             Some(ref pool) => pool.fill_interval(),
@@ -62,10 +123,22 @@ impl RetrieveConnectionPoolConfig for AgentConfig {
             None => 0,
         }
     }
-    fn retake_interval(&self) -> u64 {
+    fn max_take_wait(&self) -> u64 {
         match self.connection_pool {
-            Some(ref pool) => pool.retake_interval(),
-            None => 2,
+            Some(ref pool) => pool.max_take_wait(),
+            None => 0,
+        }
+    }
+    fn max_fill_backoff(&self) -> u64 {
+        match self.connection_pool {
+            Some(ref pool) => pool.max_fill_backoff(),
+            None => 0,
+        }
+    }
+    fn take_check_threshold(&self) -> u64 {
+        match self.connection_pool {
+            Some(ref pool) => pool.take_check_threshold(),
+            None => 0,
         }
     }
 }
@@ -74,10 +147,10 @@ impl RetrieveServerConfig for AgentConfig {
     fn worker_thread_number(&self) -> usize {
         self.worker_thread_number
     }
-    fn server_port(&self) -> u16 {
-        self.server_port
-    }
-    fn ip_v6(&self) -> bool {
-        self.ip_v6
+    fn listen_addresses(&self) -> Vec<ServerListenAddress> {
+        vec![ServerListenAddress::unspecified(
+            self.ip_v6,
+            self.server_port,
+        )]
     }
 }
@@ -2,46 +2,15 @@ mod config;
 mod error;
 mod tunnel;
 pub use config::AgentConfig;
-use ppaass_common::config::RetrieveServerConfig;
+use ppaass_common::compression::CompressionCounter;
 use ppaass_common::error::CommonError;
-use ppaass_common::server::{CommonServer, Server, ServerListener, ServerState};
+use ppaass_common::rsa_pool::RsaPool;
+use ppaass_common::server::{consume_server_events, create_server_listeners, Server, ServerState};
 use ppaass_common::user::UserInfoRepository;
-use ppaass_common::ProxyTcpConnectionPool;
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use ppaass_common::{ProxyServerSelector, ProxyTcpConnectionPool};
 use std::sync::Arc;
-use tokio::net::TcpListener;
-use tracing::{debug, info};
+use tracing::info;
 use tunnel::handle_client_connection;
-async fn create_server_listener<T: RetrieveServerConfig>(
-    config: Arc<T>,
-) -> Result<ServerListener, CommonError> {
-    if config.ip_v6() {
-        debug!(
-            "Starting server listener with IPv6 on port: {}",
-            config.server_port()
-        );
-        Ok(ServerListener::TcpListener(
-            TcpListener::bind(SocketAddr::new(
-                IpAddr::V6(Ipv6Addr::UNSPECIFIED),
-                config.server_port(),
-            ))
-            .await?,
-        ))
-    } else {
-        debug!(
-            "Starting server listener with IPv4 on port: {}",
-            config.server_port()
-        );
-        Ok(ServerListener::TcpListener(
-            TcpListener::bind(SocketAddr::new(
-                IpAddr::V4(Ipv4Addr::UNSPECIFIED),
-                config.server_port(),
-            ))
-            .await?,
-        ))
-    }
-}
-
 pub async fn start_server<T>(config: Arc<AgentConfig>, user_repo: Arc<T>) -> Result<(), CommonError>
 where
     T: UserInfoRepository + Send + Sync + 'static,
@@ -59,14 +28,38 @@ where
     };
     info!("Start agent server with username: {}", &username);
     server_state.add_value((username.clone(), user_info.clone()));
+    let compression_counter = Arc::new(CompressionCounter::new("proxy"));
+    if config.compression.enabled {
+        compression_counter.start_log_stats(config.compression.stats_interval);
+    }
+    server_state.add_value(compression_counter.clone());
+    let rsa_pool = Arc::new(RsaPool::new(config.rsa_pool));
+    rsa_pool.start_log_stats();
+    server_state.add_value(rsa_pool.clone());
+    let proxy_server_selector = Arc::new(ProxyServerSelector::new(config.proxy_selector.clone()));
+    server_state.add_value(proxy_server_selector.clone());
+    let mut connection_pool = None;
     if config.connection_pool.is_some() {
-        let proxy_tcp_connection_pool =
-            ProxyTcpConnectionPool::new(config.clone(), &username, user_info.clone()).await?;
-        server_state.add_value(Arc::new(proxy_tcp_connection_pool));
+        let proxy_tcp_connection_pool = ProxyTcpConnectionPool::new(
+            config.clone(),
+            username,
+            user_info.clone(),
+            proxy_server_selector,
+            rsa_pool,
+            compression_counter,
+        )
+        .await?;
+        let proxy_tcp_connection_pool = Arc::new(proxy_tcp_connection_pool);
+        server_state.add_value(proxy_tcp_connection_pool.clone());
+        connection_pool = Some(proxy_tcp_connection_pool);
+    }
+    let (server, server_guard) = Server::new(config.clone(), server_state);
+    if let Some(connection_pool) = connection_pool {
+        connection_pool.start_publish_events(server.connection_pool_event_sender());
     }
-    let server = CommonServer::new(config.clone(), server_state);
+    consume_server_events(server_guard);
     server
-        .run(create_server_listener, handle_client_connection)
+        .run(create_server_listeners, handle_client_connection)
         .await?;
     Ok(())
 }
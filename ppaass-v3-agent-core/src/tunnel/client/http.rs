@@ -1,23 +1,20 @@
-use crate::config::AgentConfig;
+use crate::tunnel::client::init_proxy_tunnel;
 use http_body_util::combinators::BoxBody;
-use http_body_util::{BodyExt, Empty};
+use http_body_util::{BodyExt, Empty, Full};
 use hyper::body::{Bytes, Incoming};
 use hyper::client::conn::http1::Builder;
 use hyper::server::conn::http1;
 use hyper::service::service_fn;
-use hyper::{Method, Request, Response};
+use hyper::{Method, Request, Response, StatusCode};
 use hyper_util::rt::TokioIo;
-use ppaass_common::config::RetrieveConnectionConfig;
+use ppaass_common::config::{RetrieveConnectionConfig, RetrieveRelayConfig};
 use ppaass_common::error::CommonError;
+use ppaass_common::relay::{copy_bidirectional_with_idle_timeout, DEFAULT_RELAY_BUFFER_SIZE};
 use ppaass_common::server::ServerState;
 use ppaass_common::user::UserInfo;
-use ppaass_common::{
-    FramedConnection, ProxyTcpConnectionNewState, ProxyTcpConnectionPool, TunnelInitRequest,
-    UnifiedAddress,
-};
+use ppaass_common::{TunnelInitFailureReason, TunnelInitRequest, UnifiedAddress};
 use std::net::SocketAddr;
 use std::sync::Arc;
-use tokio::io::copy_bidirectional;
 use tokio::net::TcpStream;
 use tokio::sync::RwLock;
 use tower::ServiceBuilder;
@@ -28,7 +25,59 @@ fn success_empty_body() -> BoxBody<Bytes, hyper::Error> {
         .boxed()
 }
 
-async fn client_http_request_handler<T: RetrieveConnectionConfig>(
+/// Answer the client with 403, 504 or 502 when the tunnel can not be initialized
+fn tunnel_init_failure_response(error: &CommonError) -> Response<BoxBody<Bytes, hyper::Error>> {
+    let (status, message) = match error {
+        CommonError::TunnelInitRejected(reason) => match reason {
+            TunnelInitFailureReason::AuthenticateFail => {
+                (StatusCode::FORBIDDEN, "Proxy authentication failed")
+            }
+            TunnelInitFailureReason::DestinationBlocked => {
+                (StatusCode::FORBIDDEN, "Destination blocked by proxy")
+            }
+            TunnelInitFailureReason::ForbiddenByPolicy => {
+                (StatusCode::FORBIDDEN, "Destination forbidden by policy")
+            }
+            TunnelInitFailureReason::QuotaExceeded => (StatusCode::FORBIDDEN, "Quota exceeded"),
+            TunnelInitFailureReason::ConnectionLimitExceeded => {
+                (StatusCode::TOO_MANY_REQUESTS, "Too many connections")
+            }
+            TunnelInitFailureReason::UserExpired => (StatusCode::FORBIDDEN, "User expired"),
+            TunnelInitFailureReason::ConnectTimeout => {
+                (StatusCode::GATEWAY_TIMEOUT, "Destination connect timeout")
+            }
+            TunnelInitFailureReason::DnsResolveFail => {
+                (StatusCode::BAD_GATEWAY, "Fail to resolve destination")
+            }
+            TunnelInitFailureReason::ConnectionRefused => {
+                (StatusCode::BAD_GATEWAY, "Destination refused connection")
+            }
+            TunnelInitFailureReason::DestinationUnreachable => {
+                (StatusCode::BAD_GATEWAY, "Destination unreachable")
+            }
+            TunnelInitFailureReason::InitWithDestinationFail => {
+                (StatusCode::BAD_GATEWAY, "Fail to connect destination")
+            }
+        },
+        CommonError::Timeout(_)
+        | CommonError::ProxyConnectionUnavailable(_)
+        | CommonError::HandshakeRequestTimeout(..)
+        | CommonError::HandshakeResponseTimeout(..)
+        | CommonError::TunnelInitTimeout(..) => {
+            (StatusCode::GATEWAY_TIMEOUT, "Proxy connect timeout")
+        }
+        _ => (StatusCode::BAD_GATEWAY, "Fail to connect proxy"),
+    };
+    let mut response = Response::new(
+        Full::new(Bytes::from(format!("{message}\n")))
+            .map_err(|never| match never {})
+            .boxed(),
+    );
+    *response.status_mut() = status;
+    response
+}
+
+async fn client_http_request_handler<T: RetrieveConnectionConfig + RetrieveRelayConfig>(
     config: &T,
     username: &str,
     user_info: &UserInfo,
@@ -55,28 +104,28 @@ async fn client_http_request_handler<T: RetrieveConnectionConfig>(
     debug!(
         "Receive client http request to destination: {destination_address:?}, client socket address: {client_socket_addr}"
     );
-    let proxy_tcp_connection_pool =
-        server_state.get_value::<Arc<ProxyTcpConnectionPool<AgentConfig>>>();
-    let proxy_tcp_connection = match proxy_tcp_connection_pool {
-        None => {
-            FramedConnection::<ProxyTcpConnectionNewState>::create(
-                username,
-                user_info,
-                config.frame_size(),
-                config.connect_timeout(),
-            )
-            .await?
+    let tunnel_init_request = TunnelInitRequest {
+        destination_address,
+        keep_alive: false,
+    };
+    let mut proxy_tcp_connection = match init_proxy_tunnel(
+        config,
+        username,
+        user_info,
+        &server_state,
+        tunnel_init_request,
+    )
+    .await
+    {
+        Ok(proxy_tcp_connection) => proxy_tcp_connection,
+        Err(e) => {
+            error!(
+                "Fail to initialize tunnel for client [{client_socket_addr}] to destination [{destination_uri}]: {e:?}"
+            );
+            return Ok(tunnel_init_failure_response(&e));
         }
-        Some(pool) => pool.take_proxy_connection().await?,
     };
 
-    let mut proxy_tcp_connection = proxy_tcp_connection
-        .tunnel_init(TunnelInitRequest {
-            destination_address,
-            keep_alive: false,
-        })
-        .await?;
-
     if Method::CONNECT == client_http_request.method() {
         // Received an HTTP request like:
         // ```
@@ -92,6 +141,7 @@ async fn client_http_request_handler<T: RetrieveConnectionConfig>(
         // connection be upgraded, so we can't return a response inside
         // `on_upgrade` future.
 
+        let relay_idle_timeout = config.relay_idle_timeout();
         tokio::task::spawn(async move {
             match hyper::upgrade::on(client_http_request).await {
                 Err(e) => {
@@ -102,18 +152,25 @@ async fn client_http_request_handler<T: RetrieveConnectionConfig>(
                     // Connect to remote server
                     let mut upgraded_client_io = TokioIo::new(upgraded_client_io);
                     // Proxying data
-                    let (from_client, from_proxy) = match copy_bidirectional(
+                    let (relay_result, relay_error) = copy_bidirectional_with_idle_timeout(
                         &mut upgraded_client_io,
                         &mut proxy_tcp_connection,
+                        DEFAULT_RELAY_BUFFER_SIZE,
+                        DEFAULT_RELAY_BUFFER_SIZE,
+                        relay_idle_timeout,
                     )
-                    .await
-                    {
-                        Err(e) => {
-                            error!("Fail to proxy data between agent and proxy: {e:?}");
-                            return;
-                        }
-                        Ok((from_client, from_proxy)) => (from_client, from_proxy),
-                    };
+                    .await;
+                    if let Some(e) = relay_error {
+                        error!("Fail to proxy data between agent and proxy: {e:?}");
+                        return;
+                    }
+                    if relay_result.idle_timeout {
+                        info!(
+                            "Close tunnel of client [{client_socket_addr}] because of no data in {relay_idle_timeout} seconds"
+                        );
+                    }
+                    let (from_client, from_proxy) =
+                        (relay_result.a_data_size, relay_result.b_data_size);
 
                     // Print message when done
                     info!(
@@ -144,7 +201,7 @@ async fn client_http_request_handler<T: RetrieveConnectionConfig>(
     }
 }
 
-pub async fn http_protocol_proxy<T: RetrieveConnectionConfig>(
+pub async fn http_protocol_proxy<T: RetrieveConnectionConfig + RetrieveRelayConfig>(
     client_tcp_stream: TcpStream,
     client_socket_addr: SocketAddr,
     config: &T,
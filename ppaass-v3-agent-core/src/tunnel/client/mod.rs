@@ -1,6 +1,66 @@
 mod http;
 mod socks5;
 
+use crate::config::AgentConfig;
 pub use http::*;
-
+use ppaass_common::compression::CompressionCounter;
+use ppaass_common::config::RetrieveConnectionConfig;
+use ppaass_common::error::CommonError;
+use ppaass_common::rsa_pool::RsaPool;
+use ppaass_common::server::ServerState;
+use ppaass_common::user::UserInfo;
+use ppaass_common::{
+    CryptoLengthDelimitedFramed, FramedConnection, ProxyServerSelector, ProxyTcpConnectionNewState,
+    ProxyTcpConnectionPool, TunnelInitRequest,
+};
 pub use socks5::*;
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tokio_util::bytes::BytesMut;
+use tokio_util::io::{SinkWriter, StreamReader};
+
+type ProxyTunnelConnection =
+    FramedConnection<SinkWriter<StreamReader<CryptoLengthDelimitedFramed<TcpStream>, BytesMut>>>;
+
+/// Initialize the tunnel on a proxy connection, the proxy connection is taken
+/// from the pool when there is one, otherwise a new one is created.
+async fn init_proxy_tunnel<T: RetrieveConnectionConfig>(
+    config: &T,
+    username: &str,
+    user_info: &UserInfo,
+    server_state: &ServerState,
+    tunnel_init_request: TunnelInitRequest,
+) -> Result<ProxyTunnelConnection, CommonError> {
+    let proxy_tcp_connection_pool =
+        server_state.get_value::<Arc<ProxyTcpConnectionPool<AgentConfig>>>();
+    match proxy_tcp_connection_pool {
+        None => {
+            let proxy_server_selector = server_state
+                .get_value::<Arc<ProxyServerSelector>>()
+                .ok_or(CommonError::Other(
+                    "Can not get proxy server selector".to_owned(),
+                ))?;
+            let compression_counter =
+                server_state
+                    .get_value::<Arc<CompressionCounter>>()
+                    .ok_or(CommonError::Other(
+                        "Can not get compression counter".to_owned(),
+                    ))?;
+            let rsa_pool = server_state
+                .get_value::<Arc<RsaPool>>()
+                .ok_or(CommonError::Other("Can not get RSA pool".to_owned()))?;
+            FramedConnection::<ProxyTcpConnectionNewState>::create(
+                username,
+                user_info,
+                proxy_server_selector,
+                rsa_pool,
+                compression_counter,
+                config,
+            )
+            .await?
+            .tunnel_init(tunnel_init_request)
+            .await
+        }
+        Some(pool) => pool.tunnel_init(tunnel_init_request).await,
+    }
+}
@@ -1,12 +1,10 @@
-use crate::config::AgentConfig;
-use ppaass_common::config::RetrieveConnectionConfig;
+use crate::tunnel::client::init_proxy_tunnel;
+use ppaass_common::config::{RetrieveConnectionConfig, RetrieveRelayConfig};
 use ppaass_common::error::CommonError;
+use ppaass_common::relay::{copy_bidirectional_with_idle_timeout, DEFAULT_RELAY_BUFFER_SIZE};
 use ppaass_common::server::ServerState;
 use ppaass_common::user::UserInfo;
-use ppaass_common::{
-    FramedConnection, ProxyTcpConnectionNewState, ProxyTcpConnectionPool, TunnelInitRequest,
-    UnifiedAddress,
-};
+use ppaass_common::{TunnelInitFailureReason, TunnelInitRequest, UnifiedAddress};
 use socks5_impl::protocol::handshake::Request as Socks5HandshakeRequest;
 use socks5_impl::protocol::handshake::Response as Socks5HandshakeResponse;
 use socks5_impl::protocol::{Address, AsyncStreamOperation, AuthMethod, Reply};
@@ -15,11 +13,33 @@ use socks5_impl::protocol::{
 };
 use std::net::SocketAddr;
 use std::sync::Arc;
-use tokio::io::copy_bidirectional;
 use tokio::net::TcpStream;
 use tokio::sync::RwLock;
 use tracing::{debug, error, info};
-pub async fn socks5_protocol_proxy<T: RetrieveConnectionConfig>(
+/// The socks5 reply to the client when the tunnel can not be initialized
+fn tunnel_init_failure_reply(error: &CommonError) -> Reply {
+    match error {
+        CommonError::TunnelInitRejected(reason) => match reason {
+            TunnelInitFailureReason::AuthenticateFail
+            | TunnelInitFailureReason::DestinationBlocked
+            | TunnelInitFailureReason::ForbiddenByPolicy
+            | TunnelInitFailureReason::QuotaExceeded
+            | TunnelInitFailureReason::ConnectionLimitExceeded
+            | TunnelInitFailureReason::UserExpired => Reply::ConnectionNotAllowed,
+            TunnelInitFailureReason::DnsResolveFail
+            | TunnelInitFailureReason::ConnectTimeout
+            | TunnelInitFailureReason::DestinationUnreachable => Reply::HostUnreachable,
+            TunnelInitFailureReason::ConnectionRefused => Reply::ConnectionRefused,
+            TunnelInitFailureReason::InitWithDestinationFail => Reply::GeneralFailure,
+        },
+        CommonError::HandshakeRequestTimeout(..)
+        | CommonError::HandshakeResponseTimeout(..)
+        | CommonError::TunnelInitTimeout(..) => Reply::HostUnreachable,
+        _ => Reply::GeneralFailure,
+    }
+}
+
+pub async fn socks5_protocol_proxy<T: RetrieveConnectionConfig + RetrieveRelayConfig>(
     mut client_tcp_stream: TcpStream,
     client_socket_addr: SocketAddr,
     config: &T,
@@ -41,22 +61,6 @@ pub async fn socks5_protocol_proxy<T: RetrieveConnectionConfig>(
     match init_request.command {
         Socks5InitCommand::Connect => {
             debug!("Receive socks5 CONNECT command: {client_socket_addr}");
-            let proxy_tcp_connection_pool =
-                server_state.get_value::<Arc<ProxyTcpConnectionPool<AgentConfig>>>();
-            let proxy_tcp_connection = match proxy_tcp_connection_pool {
-                None => {
-                    let user_info = user_info.read().await;
-                    FramedConnection::<ProxyTcpConnectionNewState>::create(
-                        username,
-                        &user_info,
-                        config.frame_size(),
-                        config.connect_timeout(),
-                    )
-                    .await?
-                }
-                Some(pool) => pool.take_proxy_connection().await?,
-            };
-
             let destination_address = match &init_request.address {
                 Address::SocketAddress(dst_addr) => dst_addr.into(),
                 Address::DomainAddress(host, port) => UnifiedAddress::Domain {
@@ -64,13 +68,34 @@ pub async fn socks5_protocol_proxy<T: RetrieveConnectionConfig>(
                     port: *port,
                 },
             };
-
-            let mut proxy_tcp_connection = proxy_tcp_connection
-                .tunnel_init(TunnelInitRequest {
-                    destination_address,
-                    keep_alive: false,
-                })
-                .await?;
+            let tunnel_init_request = TunnelInitRequest {
+                destination_address,
+                keep_alive: false,
+            };
+            let proxy_tunnel_result = {
+                let user_info = user_info.read().await;
+                init_proxy_tunnel(
+                    config,
+                    username,
+                    &user_info,
+                    &server_state,
+                    tunnel_init_request,
+                )
+                .await
+            };
+            let mut proxy_tcp_connection = match proxy_tunnel_result {
+                Ok(proxy_tcp_connection) => proxy_tcp_connection,
+                Err(e) => {
+                    let init_response = Socks5InitResponse::new(
+                        tunnel_init_failure_reply(&e),
+                        init_request.address,
+                    );
+                    init_response
+                        .write_to_async_stream(&mut client_tcp_stream)
+                        .await?;
+                    return Err(e);
+                }
+            };
 
             let init_response = Socks5InitResponse::new(Reply::Succeeded, init_request.address);
             init_response
@@ -78,14 +103,25 @@ pub async fn socks5_protocol_proxy<T: RetrieveConnectionConfig>(
                 .await?;
 
             // Proxying data
-            let (from_client, from_proxy) =
-                match copy_bidirectional(&mut client_tcp_stream, &mut proxy_tcp_connection).await {
-                    Err(e) => {
-                        error!("Fail to proxy data between agent and proxy: {e:?}");
-                        return Ok(());
-                    }
-                    Ok((from_client, from_proxy)) => (from_client, from_proxy),
-                };
+            let (relay_result, relay_error) = copy_bidirectional_with_idle_timeout(
+                &mut client_tcp_stream,
+                &mut proxy_tcp_connection,
+                DEFAULT_RELAY_BUFFER_SIZE,
+                DEFAULT_RELAY_BUFFER_SIZE,
+                config.relay_idle_timeout(),
+            )
+            .await;
+            if let Some(e) = relay_error {
+                error!("Fail to proxy data between agent and proxy: {e:?}");
+                return Ok(());
+            }
+            if relay_result.idle_timeout {
+                info!(
+                    "Close tunnel of client [{client_socket_addr}] because of no data in {} seconds",
+                    config.relay_idle_timeout()
+                );
+            }
+            let (from_client, from_proxy) = (relay_result.a_data_size, relay_result.b_data_size);
             info!(
                 "Agent wrote {} bytes to proxy, received {} bytes from proxy",
                 from_client, from_proxy
@@ -93,12 +129,22 @@ pub async fn socks5_protocol_proxy<T: RetrieveConnectionConfig>(
         }
         Socks5InitCommand::Bind => {
             debug!("Receive socks5 BIND command: {client_socket_addr:?}");
+            let init_response =
+                Socks5InitResponse::new(Reply::CommandNotSupported, init_request.address);
+            init_response
+                .write_to_async_stream(&mut client_tcp_stream)
+                .await?;
             return Err(CommonError::Other(format!(
                 "Unsupported socks5 bind command: {client_socket_addr}"
             )));
         }
         Socks5InitCommand::UdpAssociate => {
             debug!("Receive socks5 UDP ASSOCIATE command: {client_socket_addr:?}");
+            let init_response =
+                Socks5InitResponse::new(Reply::CommandNotSupported, init_request.address);
+            init_response
+                .write_to_async_stream(&mut client_tcp_stream)
+                .await?;
             return Err(CommonError::Other(format!(
                 "Unsupported socks5 udp associate command: {client_socket_addr}"
             )));
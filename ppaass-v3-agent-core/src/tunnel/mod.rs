@@ -1,6 +1,6 @@
 mod client;
 pub use client::*;
-use ppaass_common::config::RetrieveConnectionConfig;
+use ppaass_common::config::{RetrieveConnectionConfig, RetrieveRelayConfig};
 use ppaass_common::error::CommonError;
 use ppaass_common::server::ServerState;
 use ppaass_common::user::UserInfo;
@@ -11,7 +11,7 @@ use tracing::{debug, error};
 const SOCKS5_VERSION: u8 = 0x05;
 const SOCKS4_VERSION: u8 = 0x04;
 
-pub async fn handle_client_connection<T: RetrieveConnectionConfig>(
+pub async fn handle_client_connection<T: RetrieveConnectionConfig + RetrieveRelayConfig>(
     config: Arc<T>,
     server_state: Arc<ServerState>,
     client_tcp_stream: TcpStream,
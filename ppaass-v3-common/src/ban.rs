@@ -0,0 +1,220 @@
+use crate::config::BanConfig;
+use crate::event::{LogEvent, LogEventLevel};
+use chrono::Local;
+use ipnet::{IpNet, Ipv4Net, Ipv6Net};
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::Sender;
+use tokio::time::sleep;
+use tracing::{error, info};
+
+/// The sources tracked at most, the sources without recent failures
+/// are removed when the limit is reached
+const MAX_TRACKED_SOURCES: usize = 65536;
+
+#[derive(Default)]
+struct SourceFailures {
+    failure_times: VecDeque<Instant>,
+    banned_until: Option<Instant>,
+}
+
+/// Count the handshake failures of the source addresses in a sliding window
+/// and ban the address for a while when there are too many. The IPv6 source
+/// addresses are grouped by the configured prefix, so a host can not escape
+/// the ban by rotating the addresses of its prefix.
+pub struct BanTracker {
+    config: BanConfig,
+    sources: Mutex<HashMap<IpNet, SourceFailures>>,
+    log_event_sender: OnceLock<Sender<LogEvent>>,
+}
+
+impl BanTracker {
+    pub fn new(config: BanConfig) -> Self {
+        Self {
+            config,
+            sources: Mutex::new(HashMap::new()),
+            log_event_sender: OnceLock::new(),
+        }
+    }
+
+    /// The ban and unban events are sent to the server log events
+    pub fn set_log_event_sender(&self, log_event_sender: Sender<LogEvent>) {
+        let _ = self.log_event_sender.set(log_event_sender);
+    }
+
+    fn is_allowed(&self, ip_address: IpAddr) -> bool {
+        self.config
+            .allow_networks
+            .iter()
+            .any(|network| network.contains(&ip_address))
+    }
+
+    /// The source the failures of the address are counted on, the IPv4-mapped
+    /// IPv6 address is tracked as IPv4 address
+    fn source(&self, ip_address: IpAddr) -> IpNet {
+        match ip_address.to_canonical() {
+            IpAddr::V4(ip_address) => IpNet::V4(Ipv4Net::from(ip_address)),
+            IpAddr::V6(ip_address) => IpNet::V6(
+                Ipv6Net::new(ip_address, self.config.ipv6_prefix_length)
+                    .map(|source| source.trunc())
+                    .unwrap_or(Ipv6Net::from(ip_address)),
+            ),
+        }
+    }
+
+    pub fn is_banned(&self, ip_address: IpAddr) -> bool {
+        if !self.config.enabled {
+            return false;
+        }
+        let source = self.source(ip_address);
+        let sources = match self.sources.lock() {
+            Ok(sources) => sources,
+            Err(e) => {
+                error!("Fail to lock ban tracker: {e:?}");
+                return false;
+            }
+        };
+        sources
+            .get(&source)
+            .and_then(|source_failures| source_failures.banned_until)
+            .is_some_and(|banned_until| Instant::now() < banned_until)
+    }
+
+    pub fn record_failure(self: &Arc<Self>, ip_address: IpAddr) {
+        if !self.config.enabled {
+            return;
+        }
+        if self.is_allowed(ip_address.to_canonical()) {
+            return;
+        }
+        let source = self.source(ip_address);
+        let now = Instant::now();
+        let window = Duration::from_secs(self.config.window);
+        let failures = {
+            let mut sources = match self.sources.lock() {
+                Ok(sources) => sources,
+                Err(e) => {
+                    error!("Fail to lock ban tracker: {e:?}");
+                    return;
+                }
+            };
+            if sources.len() >= MAX_TRACKED_SOURCES {
+                sources.retain(|_, source_failures| {
+                    source_failures.banned_until.is_some()
+                        || source_failures
+                            .failure_times
+                            .back()
+                            .is_some_and(|failure_time| now.duration_since(*failure_time) < window)
+                });
+            }
+            let source_failures = sources.entry(source).or_default();
+            if source_failures.banned_until.is_some() {
+                return;
+            }
+            while source_failures
+                .failure_times
+                .front()
+                .is_some_and(|failure_time| now.duration_since(*failure_time) >= window)
+            {
+                source_failures.failure_times.pop_front();
+            }
+            source_failures.failure_times.push_back(now);
+            let failures = source_failures.failure_times.len();
+            if failures < self.config.max_failures {
+                return;
+            }
+            source_failures.failure_times.clear();
+            source_failures.banned_until =
+                Some(now + Duration::from_secs(self.config.ban_duration));
+            failures
+        };
+        self.publish(
+            LogEventLevel::Warning,
+            format!(
+                "Ban [{source}] for {} seconds because of {failures} handshake failures in {} seconds",
+                self.config.ban_duration, self.config.window
+            ),
+        );
+        let ban_tracker = self.clone();
+        tokio::spawn(async move {
+            sleep(Duration::from_secs(ban_tracker.config.ban_duration)).await;
+            ban_tracker.unban(source);
+        });
+    }
+
+    fn unban(&self, source: IpNet) {
+        {
+            let mut sources = match self.sources.lock() {
+                Ok(sources) => sources,
+                Err(e) => {
+                    error!("Fail to lock ban tracker: {e:?}");
+                    return;
+                }
+            };
+            sources.remove(&source);
+        }
+        self.publish(LogEventLevel::Info, format!("Unban [{source}]"));
+    }
+
+    fn publish(&self, level: LogEventLevel, message: String) {
+        info!("{message}");
+        let Some(log_event_sender) = self.log_event_sender.get() else {
+            return;
+        };
+        if let Err(e) = log_event_sender.try_send(LogEvent {
+            level,
+            timestamp: Local::now(),
+            message,
+        }) {
+            error!("Fail to send ban log event: {e:?}");
+        }
+    }
+}
+
+#[tokio::test]
+async fn test() {
+    let ban_tracker = Arc::new(BanTracker::new(BanConfig {
+        enabled: true,
+        max_failures: 3,
+        window: 60,
+        ban_duration: 1,
+        allow_networks: vec!["10.0.0.0/8".parse().unwrap()],
+        ipv6_prefix_length: 64,
+    }));
+    let (log_event_sender, mut log_event_receiver) = tokio::sync::mpsc::channel(16);
+    ban_tracker.set_log_event_sender(log_event_sender);
+    let source: IpAddr = "192.0.2.1".parse().unwrap();
+    ban_tracker.record_failure(source);
+    ban_tracker.record_failure(source);
+    assert!(!ban_tracker.is_banned(source));
+    ban_tracker.record_failure(source);
+    assert!(ban_tracker.is_banned(source));
+    // The IPv4-mapped IPv6 address is the same source
+    assert!(ban_tracker.is_banned("::ffff:192.0.2.1".parse().unwrap()));
+    let allowed: IpAddr = "10.0.0.1".parse().unwrap();
+    for _ in 0..5 {
+        ban_tracker.record_failure(allowed);
+    }
+    assert!(!ban_tracker.is_banned(allowed));
+    assert!(log_event_receiver
+        .recv()
+        .await
+        .unwrap()
+        .message
+        .starts_with("Ban"));
+    assert!(log_event_receiver
+        .recv()
+        .await
+        .unwrap()
+        .message
+        .starts_with("Unban"));
+    assert!(!ban_tracker.is_banned(source));
+    // The IPv6 addresses in the same /64 are the same source
+    for host in 1..=3 {
+        ban_tracker.record_failure(format!("2001:db8::{host}").parse().unwrap());
+    }
+    assert!(ban_tracker.is_banned("2001:db8::ffff:1".parse().unwrap()));
+    assert!(!ban_tracker.is_banned("2001:db8:0:1::1".parse().unwrap()));
+}
@@ -0,0 +1,282 @@
+use crate::config::CompressionConfig;
+use crate::error::CommonError;
+use ppaass_protocol::Compression;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::sleep;
+use tokio_util::bytes::{Buf, BufMut, BytesMut};
+use tracing::info;
+
+const RAW_FRAME: u8 = 0;
+const COMPRESSED_FRAME: u8 = 1;
+const ZSTD_LEVEL: i32 = 3;
+/// A frame can not decompress to more than the max frame length, so a
+/// small frame can not expand to exhaust the memory
+const MAX_DECOMPRESSED_SIZE: usize = 8 * 1024 * 1024;
+/// The bytes at the beginning of the frame used to estimate the entropy
+const ENTROPY_SAMPLE_SIZE: usize = 1024;
+/// The data with more entropy bits per byte is likely compressed or
+/// encrypted already
+const MAX_COMPRESSIBLE_ENTROPY: f64 = 7.5;
+
+/// The data sent with compression negotiated, each kind of connection
+/// keeps its own counter so the agent and forward traffic are not mixed
+#[derive(Debug)]
+pub struct CompressionCounter {
+    name: &'static str,
+    original_bytes: AtomicU64,
+    sent_bytes: AtomicU64,
+    compressed_frames: AtomicU64,
+    skipped_frames: AtomicU64,
+}
+
+/// The data sent by the connections counted by one counter
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CompressionStats {
+    /// The data bytes before compression
+    pub original_bytes: u64,
+    /// The data bytes after compression, the skipped frames are counted
+    /// in the original size
+    pub sent_bytes: u64,
+    pub compressed_frames: u64,
+    /// The frames sent without compression because they are small,
+    /// look incompressible or do not get smaller after compression
+    pub skipped_frames: u64,
+}
+
+impl CompressionStats {
+    pub fn saved_bytes(&self) -> u64 {
+        self.original_bytes.saturating_sub(self.sent_bytes)
+    }
+}
+
+impl CompressionCounter {
+    /// The name tells the connections counted in the log
+    pub fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            original_bytes: AtomicU64::new(0),
+            sent_bytes: AtomicU64::new(0),
+            compressed_frames: AtomicU64::new(0),
+            skipped_frames: AtomicU64::new(0),
+        }
+    }
+
+    pub fn stats(&self) -> CompressionStats {
+        CompressionStats {
+            original_bytes: self.original_bytes.load(Ordering::Relaxed),
+            sent_bytes: self.sent_bytes.load(Ordering::Relaxed),
+            compressed_frames: self.compressed_frames.load(Ordering::Relaxed),
+            skipped_frames: self.skipped_frames.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn start_log_stats(self: &Arc<Self>, stats_interval: u64) {
+        let counter = self.clone();
+        tokio::spawn(async move {
+            let mut last_stats = CompressionStats::default();
+            loop {
+                sleep(Duration::from_secs(stats_interval)).await;
+                let stats = counter.stats();
+                if stats == last_stats {
+                    continue;
+                }
+                info!(
+                    "Compression of {} connections saved {} of {} bytes: {stats:?}",
+                    counter.name,
+                    stats.saved_bytes(),
+                    stats.original_bytes
+                );
+                last_stats = stats;
+            }
+        });
+    }
+
+    fn record(&self, original_size: usize, sent_size: usize, compressed: bool) {
+        self.original_bytes
+            .fetch_add(original_size as u64, Ordering::Relaxed);
+        self.sent_bytes
+            .fetch_add(sent_size as u64, Ordering::Relaxed);
+        if compressed {
+            self.compressed_frames.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.skipped_frames.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// The algorithm used by both sides, the first one supported by the agent
+/// that the proxy also supports
+pub(crate) fn select_compression(
+    agent_compressions: &[Compression],
+    config: &CompressionConfig,
+) -> Option<Compression> {
+    if !config.enabled {
+        return None;
+    }
+    agent_compressions
+        .iter()
+        .find(|compression| config.algorithms.contains(compression))
+        .copied()
+}
+
+fn looks_incompressible(data: &[u8]) -> bool {
+    let sample = &data[..data.len().min(ENTROPY_SAMPLE_SIZE)];
+    let mut byte_counts = [0usize; 256];
+    sample
+        .iter()
+        .for_each(|byte| byte_counts[*byte as usize] += 1);
+    let sample_size = sample.len() as f64;
+    let entropy: f64 = byte_counts
+        .iter()
+        .filter(|byte_count| **byte_count > 0)
+        .map(|byte_count| {
+            let probability = *byte_count as f64 / sample_size;
+            -probability * probability.log2()
+        })
+        .sum();
+    entropy > MAX_COMPRESSIBLE_ENTROPY
+}
+
+/// Compress the frame data with the negotiated algorithm, the frame starts
+/// with one byte flag telling whether the data is compressed.
+#[derive(Debug, Clone)]
+pub struct FrameCompressor {
+    compression: Compression,
+    min_compress_size: usize,
+    counter: Arc<CompressionCounter>,
+}
+
+impl FrameCompressor {
+    pub fn new(
+        compression: Compression,
+        min_compress_size: usize,
+        counter: Arc<CompressionCounter>,
+    ) -> Self {
+        Self {
+            compression,
+            min_compress_size,
+            counter,
+        }
+    }
+
+    fn compress(&self, data: &[u8]) -> Result<Vec<u8>, CommonError> {
+        match self.compression {
+            Compression::Zstd => Ok(zstd::bulk::compress(data, ZSTD_LEVEL)?),
+            Compression::Lz4 => Ok(lz4_flex::block::compress_prepend_size(data)),
+        }
+    }
+
+    fn decompress(&self, data: &[u8]) -> Result<Vec<u8>, CommonError> {
+        // The frame carries the decompressed size, check it before the output
+        // buffer is allocated with the size
+        let (decompressed_size, compressed) = match self.compression {
+            Compression::Zstd => {
+                let Ok(Some(decompressed_size)) = zstd::zstd_safe::get_frame_content_size(data)
+                else {
+                    return Err(CommonError::Other(
+                        "Zstd frame has no content size".to_string(),
+                    ));
+                };
+                (decompressed_size, data)
+            }
+            Compression::Lz4 => {
+                let Some((size_bytes, block)) = data.split_first_chunk::<4>() else {
+                    return Err(CommonError::Other("Lz4 frame is too short".to_string()));
+                };
+                (u32::from_le_bytes(*size_bytes) as u64, block)
+            }
+        };
+        if decompressed_size > MAX_DECOMPRESSED_SIZE as u64 {
+            return Err(CommonError::Other(format!(
+                "Decompressed frame exceeds {MAX_DECOMPRESSED_SIZE} bytes"
+            )));
+        }
+        let decompressed_size = decompressed_size as usize;
+        match self.compression {
+            Compression::Zstd => Ok(zstd::bulk::decompress(compressed, decompressed_size)?),
+            Compression::Lz4 => lz4_flex::block::decompress(compressed, decompressed_size)
+                .map_err(|e| CommonError::Other(format!("Fail to decompress lz4 frame: {e}"))),
+        }
+    }
+
+    pub(crate) fn compress_frame(&self, data: &[u8]) -> Result<BytesMut, CommonError> {
+        let compressed = if data.len() < self.min_compress_size || looks_incompressible(data) {
+            None
+        } else {
+            Some(self.compress(data)?).filter(|compressed| compressed.len() < data.len())
+        };
+        let mut frame;
+        match compressed {
+            Some(compressed) => {
+                self.counter.record(data.len(), compressed.len(), true);
+                frame = BytesMut::with_capacity(1 + compressed.len());
+                frame.put_u8(COMPRESSED_FRAME);
+                frame.put_slice(&compressed);
+            }
+            None => {
+                self.counter.record(data.len(), data.len(), false);
+                frame = BytesMut::with_capacity(1 + data.len());
+                frame.put_u8(RAW_FRAME);
+                frame.put_slice(data);
+            }
+        }
+        Ok(frame)
+    }
+
+    pub(crate) fn decompress_frame(&self, mut frame: BytesMut) -> Result<BytesMut, CommonError> {
+        if frame.is_empty() {
+            return Err(CommonError::Other("Compression frame is empty".to_string()));
+        }
+        match frame.get_u8() {
+            RAW_FRAME => Ok(frame),
+            COMPRESSED_FRAME => Ok(BytesMut::from(&self.decompress(&frame)?[..])),
+            flag => Err(CommonError::Other(format!(
+                "Unknown compression frame flag: {flag}"
+            ))),
+        }
+    }
+}
+
+#[test]
+fn test() {
+    let text = "GET /api/v1/users HTTP/1.1\r\nHost: example.com\r\n\r\n".repeat(32);
+    let random_data: Vec<u8> = (0..4096).map(|_| rand::random::<u8>()).collect();
+    let counter = Arc::new(CompressionCounter::new("test"));
+    for compression in [Compression::Zstd, Compression::Lz4] {
+        let compressor = FrameCompressor::new(compression, 256, counter.clone());
+        let frame = compressor.compress_frame(text.as_bytes()).unwrap();
+        assert_eq!(COMPRESSED_FRAME, frame[0]);
+        assert!(frame.len() < text.len());
+        let decompressed = compressor.decompress_frame(frame).unwrap();
+        assert_eq!(text.as_bytes(), &decompressed[..]);
+        for data in [&b"short"[..], &random_data[..]] {
+            let frame = compressor.compress_frame(data).unwrap();
+            assert_eq!(RAW_FRAME, frame[0]);
+            assert_eq!(data, &compressor.decompress_frame(frame).unwrap()[..]);
+        }
+    }
+    // The streaming encoder leaves the content size out of the frame header
+    let mut frame = BytesMut::from(&[COMPRESSED_FRAME][..]);
+    frame.put_slice(&zstd::stream::encode_all(text.as_bytes(), ZSTD_LEVEL).unwrap());
+    let compressor = FrameCompressor::new(Compression::Zstd, 256, counter.clone());
+    assert!(compressor.decompress_frame(frame).is_err());
+    let frame = BytesMut::from(&[COMPRESSED_FRAME, 0xff, 0xff, 0xff, 0xff][..]);
+    let compressor = FrameCompressor::new(Compression::Lz4, 256, counter.clone());
+    assert!(compressor.decompress_frame(frame).is_err());
+    let config = CompressionConfig {
+        enabled: true,
+        algorithms: vec![Compression::Lz4],
+        ..Default::default()
+    };
+    assert_eq!(
+        Some(Compression::Lz4),
+        select_compression(&[Compression::Zstd, Compression::Lz4], &config)
+    );
+    assert_eq!(None, select_compression(&[Compression::Zstd], &config));
+    let stats = counter.stats();
+    assert_eq!(2, stats.compressed_frames);
+    assert_eq!(4, stats.skipped_frames);
+    assert!(stats.saved_bytes() > 0);
+}
@@ -1,37 +1,138 @@
+use ipnet::IpNet;
+use ppaass_protocol::Compression;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 pub trait RetrieveConnectionPoolConfig {
-    fn max_pool_size(&self) -> usize;
+    fn min_idle(&self) -> usize;
+    fn max_idle(&self) -> usize;
+    fn target_hit_rate(&self) -> f64;
     fn fill_interval(&self) -> u64;
     fn check_interval(&self) -> u64;
     fn connection_max_alive(&self) -> i64;
     fn heartbeat_timeout(&self) -> u64;
-    fn retake_interval(&self) -> u64;
+    fn max_take_wait(&self) -> u64;
+    fn max_fill_backoff(&self) -> u64;
+    fn take_check_threshold(&self) -> u64;
 }
 
 pub trait RetrieveConnectionConfig {
     fn frame_size(&self) -> usize;
     fn connect_timeout(&self) -> u64;
+    fn happy_eyeballs(&self) -> &HappyEyeballsConfig;
+    fn deadline(&self) -> &DeadlineConfig;
+    fn obfuscation(&self) -> &ObfuscationConfig;
+    fn compression(&self) -> &CompressionConfig;
+    fn rekey(&self) -> &RekeyConfig;
+}
+
+/// The configuration of the connections accepted from the agents
+pub trait RetrieveAgentConnectionConfig {
+    fn agent_frame_buffer_size(&self) -> usize;
+    fn agent_deadline(&self) -> &DeadlineConfig;
+    fn agent_obfuscation(&self) -> &ObfuscationConfig;
+    fn agent_compression(&self) -> &CompressionConfig;
+    fn agent_rekey(&self) -> &RekeyConfig;
+}
+
+pub trait RetrieveRelayConfig {
+    /// The seconds without data in both directions before a relay is closed
+    fn relay_idle_timeout(&self) -> u64;
 }
 
 pub trait RetrieveServerConfig {
     fn worker_thread_number(&self) -> usize;
-    fn server_port(&self) -> u16;
-    fn ip_v6(&self) -> bool;
+    fn listen_addresses(&self) -> Vec<ServerListenAddress>;
+}
+
+/// One address the server listens on, every listener
+/// share the same connection handler.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ServerListenAddress {
+    /// The socket address to bind, e.g. `0.0.0.0:443` or `[::]:443`
+    pub address: SocketAddr,
+    /// Set IPV6_V6ONLY on the listener socket, only work
+    /// for IPv6 address, when it is false an IPv6 listener
+    /// will also accept IPv4 connection on dual-stack system.
+    #[serde(default)]
+    pub ip_v6_only: bool,
+}
+
+impl ServerListenAddress {
+    /// Listen on the unspecified address of the family on the port, it is
+    /// the address configured with the `ip_v6` and `server_port` keys
+    pub fn unspecified(ip_v6: bool, port: u16) -> Self {
+        let ip = if ip_v6 {
+            IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+        } else {
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+        };
+        Self {
+            address: SocketAddr::new(ip, port),
+            ip_v6_only: false,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ConnectionPoolConfig {
-    pub max_pool_size: usize,
+    /// The idle proxy connections kept in the pool at least
+    #[serde(default = "default_min_idle")]
+    pub min_idle: usize,
+    /// The idle proxy connections kept in the pool at most
+    #[serde(alias = "max_pool_size")]
+    pub max_idle: usize,
+    /// The pool grows when the ratio of takes served by idle
+    /// proxy connection is lower than this value
+    #[serde(default = "default_target_hit_rate")]
+    pub target_hit_rate: f64,
     pub fill_interval: u64,
     pub check_interval: u64,
     pub connection_max_alive: i64,
     pub heartbeat_timeout: u64,
-    pub retake_interval: u64,
+    /// The max seconds to wait when taking a proxy connection from the pool
+    #[serde(default = "default_max_take_wait")]
+    pub max_take_wait: u64,
+    /// The max seconds between two filling when the connection
+    /// creation keeps failing, the filling interval doubles on
+    /// each failed filling until this value
+    #[serde(default = "default_max_fill_backoff")]
+    pub max_fill_backoff: u64,
+    /// Check the proxy connection with heartbeat when taking it
+    /// from the pool if it is not checked in this seconds
+    #[serde(default = "default_take_check_threshold")]
+    pub take_check_threshold: u64,
+}
+
+fn default_min_idle() -> usize {
+    1
+}
+
+fn default_target_hit_rate() -> f64 {
+    0.9
+}
+
+fn default_max_take_wait() -> u64 {
+    30
+}
+
+fn default_max_fill_backoff() -> u64 {
+    120
+}
+
+fn default_take_check_threshold() -> u64 {
+    10
 }
 
 impl RetrieveConnectionPoolConfig for ConnectionPoolConfig {
-    fn max_pool_size(&self) -> usize {
-        self.max_pool_size
+    fn min_idle(&self) -> usize {
+        self.min_idle
+    }
+    fn max_idle(&self) -> usize {
+        self.max_idle
+    }
+    fn target_hit_rate(&self) -> f64 {
+        self.target_hit_rate
     }
     fn fill_interval(&self) -> u64 {
         self.fill_interval
@@ -45,7 +146,454 @@ impl RetrieveConnectionPoolConfig for ConnectionPoolConfig {
     fn heartbeat_timeout(&self) -> u64 {
         self.heartbeat_timeout
     }
-    fn retake_interval(&self) -> u64 {
-        self.retake_interval
+    fn max_take_wait(&self) -> u64 {
+        self.max_take_wait
+    }
+    fn max_fill_backoff(&self) -> u64 {
+        self.max_fill_backoff
+    }
+    fn take_check_threshold(&self) -> u64 {
+        self.take_check_threshold
+    }
+}
+
+/// The address family tried first when connecting to a host with both
+/// IPv4 and IPv6 addresses
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AddressFamilyPreference {
+    #[default]
+    Ipv6,
+    Ipv4,
+}
+
+/// The Happy Eyeballs (RFC 8305) configuration, the connection attempts to
+/// the resolved addresses are started one by one with a delay in between
+/// and the first established connection wins.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HappyEyeballsConfig {
+    #[serde(default)]
+    pub prefer_family: AddressFamilyPreference,
+    /// How many addresses of the preferred family are tried before
+    /// the addresses of the two families are interleaved
+    #[serde(default = "default_first_address_family_count")]
+    pub first_address_family_count: usize,
+    /// The milliseconds to wait before starting the next connection
+    /// attempt when the previous one has not finished
+    #[serde(default = "default_connection_attempt_delay")]
+    pub connection_attempt_delay: u64,
+}
+
+fn default_first_address_family_count() -> usize {
+    1
+}
+
+fn default_connection_attempt_delay() -> u64 {
+    250
+}
+
+impl Default for HappyEyeballsConfig {
+    fn default() -> Self {
+        Self {
+            prefer_family: AddressFamilyPreference::default(),
+            first_address_family_count: default_first_address_family_count(),
+            connection_attempt_delay: default_connection_attempt_delay(),
+        }
+    }
+}
+
+/// The seconds each step before relaying can take, a peer sending the
+/// handshake or the tunnel init slowly is disconnected at the deadline.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeadlineConfig {
+    /// Receiving the handshake request on proxy, sending it on agent
+    #[serde(default = "default_handshake_deadline")]
+    pub handshake_request: u64,
+    /// Sending the handshake response on proxy, receiving it on agent
+    #[serde(default = "default_handshake_deadline")]
+    pub handshake_response: u64,
+    /// Sending the tunnel init response on proxy, sending the tunnel init
+    /// request and receiving the response on agent
+    #[serde(default = "default_tunnel_init_deadline")]
+    pub tunnel_init: u64,
+}
+
+fn default_handshake_deadline() -> u64 {
+    10
+}
+
+fn default_tunnel_init_deadline() -> u64 {
+    30
+}
+
+impl Default for DeadlineConfig {
+    fn default() -> Self {
+        Self {
+            handshake_request: default_handshake_deadline(),
+            handshake_response: default_handshake_deadline(),
+            tunnel_init: default_tunnel_init_deadline(),
+        }
+    }
+}
+
+/// Hide the frame lengths with a keystream and send random padding frames
+/// between the data frames, it is used only when both agent and proxy enable
+/// it in the handshake.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct ObfuscationConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// The probability to send a padding frame before each data frame
+    #[serde(default = "default_padding_probability")]
+    pub padding_probability: f64,
+    /// The padding frame size is random between the min and max bytes
+    #[serde(default = "default_min_padding_size")]
+    pub min_padding_size: usize,
+    #[serde(default = "default_max_padding_size")]
+    pub max_padding_size: usize,
+}
+
+fn default_padding_probability() -> f64 {
+    0.2
+}
+
+fn default_min_padding_size() -> usize {
+    16
+}
+
+fn default_max_padding_size() -> usize {
+    512
+}
+
+impl Default for ObfuscationConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            padding_probability: default_padding_probability(),
+            min_padding_size: default_min_padding_size(),
+            max_padding_size: default_max_padding_size(),
+        }
+    }
+}
+
+/// Compress the frames before encryption, the algorithm is the first one
+/// of the agent supported algorithms that the proxy also supports.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CompressionConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// The supported algorithms in the order of preference
+    #[serde(default = "default_compression_algorithms")]
+    pub algorithms: Vec<Compression>,
+    /// The frames smaller than this bytes are sent without compression
+    #[serde(default = "default_min_compress_size")]
+    pub min_compress_size: usize,
+    /// The interval in seconds to log the bytes saved by compression
+    #[serde(default = "default_compression_stats_interval")]
+    pub stats_interval: u64,
+}
+
+fn default_compression_algorithms() -> Vec<Compression> {
+    vec![Compression::Zstd, Compression::Lz4]
+}
+
+fn default_min_compress_size() -> usize {
+    256
+}
+
+fn default_compression_stats_interval() -> u64 {
+    60
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            algorithms: default_compression_algorithms(),
+            min_compress_size: default_min_compress_size(),
+            stats_interval: default_compression_stats_interval(),
+        }
+    }
+}
+
+/// Switch to a new key in relay after the bytes are sent or the seconds
+/// passed with the current key, it is used only when both agent and proxy
+/// enable it. Each side switches the key of the data it sends.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RekeyConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// The bytes sent with one key, 0 to disable
+    #[serde(default = "default_rekey_max_bytes")]
+    pub max_bytes: u64,
+    /// The seconds one key is used, 0 to disable
+    #[serde(default = "default_rekey_max_seconds")]
+    pub max_seconds: u64,
+}
+
+fn default_rekey_max_bytes() -> u64 {
+    1024 * 1024 * 1024
+}
+
+fn default_rekey_max_seconds() -> u64 {
+    3600
+}
+
+impl Default for RekeyConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_bytes: default_rekey_max_bytes(),
+            max_seconds: default_rekey_max_seconds(),
+        }
+    }
+}
+
+/// The addresses looked up for a domain
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DnsIpStrategy {
+    Ipv4Only,
+    Ipv6Only,
+    /// Look up both IPv4 and IPv6 addresses
+    #[default]
+    Ipv4AndIpv6,
+    /// Look up IPv6 addresses, fall back to IPv4 when there is none
+    Ipv6ThenIpv4,
+    /// Look up IPv4 addresses, fall back to IPv6 when there is none
+    Ipv4ThenIpv6,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DnsConfig {
+    /// The upstream DNS servers, use the system configuration when it is empty
+    #[serde(default)]
+    pub upstream_servers: Vec<SocketAddr>,
+    #[serde(default)]
+    pub ip_strategy: DnsIpStrategy,
+    /// The seconds to wait for the upstream DNS server
+    #[serde(default = "default_dns_timeout")]
+    pub timeout: u64,
+    /// How many domains are cached at most
+    #[serde(default = "default_dns_cache_size")]
+    pub cache_size: usize,
+    /// The max seconds to cache the resolved addresses, the
+    /// addresses are cached in the TTL of the DNS records
+    #[serde(default = "default_dns_max_ttl")]
+    pub max_ttl: u64,
+    /// The max seconds to cache that a domain has no address
+    #[serde(default = "default_dns_negative_ttl")]
+    pub negative_ttl: u64,
+    /// The domains resolved to the given addresses without querying DNS
+    #[serde(default)]
+    pub static_overrides: HashMap<String, Vec<IpAddr>>,
+}
+
+fn default_dns_timeout() -> u64 {
+    5
+}
+
+fn default_dns_cache_size() -> usize {
+    4096
+}
+
+fn default_dns_max_ttl() -> u64 {
+    3600
+}
+
+fn default_dns_negative_ttl() -> u64 {
+    30
+}
+
+impl Default for DnsConfig {
+    fn default() -> Self {
+        Self {
+            upstream_servers: Vec::new(),
+            ip_strategy: DnsIpStrategy::default(),
+            timeout: default_dns_timeout(),
+            cache_size: default_dns_cache_size(),
+            max_ttl: default_dns_max_ttl(),
+            negative_ttl: default_dns_negative_ttl(),
+            static_overrides: HashMap::new(),
+        }
+    }
+}
+
+/// Ban the source addresses failing the handshake too many times, the
+/// connections from a banned address are dropped right after accepted.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BanConfig {
+    #[serde(default = "default_ban_enabled")]
+    pub enabled: bool,
+    /// How many failures in the window ban the source address
+    #[serde(default = "default_ban_max_failures")]
+    pub max_failures: usize,
+    /// The seconds of the sliding window to count the failures
+    #[serde(default = "default_ban_window")]
+    pub window: u64,
+    /// The seconds the source address is banned
+    #[serde(default = "default_ban_duration")]
+    pub ban_duration: u64,
+    /// The source networks never banned
+    #[serde(default)]
+    pub allow_networks: Vec<IpNet>,
+    /// The prefix length the IPv6 source addresses are grouped by, a host
+    /// usually owns a whole /64 and can change its address inside it
+    #[serde(default = "default_ban_ipv6_prefix_length")]
+    pub ipv6_prefix_length: u8,
+}
+
+fn default_ban_enabled() -> bool {
+    true
+}
+
+fn default_ban_max_failures() -> usize {
+    5
+}
+
+fn default_ban_window() -> u64 {
+    60
+}
+
+fn default_ban_duration() -> u64 {
+    600
+}
+
+fn default_ban_ipv6_prefix_length() -> u8 {
+    64
+}
+
+impl Default for BanConfig {
+    fn default() -> Self {
+        Self {
+            enabled: default_ban_enabled(),
+            max_failures: default_ban_max_failures(),
+            window: default_ban_window(),
+            ban_duration: default_ban_duration(),
+            allow_networks: Vec::new(),
+            ipv6_prefix_length: default_ban_ipv6_prefix_length(),
+        }
+    }
+}
+
+/// Run the RSA operations of the handshakes on the blocking threads, the
+/// handshakes over the pending limit are rejected instead of queued.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RsaPoolConfig {
+    /// How many handshakes run the RSA operations at the same time
+    #[serde(default = "default_rsa_pool_max_concurrency")]
+    pub max_concurrency: usize,
+    /// How many handshakes can wait for or run the RSA operations
+    #[serde(default = "default_rsa_pool_max_pending")]
+    pub max_pending: usize,
+    /// The interval in seconds to log the pool stats
+    #[serde(default = "default_rsa_pool_stats_interval")]
+    pub stats_interval: u64,
+}
+
+fn default_rsa_pool_max_concurrency() -> usize {
+    8
+}
+
+fn default_rsa_pool_max_pending() -> usize {
+    256
+}
+
+fn default_rsa_pool_stats_interval() -> u64 {
+    60
+}
+
+impl Default for RsaPoolConfig {
+    fn default() -> Self {
+        Self {
+            max_concurrency: default_rsa_pool_max_concurrency(),
+            max_pending: default_rsa_pool_max_pending(),
+            stats_interval: default_rsa_pool_stats_interval(),
+        }
+    }
+}
+
+/// Reject the replayed handshake requests, the request carries a timestamp
+/// and a random nonce, the proxy remembers the nonces in the clock skew window.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReplayConfig {
+    /// The max seconds between the request timestamp and the proxy clock
+    #[serde(default = "default_replay_max_clock_skew")]
+    pub max_clock_skew: u64,
+    /// How many nonces are remembered at most, the nonces are kept until they
+    /// are out of the clock skew window, the handshakes are rejected when the
+    /// cache is full
+    #[serde(default = "default_replay_nonce_cache_size")]
+    pub nonce_cache_size: usize,
+}
+
+fn default_replay_max_clock_skew() -> u64 {
+    120
+}
+
+fn default_replay_nonce_cache_size() -> usize {
+    100000
+}
+
+impl Default for ReplayConfig {
+    fn default() -> Self {
+        Self {
+            max_clock_skew: default_replay_max_clock_skew(),
+            nonce_cache_size: default_replay_nonce_cache_size(),
+        }
+    }
+}
+
+/// The policy to select the proxy server for a new proxy connection
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ProxySelectPolicy {
+    /// Randomly select with the weight calculated from success rate and latency
+    #[default]
+    WeightedRandom,
+    /// Select the proxy server with the least latency
+    LeastLatency,
+    /// Select the proxy server one by one
+    RoundRobin,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ProxySelectorConfig {
+    #[serde(default)]
+    pub policy: ProxySelectPolicy,
+    /// Open the circuit breaker of the proxy server (mark it down)
+    /// after this number of continuous failures
+    #[serde(default = "default_proxy_down_failure_threshold")]
+    pub down_failure_threshold: u32,
+    /// How many seconds the proxy server keeps down when the circuit
+    /// breaker opens at the first time, it doubles each time the
+    /// breaker opens again without a success in between
+    #[serde(default = "default_proxy_down_duration")]
+    pub down_duration: u64,
+    /// The max seconds the proxy server keeps down
+    #[serde(default = "default_proxy_max_down_duration")]
+    pub max_down_duration: u64,
+}
+
+fn default_proxy_down_failure_threshold() -> u32 {
+    3
+}
+
+fn default_proxy_down_duration() -> u64 {
+    30
+}
+
+fn default_proxy_max_down_duration() -> u64 {
+    600
+}
+
+impl Default for ProxySelectorConfig {
+    fn default() -> Self {
+        Self {
+            policy: ProxySelectPolicy::default(),
+            down_failure_threshold: default_proxy_down_failure_threshold(),
+            down_duration: default_proxy_down_duration(),
+            max_down_duration: default_proxy_max_down_duration(),
+        }
     }
 }
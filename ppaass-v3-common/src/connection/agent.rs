@@ -1,13 +1,20 @@
+use crate::compression::{select_compression, CompressionCounter, FrameCompressor};
+use crate::config::{ObfuscationConfig, RekeyConfig, RetrieveAgentConnectionConfig};
 use crate::connection::codec::{
-    HandshakeRequestDecoder, HandshakeResponseEncoder, TunnelControlRequestResponseCodec,
+    CryptoLengthDelimitedCodec, FrameDirection, HandshakeRequestDecoder, HandshakeResponseEncoder,
+    TunnelControlRequestResponseCodec, RELAY_KEYSTREAM,
 };
 use crate::connection::CryptoLengthDelimitedFramed;
 use crate::error::CommonError;
+use crate::replay::{unseal_encryption, ReplayGuard, HANDSHAKE_NONCE_SIZE};
+use crate::rsa_pool::RsaPool;
+use crate::transcript::HandshakeTranscript;
 use crate::user::repo::fs::USER_INFO_ADDITION_INFO_EXPIRED_DATE_TIME;
-use crate::user::UserInfoRepository;
+use crate::user::{UserInfo, UserInfoRepository};
 use crate::{
     random_generate_encryption, rsa_decrypt_encryption, rsa_encrypt_encryption, FramedConnection,
 };
+use bytes::Bytes;
 use chrono::{DateTime, Utc};
 use futures_util::SinkExt;
 use futures_util::StreamExt;
@@ -15,66 +22,193 @@ use ppaass_protocol::{
     Encryption, HandshakeRequest, HandshakeResponse, HeartbeatResponse, TunnelControlRequest,
     TunnelControlResponse, TunnelInitRequest, TunnelInitResponse,
 };
+use rand::random;
+use std::io::ErrorKind;
 use std::net::SocketAddr;
+use std::pin::Pin;
 use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 use tokio::net::TcpStream;
+use tokio::sync::RwLock;
+use tokio::time::timeout;
 use tokio_util::bytes::BytesMut;
 use tokio_util::codec::{Framed, FramedParts};
 use tokio_util::io::{SinkWriter, StreamReader};
 use tracing::debug;
 pub struct AgentTcpConnectionNewState {}
 pub struct AgentTcpConnectionTunnelCtlState {
+    username: String,
+    user_info: Arc<RwLock<UserInfo>>,
     tunnel_ctl_request_response_framed: Framed<TcpStream, TunnelControlRequestResponseCodec>,
     proxy_encryption: Arc<Encryption>,
     agent_encryption: Arc<Encryption>,
+    tunnel_init_deadline: u64,
+    obfuscation: Option<ObfuscationConfig>,
+    compressor: Option<FrameCompressor>,
+    rekey: Option<RekeyConfig>,
+}
+
+/// Keep the bytes read from the agent in handshake, so the connection can
+/// be handed over to another server with the bytes when the handshake fails.
+struct HandshakeRecordStream {
+    inner: TcpStream,
+    received_bytes: BytesMut,
+}
+
+impl AsyncRead for HandshakeRecordStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let filled_before_read = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        this.received_bytes
+            .extend_from_slice(&buf.filled()[filled_before_read..]);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for HandshakeRecordStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// The agent connection fails in handshake, the connection and the bytes
+/// received from it are returned when the handshake request is rejected.
+#[derive(Debug)]
+pub struct AgentHandshakeError {
+    pub error: CommonError,
+    pub rejected_connection: Option<(TcpStream, BytesMut)>,
+}
+
+impl From<CommonError> for AgentHandshakeError {
+    fn from(error: CommonError) -> Self {
+        Self {
+            error,
+            rejected_connection: None,
+        }
+    }
+}
+
+/// The handshake fails because of the request sent by the agent, the other
+/// errors like the RSA pool overflow happen on the proxy side, the connection
+/// is not handed over because it may come from a valid agent. The bytes not
+/// in the handshake format, like an HTTP request or a TLS client hello, are
+/// decoded as a length over the max frame length and fail with invalid data.
+fn is_rejected_handshake_request(error: &CommonError, received_bytes: &[u8]) -> bool {
+    match error {
+        CommonError::Io(e) => e.kind() == ErrorKind::InvalidData,
+        CommonError::ConnectionExhausted(_) => !received_bytes.is_empty(),
+        CommonError::RsaCryptoNotFound(_)
+        | CommonError::Rsa(_)
+        | CommonError::Aes(_)
+        | CommonError::UserExpired(_)
+        | CommonError::HandshakeReplay(_)
+        | CommonError::BincodeDecode(_)
+        | CommonError::Protocol(_)
+        | CommonError::HandshakeRequestTimeout(..) => true,
+        _ => false,
+    }
+}
+
+/// The handshake request accepted by the proxy
+struct AcceptedHandshakeRequest {
+    username: String,
+    user_info: Arc<RwLock<UserInfo>>,
+    agent_encryption: Encryption,
+    proxy_encryption: Encryption,
+    obfuscation: Option<ObfuscationConfig>,
+    compressor: Option<FrameCompressor>,
+    rekey: Option<RekeyConfig>,
+    handshake_response: HandshakeResponse,
 }
 
 impl FramedConnection<AgentTcpConnectionNewState> {
-    pub async fn create<R>(
+    pub async fn create<R, C>(
         agent_tcp_stream: TcpStream,
         agent_socket_address: SocketAddr,
         user_info_repo: &R,
-        frame_buffer_size: usize,
-    ) -> Result<FramedConnection<AgentTcpConnectionTunnelCtlState>, CommonError>
+        replay_guard: &ReplayGuard,
+        rsa_pool: &RsaPool,
+        compression_counter: &Arc<CompressionCounter>,
+        config: &C,
+    ) -> Result<FramedConnection<AgentTcpConnectionTunnelCtlState>, AgentHandshakeError>
     where
         R: UserInfoRepository + Sync + Send + 'static,
+        C: RetrieveAgentConnectionConfig,
     {
-        let mut handshake_request_framed =
-            Framed::new(agent_tcp_stream, HandshakeRequestDecoder::new());
-        let HandshakeRequest {
-            authentication,
-            encryption,
-        } = handshake_request_framed
-            .next()
-            .await
-            .ok_or(CommonError::ConnectionExhausted(agent_socket_address))??;
-        let user_info = user_info_repo
-            .get_user(&authentication)
-            .await?
-            .ok_or(CommonError::RsaCryptoNotFound(authentication.clone()))?;
-        let user_info = user_info.read().await;
-        let user_expired_time = user_info
-            .get_additional_info::<DateTime<Utc>>(USER_INFO_ADDITION_INFO_EXPIRED_DATE_TIME);
-        if let Some(user_expired_time) = user_expired_time {
-            if Utc::now() > *user_expired_time {
-                return Err(CommonError::UserExpired(authentication));
-            }
-        }
-        let agent_encryption =
-            rsa_decrypt_encryption(&encryption, user_info.rsa_crypto())?.into_owned();
-        let proxy_encryption = random_generate_encryption();
-        let encrypted_proxy_encryption =
-            rsa_encrypt_encryption(&proxy_encryption, user_info.rsa_crypto())?;
-        let handshake_response = HandshakeResponse {
-            encryption: encrypted_proxy_encryption.into_owned(),
-        };
+        let frame_buffer_size = config.agent_frame_buffer_size();
+        let deadline = config.agent_deadline();
+        let mut handshake_request_framed = Framed::new(
+            HandshakeRecordStream {
+                inner: agent_tcp_stream,
+                received_bytes: BytesMut::new(),
+            },
+            HandshakeRequestDecoder::new(),
+        );
+        let accept_result = Self::accept_handshake_request(
+            &mut handshake_request_framed,
+            agent_socket_address,
+            user_info_repo,
+            replay_guard,
+            rsa_pool,
+            compression_counter,
+            config,
+        )
+        .await;
         let FramedParts {
-            io: agent_tcp_stream,
+            io:
+                HandshakeRecordStream {
+                    inner: agent_tcp_stream,
+                    received_bytes,
+                },
             ..
         } = handshake_request_framed.into_parts();
+        let AcceptedHandshakeRequest {
+            username,
+            user_info,
+            agent_encryption,
+            proxy_encryption,
+            obfuscation,
+            compressor,
+            rekey,
+            handshake_response,
+        } = match accept_result {
+            Ok(accepted_handshake_request) => accepted_handshake_request,
+            Err(error) => {
+                let rejected_connection = is_rejected_handshake_request(&error, &received_bytes)
+                    .then_some((agent_tcp_stream, received_bytes));
+                return Err(AgentHandshakeError {
+                    error,
+                    rejected_connection,
+                });
+            }
+        };
         let mut handshake_response_framed =
             Framed::new(agent_tcp_stream, HandshakeResponseEncoder::new());
-        handshake_response_framed.send(handshake_response).await?;
+        timeout(
+            Duration::from_secs(deadline.handshake_response),
+            handshake_response_framed.send(handshake_response),
+        )
+        .await
+        .map_err(|_| {
+            CommonError::HandshakeResponseTimeout(agent_socket_address, deadline.handshake_response)
+        })??;
         let FramedParts {
             io: agent_tcp_stream,
             ..
@@ -86,26 +220,168 @@ impl FramedConnection<AgentTcpConnectionNewState> {
 
             frame_buffer_size,
             state: AgentTcpConnectionTunnelCtlState {
+                username,
+                user_info,
                 proxy_encryption: proxy_encryption.clone(),
                 agent_encryption: agent_encryption.clone(),
+                tunnel_init_deadline: deadline.tunnel_init,
+                obfuscation,
+                compressor: compressor.clone(),
+                rekey,
                 tunnel_ctl_request_response_framed: Framed::with_capacity(
                     agent_tcp_stream,
-                    TunnelControlRequestResponseCodec::new(agent_encryption, proxy_encryption),
+                    TunnelControlRequestResponseCodec::new(
+                        agent_encryption,
+                        proxy_encryption,
+                        obfuscation,
+                        compressor,
+                    )?,
                     frame_buffer_size,
                 ),
             },
         })
     }
+
+    /// Receive the handshake request and authenticate the agent user
+    async fn accept_handshake_request<R, C>(
+        handshake_request_framed: &mut Framed<HandshakeRecordStream, HandshakeRequestDecoder>,
+        agent_socket_address: SocketAddr,
+        user_info_repo: &R,
+        replay_guard: &ReplayGuard,
+        rsa_pool: &RsaPool,
+        compression_counter: &Arc<CompressionCounter>,
+        config: &C,
+    ) -> Result<AcceptedHandshakeRequest, CommonError>
+    where
+        R: UserInfoRepository + Sync + Send + 'static,
+        C: RetrieveAgentConnectionConfig,
+    {
+        let deadline = config.agent_deadline();
+        let obfuscation = config.agent_obfuscation();
+        let compression = config.agent_compression();
+        let rekey = config.agent_rekey();
+        let handshake_request = timeout(
+            Duration::from_secs(deadline.handshake_request),
+            handshake_request_framed.next(),
+        )
+        .await
+        .map_err(|_| {
+            CommonError::HandshakeRequestTimeout(agent_socket_address, deadline.handshake_request)
+        })?
+        .ok_or(CommonError::ConnectionExhausted(agent_socket_address))??;
+        // Reject the replayed request before looking up the user and the RSA decryption
+        let nonce_reservation =
+            replay_guard.reserve(handshake_request.timestamp, &handshake_request.nonce)?;
+        let transcript = HandshakeTranscript::new(&handshake_request)?;
+        let HandshakeRequest {
+            authentication,
+            encryption,
+            obfuscation: obfuscation_requested,
+            compressions,
+            rekey: rekey_requested,
+            timestamp,
+            nonce,
+        } = handshake_request;
+        let user_info = user_info_repo
+            .get_user(&authentication)
+            .await?
+            .ok_or(CommonError::RsaCryptoNotFound(authentication.clone()))?;
+        let user_info_lock = user_info.clone();
+        let user_info = user_info.read().await;
+        let user_expired_time = user_info
+            .get_additional_info::<DateTime<Utc>>(USER_INFO_ADDITION_INFO_EXPIRED_DATE_TIME);
+        if let Some(user_expired_time) = user_expired_time
+            && Utc::now() > *user_expired_time
+        {
+            return Err(CommonError::UserExpired(authentication));
+        }
+        let rsa_crypto = user_info.rsa_crypto().clone();
+        drop(user_info);
+        // Obfuscate the frames only when both sides enable it
+        let obfuscation_enabled = obfuscation_requested && obfuscation.enabled;
+        let obfuscation = *obfuscation;
+        let selected_compression = select_compression(&compressions, compression);
+        let rekey = (rekey_requested && rekey.enabled).then_some(*rekey);
+        // All the RSA operations of the handshake run on the blocking threads
+        let (agent_encryption, proxy_encryption, obfuscation, handshake_response) = rsa_pool
+            .run(move || {
+                let agent_encryption = unseal_encryption(
+                    rsa_decrypt_encryption(&encryption, &rsa_crypto)?.into_owned(),
+                    timestamp,
+                    &nonce,
+                )?;
+                // The obfuscation keystream is derived from the encryption key,
+                // it is refused over plain encryption
+                let obfuscation = (obfuscation_enabled
+                    && !matches!(agent_encryption, Encryption::Plain))
+                .then_some(obfuscation);
+                let proxy_encryption = random_generate_encryption();
+                let mut handshake_response = HandshakeResponse {
+                    encryption: rsa_encrypt_encryption(&proxy_encryption, &rsa_crypto)?
+                        .into_owned(),
+                    obfuscation: obfuscation.is_some(),
+                    compression: selected_compression,
+                    rekey: rekey.is_some(),
+                    nonce: Bytes::from(random::<[u8; HANDSHAKE_NONCE_SIZE]>().to_vec()),
+                    signature: Bytes::new(),
+                };
+                // Prove to the agent that the response is made with the proxy private key
+                handshake_response.signature = transcript.sign(&handshake_response, &rsa_crypto)?;
+                Ok((
+                    agent_encryption,
+                    proxy_encryption,
+                    obfuscation,
+                    handshake_response,
+                ))
+            })
+            .await?;
+        nonce_reservation.authenticated();
+        Ok(AcceptedHandshakeRequest {
+            username: authentication,
+            user_info: user_info_lock,
+            agent_encryption,
+            proxy_encryption,
+            obfuscation,
+            compressor: selected_compression.map(|selected_compression| {
+                FrameCompressor::new(
+                    selected_compression,
+                    compression.min_compress_size,
+                    compression_counter.clone(),
+                )
+            }),
+            rekey,
+            handshake_response,
+        })
+    }
 }
 impl FramedConnection<AgentTcpConnectionTunnelCtlState> {
-    pub async fn wait_tunnel_init(&mut self) -> Result<TunnelInitRequest, CommonError> {
+    /// The user authenticated in the handshake
+    pub fn username(&self) -> &str {
+        &self.state.username
+    }
+
+    pub fn user_info(&self) -> &Arc<RwLock<UserInfo>> {
+        &self.state.user_info
+    }
+
+    /// Wait for the tunnel init request and answer the heartbeats in between,
+    /// fail when no frame is received for the idle timeout in seconds,
+    /// 0 means waiting forever.
+    pub async fn wait_tunnel_init(
+        &mut self,
+        idle_timeout: u64,
+    ) -> Result<TunnelInitRequest, CommonError> {
         loop {
-            let tunnel_ctl_request = self
-                .state
-                .tunnel_ctl_request_response_framed
-                .next()
-                .await
-                .ok_or(CommonError::ConnectionExhausted(self.socket_address))??;
+            let next_frame = self.state.tunnel_ctl_request_response_framed.next();
+            let next_frame = if idle_timeout == 0 {
+                next_frame.await
+            } else {
+                timeout(Duration::from_secs(idle_timeout), next_frame)
+                    .await
+                    .map_err(|_| CommonError::IdleTimeout(self.socket_address, idle_timeout))?
+            };
+            let tunnel_ctl_request =
+                next_frame.ok_or(CommonError::ConnectionExhausted(self.socket_address))??;
             match tunnel_ctl_request {
                 TunnelControlRequest::Heartbeat(heartbeat_request) => {
                     debug!(
@@ -137,20 +413,103 @@ impl FramedConnection<AgentTcpConnectionTunnelCtlState> {
         CommonError,
     > {
         let tunnel_ctl_response = TunnelControlResponse::TunnelInit(tunnel_init_response);
-        self.state
-            .tunnel_ctl_request_response_framed
-            .send(tunnel_ctl_response)
-            .await?;
+        let tunnel_init_deadline = self.state.tunnel_init_deadline;
+        timeout(
+            Duration::from_secs(tunnel_init_deadline),
+            self.state
+                .tunnel_ctl_request_response_framed
+                .send(tunnel_ctl_response),
+        )
+        .await
+        .map_err(|_| CommonError::TunnelInitTimeout(self.socket_address, tunnel_init_deadline))??;
         let FramedParts { io, .. } = self.state.tunnel_ctl_request_response_framed.into_parts();
         Ok(FramedConnection {
             socket_address: self.socket_address,
             state: SinkWriter::new(StreamReader::new(CryptoLengthDelimitedFramed::new(
                 io,
-                self.state.agent_encryption,
-                self.state.proxy_encryption,
+                CryptoLengthDelimitedCodec::new(
+                    self.state.agent_encryption,
+                    self.state.proxy_encryption,
+                    self.state.obfuscation,
+                    self.state.compressor,
+                    self.state.rekey,
+                    RELAY_KEYSTREAM,
+                    FrameDirection::ProxyToAgent,
+                )?,
                 self.frame_buffer_size,
             ))),
             frame_buffer_size: self.frame_buffer_size,
         })
     }
 }
+
+#[tokio::test]
+async fn test() {
+    use crate::config::{CompressionConfig, DeadlineConfig, ReplayConfig, RsaPoolConfig};
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
+    struct EmptyUserInfoRepository;
+    #[async_trait::async_trait]
+    impl UserInfoRepository for EmptyUserInfoRepository {
+        async fn get_user(
+            &self,
+            _username: &str,
+        ) -> Result<Option<Arc<RwLock<UserInfo>>>, CommonError> {
+            Ok(None)
+        }
+        async fn list_all_users(&self) -> Result<Vec<Arc<RwLock<UserInfo>>>, CommonError> {
+            Ok(Vec::new())
+        }
+    }
+    #[derive(Default)]
+    struct AgentConnectionConfig {
+        deadline: DeadlineConfig,
+        obfuscation: ObfuscationConfig,
+        compression: CompressionConfig,
+        rekey: RekeyConfig,
+    }
+    impl RetrieveAgentConnectionConfig for AgentConnectionConfig {
+        fn agent_frame_buffer_size(&self) -> usize {
+            65536
+        }
+        fn agent_deadline(&self) -> &DeadlineConfig {
+            &self.deadline
+        }
+        fn agent_obfuscation(&self) -> &ObfuscationConfig {
+            &self.obfuscation
+        }
+        fn agent_compression(&self) -> &CompressionConfig {
+            &self.compression
+        }
+        fn agent_rekey(&self) -> &RekeyConfig {
+            &self.rekey
+        }
+    }
+    // A plain HTTP request is decoded as a frame length over the max frame
+    // length, the connection is returned with the bytes for the fallback server
+    let probe = b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n";
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let mut probe_tcp_stream = TcpStream::connect(listener.local_addr().unwrap())
+        .await
+        .unwrap();
+    probe_tcp_stream.write_all(probe).await.unwrap();
+    let (agent_tcp_stream, agent_socket_address) = listener.accept().await.unwrap();
+    let Err(AgentHandshakeError {
+        error,
+        rejected_connection: Some((_, received_bytes)),
+    }) = FramedConnection::<AgentTcpConnectionNewState>::create(
+        agent_tcp_stream,
+        agent_socket_address,
+        &EmptyUserInfoRepository,
+        &ReplayGuard::new(ReplayConfig::default()),
+        &RsaPool::new(RsaPoolConfig::default()),
+        &Arc::new(CompressionCounter::new("test")),
+        &AgentConnectionConfig::default(),
+    )
+    .await
+    else {
+        panic!("The probe connection is not rejected with the received bytes");
+    };
+    assert!(matches!(error, CommonError::Io(_)));
+    assert_eq!(&probe[..], &received_bytes[..]);
+}
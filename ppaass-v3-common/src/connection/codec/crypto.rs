@@ -1,3 +1,7 @@
+use crate::compression::FrameCompressor;
+use crate::config::{ObfuscationConfig, RekeyConfig};
+use crate::connection::codec::obfuscation::{FrameDirection, FrameObfuscator};
+use crate::connection::codec::rekey::{FrameRekeyer, RekeyFrame};
 use crate::crypto::{
     decrypt_with_aes, decrypt_with_blowfish, encrypt_with_aes, encrypt_with_blowfish,
 };
@@ -5,21 +9,102 @@ use crate::error::CommonError;
 
 use ppaass_protocol::Encryption;
 use std::sync::Arc;
-use tokio_util::bytes::BytesMut;
+use tokio_util::bytes::{Bytes, BytesMut};
 use tokio_util::codec::{Decoder, Encoder, LengthDelimitedCodec};
+use tracing::debug;
 pub struct CryptoLengthDelimitedCodec {
     decoder_encryption: Arc<Encryption>,
     encoder_encryption: Arc<Encryption>,
     length_delimited: LengthDelimitedCodec,
+    /// Replace the length delimited frames when the obfuscation is negotiated,
+    /// boxed because the keystreams are large
+    obfuscator: Option<Box<FrameObfuscator>>,
+    /// Compress the data before encryption when the compression is negotiated
+    compressor: Option<FrameCompressor>,
+    /// Switch the encryption keys in the middle when the rekey is negotiated
+    rekeyer: Option<FrameRekeyer>,
 }
 
 impl CryptoLengthDelimitedCodec {
-    pub fn new(decoder_encryption: Arc<Encryption>, encoder_encryption: Arc<Encryption>) -> Self {
-        Self {
+    pub(crate) fn new(
+        decoder_encryption: Arc<Encryption>,
+        encoder_encryption: Arc<Encryption>,
+        obfuscation: Option<ObfuscationConfig>,
+        compressor: Option<FrameCompressor>,
+        rekey: Option<RekeyConfig>,
+        keystream: u64,
+        encoder_direction: FrameDirection,
+    ) -> Result<Self, CommonError> {
+        let obfuscator = obfuscation
+            .map(|obfuscation| {
+                FrameObfuscator::new(
+                    obfuscation,
+                    &decoder_encryption,
+                    &encoder_encryption,
+                    keystream,
+                    encoder_direction,
+                )
+                .map(Box::new)
+            })
+            .transpose()?;
+        Ok(Self {
             decoder_encryption,
             encoder_encryption,
             length_delimited: LengthDelimitedCodec::new(),
+            obfuscator,
+            compressor,
+            rekeyer: rekey.map(FrameRekeyer::new),
+        })
+    }
+
+    /// Decode and decrypt the next data frame
+    fn decode_frame(&mut self, src: &mut BytesMut) -> Result<Option<BytesMut>, CommonError> {
+        let Some(obfuscator) = self.obfuscator.as_mut() else {
+            return match self.length_delimited.decode(src)? {
+                None => Ok(None),
+                Some(encrypted_bytes) => {
+                    Ok(Some(decrypt(&self.decoder_encryption, encrypted_bytes)?))
+                }
+            };
+        };
+        // Skip the padding frames until a data frame is decoded
+        while let Some(encrypted_frame) = obfuscator.decode_frame(src)? {
+            let frame = decrypt(&self.decoder_encryption, encrypted_frame)?;
+            if let Some(raw_bytes) = obfuscator.unwrap_frame(frame)? {
+                return Ok(Some(raw_bytes));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Encrypt and encode the frame
+    fn encode_frame(&mut self, frame: BytesMut, dst: &mut BytesMut) -> Result<(), CommonError> {
+        let Some(obfuscator) = self.obfuscator.as_mut() else {
+            let encrypted_bytes = encrypt(&self.encoder_encryption, frame)?;
+            return Ok(self.length_delimited.encode(encrypted_bytes, dst)?);
+        };
+        if let Some(padding_frame) = obfuscator.padding_frame() {
+            let encrypted_padding_frame = encrypt(&self.encoder_encryption, padding_frame)?;
+            obfuscator.encode_frame(&encrypted_padding_frame, dst)?;
         }
+        let encrypted_frame = encrypt(&self.encoder_encryption, obfuscator.data_frame(&frame))?;
+        obfuscator.encode_frame(&encrypted_frame, dst)
+    }
+}
+
+fn decrypt(encryption: &Encryption, bytes: BytesMut) -> Result<BytesMut, CommonError> {
+    match encryption {
+        Encryption::Plain => Ok(bytes),
+        Encryption::Aes(token) => Ok(BytesMut::from(decrypt_with_aes(token, &bytes)?)),
+        Encryption::Blowfish(token) => Ok(BytesMut::from(decrypt_with_blowfish(token, &bytes)?)),
+    }
+}
+
+fn encrypt(encryption: &Encryption, bytes: BytesMut) -> Result<Bytes, CommonError> {
+    match encryption {
+        Encryption::Plain => Ok(bytes.freeze()),
+        Encryption::Aes(token) => encrypt_with_aes(token, &bytes),
+        Encryption::Blowfish(token) => encrypt_with_blowfish(token, &bytes),
     }
 }
 
@@ -27,20 +112,29 @@ impl Decoder for CryptoLengthDelimitedCodec {
     type Item = BytesMut;
     type Error = CommonError;
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        let decrypted_bytes = self.length_delimited.decode(src)?;
-        match decrypted_bytes {
-            None => Ok(None),
-            Some(decrypted_bytes) => match self.decoder_encryption.as_ref() {
-                Encryption::Plain => Ok(Some(decrypted_bytes)),
-                Encryption::Aes(token) => {
-                    let raw_bytes = decrypt_with_aes(&token, &decrypted_bytes)?;
-                    Ok(Some(BytesMut::from(raw_bytes)))
-                }
-                Encryption::Blowfish(token) => {
-                    let raw_bytes = decrypt_with_blowfish(&token, &decrypted_bytes)?;
-                    Ok(Some(BytesMut::from(raw_bytes)))
+        let frame = loop {
+            let Some(frame) = self.decode_frame(src)? else {
+                return Ok(None);
+            };
+            if self.rekeyer.is_none() {
+                break frame;
+            }
+            match FrameRekeyer::unwrap_frame(frame)? {
+                RekeyFrame::Data(frame) => break frame,
+                RekeyFrame::Rekey(rekey_salt) => {
+                    debug!("Switch to new decoder encryption after rekey frame");
+                    let new_encryption =
+                        FrameRekeyer::derive_encryption(&self.decoder_encryption, &rekey_salt)?;
+                    if let Some(obfuscator) = self.obfuscator.as_mut() {
+                        obfuscator.reseed_decoder(&new_encryption)?;
+                    }
+                    self.decoder_encryption = Arc::new(new_encryption);
                 }
-            },
+            }
+        };
+        match &self.compressor {
+            None => Ok(Some(frame)),
+            Some(compressor) => Ok(Some(compressor.decompress_frame(frame)?)),
         }
     }
 }
@@ -48,16 +142,106 @@ impl Decoder for CryptoLengthDelimitedCodec {
 impl Encoder<BytesMut> for CryptoLengthDelimitedCodec {
     type Error = CommonError;
     fn encode(&mut self, item: BytesMut, dst: &mut BytesMut) -> Result<(), Self::Error> {
-        match self.encoder_encryption.as_ref() {
-            Encryption::Plain => Ok(self.length_delimited.encode(item.freeze(), dst)?),
-            Encryption::Aes(token) => {
-                let encrypted_bytes = encrypt_with_aes(token, &item)?;
-                Ok(self.length_delimited.encode(encrypted_bytes, dst)?)
-            }
-            Encryption::Blowfish(token) => {
-                let encrypted_bytes = encrypt_with_blowfish(token, &item)?;
-                Ok(self.length_delimited.encode(encrypted_bytes, dst)?)
+        let item = match &self.compressor {
+            None => item,
+            Some(compressor) => compressor.compress_frame(&item)?,
+        };
+        let Some(rekeyer) = self.rekeyer.as_mut() else {
+            return self.encode_frame(item, dst);
+        };
+        if let Some(rekey_salt) = rekeyer.next_rekey_salt(item.len()) {
+            // The rekey frame is the last frame encrypted with the current key
+            self.encode_frame(FrameRekeyer::rekey_frame(&rekey_salt), dst)?;
+            debug!("Switch to new encoder encryption after rekey frame");
+            let new_encryption =
+                FrameRekeyer::derive_encryption(&self.encoder_encryption, &rekey_salt)?;
+            if let Some(obfuscator) = self.obfuscator.as_mut() {
+                obfuscator.reseed_encoder(&new_encryption)?;
             }
+            self.encoder_encryption = Arc::new(new_encryption);
         }
+        self.encode_frame(FrameRekeyer::data_frame(&item), dst)
+    }
+}
+
+#[test]
+fn test() {
+    use crate::compression::CompressionCounter;
+    use crate::random_generate_encryption;
+    let agent_encryption = Arc::new(random_generate_encryption());
+    let proxy_encryption = Arc::new(random_generate_encryption());
+    let obfuscation = Some(ObfuscationConfig {
+        enabled: true,
+        padding_probability: 1.0,
+        min_padding_size: 16,
+        max_padding_size: 64,
+    });
+    let compressor = Some(FrameCompressor::new(
+        ppaass_protocol::Compression::Zstd,
+        4,
+        Arc::new(CompressionCounter::new("test")),
+    ));
+    // Switch the key before every frame after the first one
+    let rekey = Some(RekeyConfig {
+        enabled: true,
+        max_bytes: 1,
+        max_seconds: 0,
+    });
+    let mut agent_codec = CryptoLengthDelimitedCodec::new(
+        proxy_encryption.clone(),
+        agent_encryption.clone(),
+        obfuscation,
+        compressor.clone(),
+        rekey,
+        0,
+        FrameDirection::AgentToProxy,
+    )
+    .unwrap();
+    let mut proxy_codec = CryptoLengthDelimitedCodec::new(
+        agent_encryption.clone(),
+        proxy_encryption,
+        obfuscation,
+        compressor,
+        rekey,
+        0,
+        FrameDirection::ProxyToAgent,
+    )
+    .unwrap();
+    let mut wire = BytesMut::new();
+    for data in [&b"hello"[..], &b"world"[..]] {
+        agent_codec.encode(BytesMut::from(data), &mut wire).unwrap();
     }
+    // The length field is hidden, the last frame arrives in two parts
+    // after the rekey frame
+    let mut received = wire.split_to(wire.len() - 3);
+    assert_eq!(
+        BytesMut::from(&b"hello"[..]),
+        proxy_codec.decode(&mut received).unwrap().unwrap()
+    );
+    assert!(proxy_codec.decode(&mut received).unwrap().is_none());
+    received.unsplit(wire);
+    assert_eq!(
+        BytesMut::from(&b"world"[..]),
+        proxy_codec.decode(&mut received).unwrap().unwrap()
+    );
+    assert!(received.is_empty());
+    assert!(!Arc::ptr_eq(
+        &agent_encryption,
+        &agent_codec.encoder_encryption
+    ));
+    assert!(!Arc::ptr_eq(
+        &agent_encryption,
+        &proxy_codec.decoder_encryption
+    ));
+    // No key to derive the obfuscation keystream from
+    assert!(CryptoLengthDelimitedCodec::new(
+        Arc::new(Encryption::Plain),
+        Arc::new(Encryption::Plain),
+        obfuscation,
+        None,
+        None,
+        0,
+        FrameDirection::AgentToProxy,
+    )
+    .is_err());
 }
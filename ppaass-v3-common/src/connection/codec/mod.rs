@@ -1,6 +1,9 @@
 mod crypto;
 mod handshake;
+mod obfuscation;
+mod rekey;
 mod tunnel;
 pub use crypto::*;
 pub use handshake::*;
+pub(crate) use obfuscation::{FrameDirection, RELAY_KEYSTREAM};
 pub use tunnel::*;
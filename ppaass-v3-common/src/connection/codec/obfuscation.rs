@@ -0,0 +1,240 @@
+use crate::config::ObfuscationConfig;
+use crate::crypto::hkdf_derive;
+use crate::error::CommonError;
+use ppaass_protocol::Encryption;
+use rand::{random_bool, random_range};
+use rand_chacha::ChaCha20Rng;
+use rand_core::{RngCore, SeedableRng};
+use tokio_util::bytes::{Buf, BufMut, BytesMut};
+
+/// The keystream of the frames in tunnel control
+pub(crate) const TUNNEL_CONTROL_KEYSTREAM: u64 = 0;
+/// The keystream of the frames in relay, it is different from the tunnel
+/// control one so the keystream bytes are never reused on a connection
+pub(crate) const RELAY_KEYSTREAM: u64 = 1;
+/// Separate the keystream seed from any other key derived from the encryption
+const LENGTH_KEYSTREAM_LABEL: &[u8] = b"ppaass-v3 length keystream";
+const DATA_FRAME: u8 = 0;
+const PADDING_FRAME: u8 = 1;
+const LENGTH_FIELD_SIZE: usize = 4;
+/// Same as the max frame length of `LengthDelimitedCodec`
+const MAX_FRAME_LENGTH: usize = 8 * 1024 * 1024;
+
+/// The direction of the frames on a connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum FrameDirection {
+    AgentToProxy,
+    ProxyToAgent,
+}
+
+impl FrameDirection {
+    pub(crate) fn reverse(self) -> Self {
+        match self {
+            FrameDirection::AgentToProxy => FrameDirection::ProxyToAgent,
+            FrameDirection::ProxyToAgent => FrameDirection::AgentToProxy,
+        }
+    }
+
+    fn label(self) -> &'static [u8] {
+        match self {
+            FrameDirection::AgentToProxy => b"agent to proxy",
+            FrameDirection::ProxyToAgent => b"proxy to agent",
+        }
+    }
+}
+
+/// Generate the bytes to hide the frame lengths in one direction, both sides
+/// derive the seed from the encryption of the direction with HKDF so they get
+/// the same bytes. The obfuscation is never negotiated over plain encryption,
+/// there is no secret to derive the seed from.
+struct LengthKeystream(ChaCha20Rng);
+
+impl LengthKeystream {
+    fn new(
+        encryption: &Encryption,
+        keystream: u64,
+        direction: FrameDirection,
+    ) -> Result<Self, CommonError> {
+        let (Encryption::Aes(token) | Encryption::Blowfish(token)) = encryption else {
+            return Err(CommonError::Other(
+                "Can not obfuscate frames over plain encryption".to_string(),
+            ));
+        };
+        let mut seed = [0u8; 32];
+        hkdf_derive(
+            LENGTH_KEYSTREAM_LABEL,
+            token,
+            &[direction.label(), &keystream.to_be_bytes()],
+            &mut seed,
+        )?;
+        Ok(Self(ChaCha20Rng::from_seed(seed)))
+    }
+
+    fn apply(&mut self, length_field: &mut [u8; LENGTH_FIELD_SIZE]) {
+        let mut mask = [0u8; LENGTH_FIELD_SIZE];
+        self.0.fill_bytes(&mut mask);
+        length_field
+            .iter_mut()
+            .zip(mask)
+            .for_each(|(byte, mask)| *byte ^= mask);
+    }
+}
+
+/// Hide the length field of the frames with the keystream and mix random
+/// padding frames into the data frames. The frame body (before encryption)
+/// starts with one byte of the frame type, the padding frames are dropped
+/// by the receiver.
+pub(crate) struct FrameObfuscator {
+    config: ObfuscationConfig,
+    keystream: u64,
+    encoder_direction: FrameDirection,
+    decoder_keystream: LengthKeystream,
+    encoder_keystream: LengthKeystream,
+    /// The length of the frame whose length field is decoded but the body
+    /// is not fully received
+    pending_frame_length: Option<usize>,
+}
+
+impl FrameObfuscator {
+    pub(crate) fn new(
+        config: ObfuscationConfig,
+        decoder_encryption: &Encryption,
+        encoder_encryption: &Encryption,
+        keystream: u64,
+        encoder_direction: FrameDirection,
+    ) -> Result<Self, CommonError> {
+        Ok(Self {
+            config,
+            keystream,
+            encoder_direction,
+            decoder_keystream: LengthKeystream::new(
+                decoder_encryption,
+                keystream,
+                encoder_direction.reverse(),
+            )?,
+            encoder_keystream: LengthKeystream::new(
+                encoder_encryption,
+                keystream,
+                encoder_direction,
+            )?,
+            pending_frame_length: None,
+        })
+    }
+
+    /// Derive the keystream of the received frames again after the rekey
+    pub(crate) fn reseed_decoder(
+        &mut self,
+        decoder_encryption: &Encryption,
+    ) -> Result<(), CommonError> {
+        self.decoder_keystream = LengthKeystream::new(
+            decoder_encryption,
+            self.keystream,
+            self.encoder_direction.reverse(),
+        )?;
+        Ok(())
+    }
+
+    /// Derive the keystream of the sent frames again after the rekey
+    pub(crate) fn reseed_encoder(
+        &mut self,
+        encoder_encryption: &Encryption,
+    ) -> Result<(), CommonError> {
+        self.encoder_keystream =
+            LengthKeystream::new(encoder_encryption, self.keystream, self.encoder_direction)?;
+        Ok(())
+    }
+
+    /// Split the next frame body from the source
+    pub(crate) fn decode_frame(
+        &mut self,
+        src: &mut BytesMut,
+    ) -> Result<Option<BytesMut>, CommonError> {
+        let frame_length = match self.pending_frame_length {
+            Some(frame_length) => frame_length,
+            None => {
+                if src.len() < LENGTH_FIELD_SIZE {
+                    return Ok(None);
+                }
+                let mut length_field = [0u8; LENGTH_FIELD_SIZE];
+                src.copy_to_slice(&mut length_field);
+                self.decoder_keystream.apply(&mut length_field);
+                let frame_length = u32::from_be_bytes(length_field) as usize;
+                if frame_length > MAX_FRAME_LENGTH {
+                    return Err(CommonError::Other(format!(
+                        "Obfuscated frame length {frame_length} exceeds the max frame length"
+                    )));
+                }
+                self.pending_frame_length = Some(frame_length);
+                frame_length
+            }
+        };
+        if src.len() < frame_length {
+            src.reserve(frame_length - src.len());
+            return Ok(None);
+        }
+        self.pending_frame_length = None;
+        Ok(Some(src.split_to(frame_length)))
+    }
+
+    /// Write the frame body to the destination with the hidden length field
+    pub(crate) fn encode_frame(
+        &mut self,
+        frame: &[u8],
+        dst: &mut BytesMut,
+    ) -> Result<(), CommonError> {
+        if frame.len() > MAX_FRAME_LENGTH {
+            return Err(CommonError::Other(format!(
+                "Obfuscated frame length {} exceeds the max frame length",
+                frame.len()
+            )));
+        }
+        let mut length_field = (frame.len() as u32).to_be_bytes();
+        self.encoder_keystream.apply(&mut length_field);
+        dst.reserve(LENGTH_FIELD_SIZE + frame.len());
+        dst.put_slice(&length_field);
+        dst.put_slice(frame);
+        Ok(())
+    }
+
+    /// The padding frame to send before the next data frame, decided by the
+    /// padding probability
+    pub(crate) fn padding_frame(&self) -> Option<BytesMut> {
+        if !random_bool(self.config.padding_probability.clamp(0.0, 1.0)) {
+            return None;
+        }
+        let max_padding_size = self
+            .config
+            .max_padding_size
+            .max(self.config.min_padding_size);
+        let padding_size = random_range(self.config.min_padding_size..=max_padding_size);
+        let mut padding_frame = BytesMut::with_capacity(1 + padding_size);
+        padding_frame.put_u8(PADDING_FRAME);
+        padding_frame.resize(1 + padding_size, 0);
+        rand::fill(&mut padding_frame[1..]);
+        Some(padding_frame)
+    }
+
+    pub(crate) fn data_frame(&self, data: &[u8]) -> BytesMut {
+        let mut data_frame = BytesMut::with_capacity(1 + data.len());
+        data_frame.put_u8(DATA_FRAME);
+        data_frame.put_slice(data);
+        data_frame
+    }
+
+    /// The data of the decrypted frame, `None` for the padding frame
+    pub(crate) fn unwrap_frame(
+        &self,
+        mut frame: BytesMut,
+    ) -> Result<Option<BytesMut>, CommonError> {
+        if frame.is_empty() {
+            return Err(CommonError::Other("Obfuscated frame is empty".to_string()));
+        }
+        match frame.get_u8() {
+            DATA_FRAME => Ok(Some(frame)),
+            PADDING_FRAME => Ok(None),
+            frame_type => Err(CommonError::Other(format!(
+                "Unknown obfuscated frame type: {frame_type}"
+            ))),
+        }
+    }
+}
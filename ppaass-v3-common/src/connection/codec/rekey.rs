@@ -0,0 +1,154 @@
+use crate::config::RekeyConfig;
+use crate::crypto::hkdf_derive;
+use crate::error::CommonError;
+use ppaass_protocol::Encryption;
+use rand::random;
+use std::time::{Duration, Instant};
+use tokio_util::bytes::{Buf, BufMut, Bytes, BytesMut};
+
+const DATA_FRAME: u8 = 0;
+const REKEY_FRAME: u8 = 1;
+/// Separate the new key from any other key derived from the encryption
+const REKEY_LABEL: &[u8] = b"ppaass-v3 rekey";
+const REKEY_SALT_SIZE: usize = 32;
+
+/// The frame decoded when the rekey is negotiated
+pub(crate) enum RekeyFrame {
+    Data(BytesMut),
+    /// The random bytes to derive the encryption of the frames after it
+    Rekey(BytesMut),
+}
+
+/// Count the data sent with the current key and switch the key when the
+/// byte or time threshold is reached. The rekey frame carries fresh random
+/// bytes encrypted with the current key, both sides derive the new key from
+/// the current key and the random bytes, so the new key is never sent. The
+/// sender switches to the new key right after the rekey frame and the receiver
+/// switches right after decoding it, so the key changes at the same frame
+/// boundary on both sides.
+pub(crate) struct FrameRekeyer {
+    config: RekeyConfig,
+    sent_bytes: u64,
+    key_start_time: Instant,
+}
+
+impl FrameRekeyer {
+    pub(crate) fn new(config: RekeyConfig) -> Self {
+        Self {
+            config,
+            sent_bytes: 0,
+            key_start_time: Instant::now(),
+        }
+    }
+
+    /// The random bytes of the rekey frame to send before the frame
+    pub(crate) fn next_rekey_salt(&mut self, frame_size: usize) -> Option<[u8; REKEY_SALT_SIZE]> {
+        let bytes_reached = self.config.max_bytes > 0 && self.sent_bytes >= self.config.max_bytes;
+        let time_reached = self.config.max_seconds > 0
+            && self.key_start_time.elapsed() >= Duration::from_secs(self.config.max_seconds);
+        let rekey_salt = if bytes_reached || time_reached {
+            self.sent_bytes = 0;
+            self.key_start_time = Instant::now();
+            Some(random::<[u8; REKEY_SALT_SIZE]>())
+        } else {
+            None
+        };
+        self.sent_bytes += frame_size as u64;
+        rekey_salt
+    }
+
+    /// Derive the new encryption of the same algorithm from the current one
+    /// with HKDF, the plain encryption has no key to switch.
+    pub(crate) fn derive_encryption(
+        encryption: &Encryption,
+        rekey_salt: &[u8],
+    ) -> Result<Encryption, CommonError> {
+        let derive_token = |token: &Bytes| -> Result<Bytes, CommonError> {
+            let mut new_token = vec![0u8; token.len()];
+            hkdf_derive(REKEY_LABEL, token, &[rekey_salt], &mut new_token)?;
+            Ok(Bytes::from(new_token))
+        };
+        match encryption {
+            Encryption::Plain => Ok(Encryption::Plain),
+            Encryption::Aes(token) => Ok(Encryption::Aes(derive_token(token)?)),
+            Encryption::Blowfish(token) => Ok(Encryption::Blowfish(derive_token(token)?)),
+        }
+    }
+
+    pub(crate) fn rekey_frame(rekey_salt: &[u8]) -> BytesMut {
+        let mut rekey_frame = BytesMut::with_capacity(1 + rekey_salt.len());
+        rekey_frame.put_u8(REKEY_FRAME);
+        rekey_frame.put_slice(rekey_salt);
+        rekey_frame
+    }
+
+    pub(crate) fn data_frame(data: &[u8]) -> BytesMut {
+        let mut data_frame = BytesMut::with_capacity(1 + data.len());
+        data_frame.put_u8(DATA_FRAME);
+        data_frame.put_slice(data);
+        data_frame
+    }
+
+    pub(crate) fn unwrap_frame(mut frame: BytesMut) -> Result<RekeyFrame, CommonError> {
+        if frame.is_empty() {
+            return Err(CommonError::Other("Rekey frame is empty".to_string()));
+        }
+        match frame.get_u8() {
+            DATA_FRAME => Ok(RekeyFrame::Data(frame)),
+            REKEY_FRAME if frame.len() == REKEY_SALT_SIZE => Ok(RekeyFrame::Rekey(frame)),
+            REKEY_FRAME => Err(CommonError::Other(format!(
+                "Rekey frame has {} random bytes, expect {REKEY_SALT_SIZE}",
+                frame.len()
+            ))),
+            frame_type => Err(CommonError::Other(format!(
+                "Unknown rekey frame type: {frame_type}"
+            ))),
+        }
+    }
+}
+
+#[test]
+fn test() {
+    let mut rekeyer = FrameRekeyer::new(RekeyConfig {
+        enabled: true,
+        max_bytes: 10,
+        max_seconds: 0,
+    });
+    assert!(rekeyer.next_rekey_salt(6).is_none());
+    assert!(rekeyer.next_rekey_salt(6).is_none());
+    assert!(rekeyer.next_rekey_salt(6).is_some());
+    assert!(rekeyer.next_rekey_salt(6).is_none());
+    let mut rekeyer = FrameRekeyer::new(RekeyConfig {
+        enabled: true,
+        max_bytes: 0,
+        max_seconds: 60,
+    });
+    assert!(rekeyer.next_rekey_salt(1024 * 1024).is_none());
+    rekeyer.key_start_time = Instant::now() - Duration::from_secs(61);
+    assert!(rekeyer.next_rekey_salt(1).is_some());
+    assert!(rekeyer.next_rekey_salt(1).is_none());
+
+    let encryption = crate::random_generate_encryption();
+    let rekey_salt = random::<[u8; REKEY_SALT_SIZE]>();
+    let RekeyFrame::Rekey(received_salt) =
+        FrameRekeyer::unwrap_frame(FrameRekeyer::rekey_frame(&rekey_salt)).unwrap()
+    else {
+        panic!("Rekey frame is not decoded");
+    };
+    let new_encryption = FrameRekeyer::derive_encryption(&encryption, &rekey_salt).unwrap();
+    let received_encryption = FrameRekeyer::derive_encryption(&encryption, &received_salt).unwrap();
+    match (&encryption, &new_encryption, &received_encryption) {
+        (Encryption::Aes(token), Encryption::Aes(new_token), Encryption::Aes(received_token))
+        | (
+            Encryption::Blowfish(token),
+            Encryption::Blowfish(new_token),
+            Encryption::Blowfish(received_token),
+        ) => {
+            assert_eq!(new_token, received_token);
+            assert_eq!(token.len(), new_token.len());
+            assert_ne!(token, new_token);
+        }
+        _ => panic!("Rekey changes the encryption algorithm"),
+    }
+    assert!(FrameRekeyer::unwrap_frame(BytesMut::from(&[REKEY_FRAME, 0][..])).is_err());
+}
@@ -1,3 +1,6 @@
+use crate::compression::FrameCompressor;
+use crate::config::ObfuscationConfig;
+use crate::connection::codec::obfuscation::{FrameDirection, TUNNEL_CONTROL_KEYSTREAM};
 use crate::connection::codec::CryptoLengthDelimitedCodec;
 use crate::error::CommonError;
 use ppaass_protocol::{Encryption, TunnelControlRequest, TunnelControlResponse};
@@ -9,13 +12,24 @@ pub struct TunnelControlRequestResponseCodec {
 }
 
 impl TunnelControlRequestResponseCodec {
-    pub fn new(decoder_encryption: Arc<Encryption>, encoder_encryption: Arc<Encryption>) -> Self {
-        Self {
+    pub fn new(
+        decoder_encryption: Arc<Encryption>,
+        encoder_encryption: Arc<Encryption>,
+        obfuscation: Option<ObfuscationConfig>,
+        compressor: Option<FrameCompressor>,
+    ) -> Result<Self, CommonError> {
+        Ok(Self {
             crypto_length_delimited_codec: CryptoLengthDelimitedCodec::new(
                 decoder_encryption,
                 encoder_encryption,
-            ),
-        }
+                obfuscation,
+                compressor,
+                // The keys are switched only in relay
+                None,
+                TUNNEL_CONTROL_KEYSTREAM,
+                FrameDirection::ProxyToAgent,
+            )?,
+        })
     }
 }
 
@@ -5,12 +5,10 @@ use crate::connection::codec::CryptoLengthDelimitedCodec;
 use crate::error::CommonError;
 pub use agent::*;
 use futures_util::{Sink, SinkExt, Stream, StreamExt};
-use ppaass_protocol::Encryption;
 pub use proxy::*;
 use std::io::Error;
 use std::net::SocketAddr;
 use std::pin::Pin;
-use std::sync::Arc;
 use std::task::{Context, Poll};
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 use tokio::net::TcpStream;
@@ -31,14 +29,13 @@ where
 {
     pub fn new(
         tcp_stream: T,
-        decoder_encryption: Arc<Encryption>,
-        encoder_encryption: Arc<Encryption>,
+        crypto_length_delimited_codec: CryptoLengthDelimitedCodec,
         frame_buffer_size: usize,
     ) -> Self {
         Self {
             crypto_length_delimited_framed: Framed::with_capacity(
                 tcp_stream,
-                CryptoLengthDelimitedCodec::new(decoder_encryption, encoder_encryption),
+                crypto_length_delimited_codec,
                 frame_buffer_size,
             ),
         }
@@ -97,6 +94,10 @@ impl<S> FramedConnection<S> {
             frame_buffer_size,
         }
     }
+
+    pub fn socket_address(&self) -> SocketAddr {
+        self.socket_address
+    }
 }
 
 impl AsyncRead
@@ -1,23 +1,33 @@
 mod pool;
+mod selector;
+mod stats;
+use crate::compression::{CompressionCounter, FrameCompressor};
+use crate::config::{ObfuscationConfig, RekeyConfig, RetrieveConnectionConfig};
 use crate::connection::codec::{
-    HandshakeRequestEncoder, HandshakeResponseDecoder, TunnelControlResponseRequestCodec,
+    CryptoLengthDelimitedCodec, FrameDirection, HandshakeRequestEncoder, HandshakeResponseDecoder,
+    TunnelControlResponseRequestCodec, RELAY_KEYSTREAM,
 };
 use crate::connection::CryptoLengthDelimitedFramed;
 use crate::error::CommonError;
+use crate::replay::{seal_encryption, HANDSHAKE_NONCE_SIZE};
+use crate::rsa_pool::RsaPool;
+use crate::transcript::HandshakeTranscript;
 use crate::user::repo::fs::USER_INFO_ADDITION_INFO_PROXY_SERVERS;
 use crate::user::UserInfo;
 use crate::{
     parse_to_socket_addresses, random_generate_encryption, rsa_decrypt_encryption,
-    rsa_encrypt_encryption, FramedConnection,
+    rsa_encrypt_encryption, sort_addresses, staggered_connect, FramedConnection,
 };
-use bytes::BytesMut;
+use bytes::{Bytes, BytesMut};
 use chrono::Utc;
 use futures_util::{SinkExt, StreamExt};
 pub use pool::*;
 use ppaass_protocol::{
     Encryption, HandshakeRequest, HandshakeResponse, HeartbeatRequest, TunnelControlRequest,
-    TunnelControlResponse, TunnelInitFailureReason, TunnelInitRequest, TunnelInitResponse,
+    TunnelControlResponse, TunnelInitRequest, TunnelInitResponse,
 };
+pub use selector::*;
+pub use stats::*;
 use std::net::SocketAddr;
 use std::sync::Arc;
 use std::time::Duration;
@@ -25,7 +35,7 @@ use tokio::net::TcpStream;
 use tokio::time::timeout;
 use tokio_util::codec::{Framed, FramedParts};
 use tokio_util::io::{SinkWriter, StreamReader};
-use tracing::debug;
+use tracing::{debug, error};
 #[derive(Debug, Clone)]
 pub struct ProxyTcpConnectionInfo {
     proxy_address: SocketAddr,
@@ -51,55 +61,187 @@ pub struct ProxyTcpConnectionTunnelCtlState {
     tunnel_ctl_response_request_framed: Framed<TcpStream, TunnelControlResponseRequestCodec>,
     proxy_encryption: Arc<Encryption>,
     agent_encryption: Arc<Encryption>,
+    tunnel_init_deadline: u64,
+    obfuscation: Option<ObfuscationConfig>,
+    compressor: Option<FrameCompressor>,
+    rekey: Option<RekeyConfig>,
 }
 
-fn select_proxy_tcp_connection_info(
-    username: &str,
-    user_info: &UserInfo,
-) -> Result<ProxyTcpConnectionInfo, CommonError> {
-    let proxy_addresses = user_info
+fn parse_proxy_addresses(user_info: &UserInfo) -> Result<Vec<SocketAddr>, CommonError> {
+    Ok(parse_proxy_servers(user_info)?
+        .into_iter()
+        .flatten()
+        .collect())
+}
+
+/// The socket addresses of each proxy server entry in the user info, the
+/// entry can not be resolved is skipped.
+fn parse_proxy_servers(user_info: &UserInfo) -> Result<Vec<Vec<SocketAddr>>, CommonError> {
+    let proxy_servers = user_info
         .get_additional_info::<Vec<String>>(USER_INFO_ADDITION_INFO_PROXY_SERVERS)
         .ok_or(CommonError::Other(format!(
             "No proxy servers defined in user info configuration: {user_info:?}"
         )))?;
-    let proxy_addresses = parse_to_socket_addresses(proxy_addresses.iter())?;
-
-    let select_index = rand::random::<u64>() % proxy_addresses.len() as u64;
-    let proxy_address = proxy_addresses[select_index as usize];
-
-    Ok(ProxyTcpConnectionInfo::new(
-        proxy_address,
-        username.to_owned(),
-    ))
+    let mut proxy_server_addresses = Vec::with_capacity(proxy_servers.len());
+    for proxy_server in proxy_servers.iter() {
+        let proxy_addresses = parse_to_socket_addresses(std::iter::once(proxy_server))?;
+        if !proxy_addresses.is_empty() {
+            proxy_server_addresses.push(proxy_addresses);
+        }
+    }
+    Ok(proxy_server_addresses)
 }
 
 impl FramedConnection<ProxyTcpConnectionNewState> {
-    pub async fn create(
+    pub async fn create<C>(
         username: &str,
         user_info: &UserInfo,
-        frame_buffer_size: usize,
-        connect_timeout: u64,
-    ) -> Result<FramedConnection<ProxyTcpConnectionTunnelCtlState>, CommonError> {
-        let proxy_tcp_connection_info = select_proxy_tcp_connection_info(username, user_info)?;
-        let proxy_tcp_stream = timeout(
-            Duration::from_secs(connect_timeout),
-            TcpStream::connect(proxy_tcp_connection_info.proxy_address()),
-        )
-        .await??;
+        proxy_server_selector: &ProxyServerSelector,
+        rsa_pool: &RsaPool,
+        compression_counter: &Arc<CompressionCounter>,
+        config: &C,
+    ) -> Result<FramedConnection<ProxyTcpConnectionTunnelCtlState>, CommonError>
+    where
+        C: RetrieveConnectionConfig,
+    {
+        let mut proxy_servers = parse_proxy_servers(user_info)?;
+        let mut last_error = None;
+        // Fail over to the other proxy servers when the selected one fails on connect or handshake
+        while !proxy_servers.is_empty() {
+            let proxy_addresses = proxy_servers.iter().flatten().copied().collect::<Vec<_>>();
+            let Some(selected_proxy_address) = proxy_server_selector.select(&proxy_addresses)
+            else {
+                break;
+            };
+            let Some(selected_proxy_server) = proxy_servers
+                .iter()
+                .position(|proxy_server| proxy_server.contains(&selected_proxy_address))
+            else {
+                break;
+            };
+            // Only race the addresses of the selected proxy server, in the order given by the selector
+            // within each address family and the families interleaved as the happy eyeballs config
+            let mut candidates = proxy_servers.remove(selected_proxy_server);
+            candidates.retain(|candidate| *candidate != selected_proxy_address);
+            let mut ordered_proxy_addresses = vec![selected_proxy_address];
+            while let Some(proxy_address) = proxy_server_selector.select(&candidates) {
+                candidates.retain(|candidate| *candidate != proxy_address);
+                ordered_proxy_addresses.push(proxy_address);
+            }
+            let start_time = Utc::now();
+            let mut attempted_proxy_addresses = Vec::new();
+            let (proxy_tcp_stream, proxy_address) = match staggered_connect(
+                sort_addresses(&ordered_proxy_addresses, config.happy_eyeballs()),
+                config.happy_eyeballs(),
+                config.connect_timeout(),
+                &mut attempted_proxy_addresses,
+            )
+            .await
+            {
+                Ok(connect_result) => connect_result,
+                Err(e) => {
+                    error!("Fail to connect proxy server of {attempted_proxy_addresses:?}, try next proxy server: {e:?}");
+                    attempted_proxy_addresses.iter().for_each(|proxy_address| {
+                        proxy_server_selector.record_connect_failure(*proxy_address)
+                    });
+                    last_error = Some(e);
+                    continue;
+                }
+            };
+            match Self::concrete_create(
+                proxy_tcp_stream,
+                ProxyTcpConnectionInfo::new(proxy_address, username.to_owned()),
+                user_info,
+                rsa_pool,
+                compression_counter,
+                config,
+            )
+            .await
+            {
+                Ok(proxy_tcp_connection) => {
+                    let handshake_latency = Utc::now()
+                        .signed_duration_since(start_time)
+                        .num_milliseconds();
+                    proxy_server_selector.record_connect_success(proxy_address, handshake_latency);
+                    return Ok(proxy_tcp_connection);
+                }
+                Err(e) => {
+                    error!("Fail to create proxy connection to [{proxy_address}], try next proxy server: {e:?}");
+                    proxy_server_selector.record_connect_failure(proxy_address);
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error.unwrap_or(CommonError::Other(format!(
+            "No available proxy server in user info configuration: {user_info:?}"
+        ))))
+    }
+
+    async fn concrete_create<C>(
+        proxy_tcp_stream: TcpStream,
+        proxy_tcp_connection_info: ProxyTcpConnectionInfo,
+        user_info: &UserInfo,
+        rsa_pool: &RsaPool,
+        compression_counter: &Arc<CompressionCounter>,
+        config: &C,
+    ) -> Result<FramedConnection<ProxyTcpConnectionTunnelCtlState>, CommonError>
+    where
+        C: RetrieveConnectionConfig,
+    {
+        let frame_buffer_size = config.frame_size();
+        let deadline = config.deadline();
+        let obfuscation = config.obfuscation();
+        let compression = config.compression();
+        let compressions = if compression.enabled {
+            compression.algorithms.clone()
+        } else {
+            Vec::new()
+        };
         proxy_tcp_stream.set_nodelay(true)?;
         proxy_tcp_stream.set_linger(None)?;
         let proxy_socket_address = proxy_tcp_stream.peer_addr()?;
-        let agent_encryption = random_generate_encryption();
-        let encrypt_agent_encryption =
-            rsa_encrypt_encryption(&agent_encryption, user_info.rsa_crypto())?;
+        // Seal the timestamp and the nonce with the encryption key so the
+        // proxy can detect the replayed request
+        let timestamp = Utc::now().timestamp();
+        let nonce = Bytes::from(rand::random::<[u8; HANDSHAKE_NONCE_SIZE]>().to_vec());
+        let rsa_crypto = user_info.rsa_crypto().clone();
+        // All the RSA operations of the handshake run on the blocking threads
+        let (agent_encryption, encrypt_agent_encryption) = rsa_pool
+            .run({
+                let rsa_crypto = rsa_crypto.clone();
+                let nonce = nonce.clone();
+                move || {
+                    let agent_encryption = random_generate_encryption();
+                    let encrypt_agent_encryption = rsa_encrypt_encryption(
+                        &seal_encryption(&agent_encryption, timestamp, &nonce),
+                        &rsa_crypto,
+                    )?
+                    .into_owned();
+                    Ok((agent_encryption, encrypt_agent_encryption))
+                }
+            })
+            .await?;
         let mut handshake_request_framed =
             Framed::new(proxy_tcp_stream, HandshakeRequestEncoder::new());
         let handshake_request = HandshakeRequest {
             authentication: proxy_tcp_connection_info.authentication().to_owned(),
-            encryption: encrypt_agent_encryption.into_owned(),
+            encryption: encrypt_agent_encryption,
+            obfuscation: obfuscation.enabled,
+            compressions: compressions.clone(),
+            rekey: config.rekey().enabled,
+            timestamp,
+            nonce,
         };
+        let transcript = HandshakeTranscript::new(&handshake_request)?;
         debug!("Begin to send handshake request to proxy: {handshake_request:?}");
-        handshake_request_framed.send(handshake_request).await?;
+        timeout(
+            Duration::from_secs(deadline.handshake_request),
+            handshake_request_framed.send(handshake_request),
+        )
+        .await
+        .map_err(|_| {
+            CommonError::HandshakeRequestTimeout(proxy_socket_address, deadline.handshake_request)
+        })??;
         debug!("Success to send handshake request to proxy: {proxy_socket_address:?}");
         debug!("Begin to receive handshake response from proxy: {proxy_socket_address:?}");
         let FramedParts {
@@ -108,29 +250,81 @@ impl FramedConnection<ProxyTcpConnectionNewState> {
         } = handshake_request_framed.into_parts();
         let mut handshake_response_framed =
             Framed::new(proxy_tcp_stream, HandshakeResponseDecoder::new());
-        let HandshakeResponse {
-            encryption: proxy_encryption,
-        } = handshake_response_framed
-            .next()
-            .await
-            .ok_or(CommonError::ConnectionExhausted(proxy_socket_address))??;
+        let handshake_response = timeout(
+            Duration::from_secs(deadline.handshake_response),
+            handshake_response_framed.next(),
+        )
+        .await
+        .map_err(|_| {
+            CommonError::HandshakeResponseTimeout(proxy_socket_address, deadline.handshake_response)
+        })?
+        .ok_or(CommonError::ConnectionExhausted(proxy_socket_address))??;
         debug!("Success to receive handshake response from proxy: {proxy_socket_address:?}");
-        let proxy_encryption =
-            rsa_decrypt_encryption(&proxy_encryption, user_info.rsa_crypto())?.into_owned();
+        let (handshake_response, proxy_encryption) = rsa_pool
+            .run(move || {
+                // Fail before using anything in the response not signed by the proxy
+                transcript
+                    .verify(&handshake_response, &rsa_crypto)
+                    .inspect_err(|e| {
+                        error!("Fail to verify handshake response from proxy [{proxy_socket_address}]: {e:?}")
+                    })?;
+                let proxy_encryption =
+                    rsa_decrypt_encryption(&handshake_response.encryption, &rsa_crypto)?
+                        .into_owned();
+                Ok((handshake_response, proxy_encryption))
+            })
+            .await?;
+        let HandshakeResponse {
+            obfuscation: obfuscation_accepted,
+            compression: selected_compression,
+            rekey: rekey_accepted,
+            ..
+        } = handshake_response;
         let FramedParts {
             io: proxy_tcp_stream,
             ..
         } = handshake_response_framed.into_parts();
         let socket_address = proxy_tcp_stream.peer_addr()?;
+        let obfuscation = (obfuscation.enabled && obfuscation_accepted).then_some(*obfuscation);
+        if obfuscation.is_some() && matches!(proxy_encryption, Encryption::Plain) {
+            return Err(CommonError::Other(format!(
+                "Proxy accepted obfuscation over plain encryption: {proxy_socket_address}"
+            )));
+        }
+        let rekey = (config.rekey().enabled && rekey_accepted).then_some(*config.rekey());
+        let compressor = match selected_compression {
+            None => None,
+            Some(selected_compression) if compressions.contains(&selected_compression) => {
+                Some(FrameCompressor::new(
+                    selected_compression,
+                    compression.min_compress_size,
+                    compression_counter.clone(),
+                ))
+            }
+            Some(selected_compression) => {
+                return Err(CommonError::Other(format!(
+                    "Proxy selected compression {selected_compression:?} not supported by agent: {proxy_socket_address}"
+                )));
+            }
+        };
         let proxy_encryption = Arc::new(proxy_encryption);
         let agent_encryption = Arc::new(agent_encryption);
         Ok(FramedConnection {
             state: ProxyTcpConnectionTunnelCtlState {
                 proxy_encryption: proxy_encryption.clone(),
                 agent_encryption: agent_encryption.clone(),
+                tunnel_init_deadline: deadline.tunnel_init,
+                obfuscation,
+                compressor: compressor.clone(),
+                rekey,
                 tunnel_ctl_response_request_framed: Framed::with_capacity(
                     proxy_tcp_stream,
-                    TunnelControlResponseRequestCodec::new(proxy_encryption, agent_encryption),
+                    TunnelControlResponseRequestCodec::new(
+                        proxy_encryption,
+                        agent_encryption,
+                        obfuscation,
+                        compressor,
+                    )?,
                     frame_buffer_size,
                 ),
             },
@@ -150,6 +344,45 @@ impl FramedConnection<ProxyTcpConnectionTunnelCtlState> {
         >,
         CommonError,
     > {
+        let tunnel_init_deadline = self.state.tunnel_init_deadline;
+        let tunnel_init_response = timeout(
+            Duration::from_secs(tunnel_init_deadline),
+            self.exchange_tunnel_init(tunnel_init_request),
+        )
+        .await
+        .map_err(|_| CommonError::TunnelInitTimeout(self.socket_address, tunnel_init_deadline))??;
+        match tunnel_init_response {
+            TunnelInitResponse::Success => {
+                let FramedParts { io, .. } =
+                    self.state.tunnel_ctl_response_request_framed.into_parts();
+                Ok(FramedConnection {
+                    socket_address: self.socket_address,
+                    frame_buffer_size: self.frame_buffer_size,
+                    state: SinkWriter::new(StreamReader::new(CryptoLengthDelimitedFramed::new(
+                        io,
+                        CryptoLengthDelimitedCodec::new(
+                            self.state.proxy_encryption,
+                            self.state.agent_encryption,
+                            self.state.obfuscation,
+                            self.state.compressor,
+                            self.state.rekey,
+                            RELAY_KEYSTREAM,
+                            FrameDirection::AgentToProxy,
+                        )?,
+                        self.frame_buffer_size,
+                    ))),
+                })
+            }
+            TunnelInitResponse::Failure(reason) => Err(CommonError::TunnelInitRejected(reason)),
+        }
+    }
+
+    /// Send the tunnel init request and wait for the response, the heartbeat
+    /// responses of the previous checks are skipped.
+    async fn exchange_tunnel_init(
+        &mut self,
+        tunnel_init_request: TunnelInitRequest,
+    ) -> Result<TunnelInitResponse, CommonError> {
         let tunnel_ctl_request = TunnelControlRequest::TunnelInit(tunnel_init_request);
         self.state
             .tunnel_ctl_response_request_framed
@@ -175,34 +408,7 @@ impl FramedConnection<ProxyTcpConnectionTunnelCtlState> {
                     continue;
                 }
                 TunnelControlResponse::TunnelInit(tunnel_init_response) => {
-                    return match tunnel_init_response {
-                        TunnelInitResponse::Success => {
-                            let FramedParts { io, .. } =
-                                self.state.tunnel_ctl_response_request_framed.into_parts();
-                            Ok(FramedConnection {
-                                socket_address: self.socket_address,
-                                frame_buffer_size: self.frame_buffer_size,
-                                state: SinkWriter::new(StreamReader::new(
-                                    CryptoLengthDelimitedFramed::new(
-                                        io,
-                                        self.state.proxy_encryption,
-                                        self.state.agent_encryption,
-                                        self.frame_buffer_size,
-                                    ),
-                                )),
-                            })
-                        }
-                        TunnelInitResponse::Failure(TunnelInitFailureReason::AuthenticateFail) => {
-                            Err(CommonError::Other(format!(
-                                "Tunnel init fail on authenticate: {tunnel_init_response:?}",
-                            )))
-                        }
-                        TunnelInitResponse::Failure(
-                            TunnelInitFailureReason::InitWithDestinationFail,
-                        ) => Err(CommonError::Other(format!(
-                            "Tunnel init fail on connect destination: {tunnel_init_response:?}",
-                        ))),
-                    };
+                    return Ok(tunnel_init_response);
                 }
             }
         }
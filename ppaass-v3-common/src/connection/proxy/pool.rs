@@ -1,15 +1,29 @@
+use crate::compression::CompressionCounter;
 use crate::config::{RetrieveConnectionConfig, RetrieveConnectionPoolConfig};
+use crate::connection::proxy::parse_proxy_addresses;
+use crate::connection::proxy::stats::ProxyTcpConnectionPoolCounters;
 use crate::error::CommonError;
+use crate::event::ConnectionPoolEvent;
+use crate::rsa_pool::RsaPool;
 use crate::user::UserInfo;
-use crate::{FramedConnection, ProxyTcpConnectionNewState, ProxyTcpConnectionTunnelCtlState};
-use chrono::{DateTime, Utc};
+use crate::{
+    CryptoLengthDelimitedFramed, FramedConnection, ProxyServerSelector, ProxyTcpConnectionNewState,
+    ProxyTcpConnectionPoolSizing, ProxyTcpConnectionPoolStats, ProxyTcpConnectionTunnelCtlState,
+};
+use bytes::BytesMut;
+use chrono::{DateTime, Local, Utc};
+use ppaass_protocol::TunnelInitRequest;
 use std::cmp::Ordering;
+use std::collections::{HashMap, VecDeque};
 use std::fmt::Debug;
 use std::sync::Arc;
 use std::time::Duration;
-use tokio::sync::mpsc::channel;
-use tokio::sync::{Mutex, RwLock};
-use tokio::time::sleep;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::Sender;
+use tokio::sync::{oneshot, Mutex, Notify, RwLock};
+use tokio::task::JoinSet;
+use tokio::time::{sleep, timeout};
+use tokio_util::io::{SinkWriter, StreamReader};
 use tracing::{debug, error};
 struct ProxyTcpConnectionPoolElement<C>
 where
@@ -42,246 +56,680 @@ where
         let delta = now - self.last_check_time;
         delta.num_seconds() > self.config.check_interval() as i64
     }
+    pub fn need_check_on_take(&self) -> bool {
+        let now = Utc::now();
+        let delta = now - self.last_check_time;
+        delta.num_seconds() > self.config.take_check_threshold() as i64
+    }
     pub fn need_close(&self) -> bool {
         let now = Utc::now();
         let delta = now - self.create_time;
         delta.num_seconds() > self.config.connection_max_alive()
     }
 }
-/// The connection pool for proxy connection.
-pub struct ProxyTcpConnectionPool<C>
+
+/// The idle size to fill after `takes` takes since last sizing, `hits` of
+/// them served by idle proxy connection. It grows with the missed takes when
+/// the hit rate is under the target, shrinks by one when the idle proxy
+/// connections are more than the takes, and stays in `[min_idle, max_idle]`.
+fn next_target_idle(
+    target_idle: usize,
+    idle: usize,
+    takes: usize,
+    hits: usize,
+    min_idle: usize,
+    max_idle: usize,
+    target_hit_rate: f64,
+) -> usize {
+    let min_idle = min_idle.min(max_idle);
+    if takes > 0 && (hits as f64 / takes as f64) < target_hit_rate {
+        // Grow with the takes missed the idle proxy connection
+        (target_idle + (takes - hits).max(1)).min(max_idle)
+    } else if idle > takes {
+        target_idle.saturating_sub(1).max(min_idle)
+    } else {
+        target_idle
+    }
+    .max(min_idle)
+}
+
+/// The idle proxy connections and the takers waiting for a proxy connection,
+/// a new proxy connection goes to the first waiting taker before it goes idle.
+struct ProxyTcpConnectionPoolState<C>
 where
     C: RetrieveConnectionPoolConfig + RetrieveConnectionConfig + Debug + Send + Sync + 'static,
 {
-    /// The pool to store the proxy connection
-    pool: Arc<Mutex<Vec<ProxyTcpConnectionPoolElement<C>>>>,
+    idle: VecDeque<ProxyTcpConnectionPoolElement<C>>,
+    waiters: VecDeque<oneshot::Sender<ProxyTcpConnectionPoolElement<C>>>,
+    /// The proxy connections under creating
+    creating: usize,
+    /// The idle proxy connections taken out by the check task
+    checking: usize,
+    /// The proxy connections created since last filling
+    created_since_fill: usize,
+    /// The proxy connections failed to create since last filling
+    failed_since_fill: usize,
+    /// The idle size the pool is filling to, between `min_idle` and `max_idle`
+    target_idle: usize,
+    /// The takes since last sizing
+    takes_since_sizing: usize,
+    /// The takes served by idle proxy connection since last sizing
+    hits_since_sizing: usize,
+    last_sizing_time: DateTime<Utc>,
+    take_rate: f64,
+    hit_rate: f64,
+    last_sizing: ProxyTcpConnectionPoolSizing,
+    counters: ProxyTcpConnectionPoolCounters,
+}
+
+struct ProxyTcpConnectionPoolInner<C>
+where
+    C: RetrieveConnectionPoolConfig + RetrieveConnectionConfig + Debug + Send + Sync + 'static,
+{
+    state: Mutex<ProxyTcpConnectionPoolState<C>>,
+    /// Wake up the filling task when a taker starts waiting
+    fill_notify: Notify,
     config: Arc<C>,
     user_info: Arc<RwLock<UserInfo>>,
     username: String,
+    proxy_server_selector: Arc<ProxyServerSelector>,
+    rsa_pool: Arc<RsaPool>,
+    compression_counter: Arc<CompressionCounter>,
 }
-impl<C> ProxyTcpConnectionPool<C>
+
+impl<C> ProxyTcpConnectionPoolInner<C>
 where
     C: RetrieveConnectionPoolConfig + RetrieveConnectionConfig + Debug + Send + Sync + 'static,
 {
-    /// Create the proxy connection pool
-    pub async fn new(
-        config: Arc<C>,
-        username: &str,
-        user_info: Arc<RwLock<UserInfo>>,
-    ) -> Result<Self, CommonError> {
-        let pool = Arc::new(Mutex::new(Vec::new()));
-        let interval = config.fill_interval();
-        let pool_clone = pool.clone();
-        let user_info_clone = user_info.clone();
-        let config_clone = config.clone();
-        let username_clone = username.to_owned();
-        tokio::spawn(async move {
-            loop {
-                debug!("Starting connection pool auto filling loop.");
-                Self::fill_pool(
-                    pool_clone.clone(),
-                    config_clone.clone(),
-                    user_info_clone.clone(),
-                    &username_clone,
-                )
-                .await;
-                sleep(Duration::from_secs(interval)).await;
+    /// Give the proxy connection to the first waiting taker,
+    /// put it into idle queue when no taker is waiting.
+    async fn put(&self, mut proxy_tcp_connection_element: ProxyTcpConnectionPoolElement<C>) {
+        let mut state = self.state.lock().await;
+        while let Some(waiter) = state.waiters.pop_front() {
+            match waiter.send(proxy_tcp_connection_element) {
+                Ok(()) => return,
+                // The taker stopped waiting, try next one
+                Err(element) => proxy_tcp_connection_element = element,
             }
-        });
-        Self::start_connection_check_task(config.clone(), pool.clone());
-        Ok(Self {
-            pool,
-            config,
-            user_info,
-            username: username.to_owned(),
-        })
+        }
+        if state.idle.len() >= state.target_idle {
+            state.counters.record_eviction(
+                proxy_tcp_connection_element
+                    .proxy_tcp_connection
+                    .socket_address(),
+            );
+            drop(state);
+            tokio::spawn(async move {
+                if let Err(e) = proxy_tcp_connection_element
+                    .proxy_tcp_connection
+                    .close()
+                    .await
+                {
+                    error!("Failed to close proxy connection: {}", e);
+                };
+            });
+            return;
+        }
+        state.idle.push_back(proxy_tcp_connection_element);
     }
-    pub async fn take_proxy_connection(
+
+    /// Grow the target idle size when the hit rate of last sizing window is
+    /// lower than the target hit rate, shrink it by one when the idle proxy
+    /// connections are more than the takes, the sizing only happens once in
+    /// every filling interval.
+    fn resize(&self, state: &mut ProxyTcpConnectionPoolState<C>) {
+        let now = Utc::now();
+        let elapsed = (now - state.last_sizing_time).num_milliseconds();
+        if elapsed < (self.config.fill_interval() * 1000) as i64 {
+            return;
+        }
+        let takes = state.takes_since_sizing;
+        let hits = state.hits_since_sizing;
+        state.take_rate = takes as f64 * 1000f64 / elapsed.max(1) as f64;
+        state.hit_rate = if takes == 0 {
+            1f64
+        } else {
+            hits as f64 / takes as f64
+        };
+        let target_idle = next_target_idle(
+            state.target_idle,
+            state.idle.len(),
+            takes,
+            hits,
+            self.config.min_idle(),
+            self.config.max_idle(),
+            self.config.target_hit_rate(),
+        );
+        state.last_sizing = match target_idle.cmp(&state.target_idle) {
+            Ordering::Greater => {
+                ProxyTcpConnectionPoolSizing::Grow(target_idle - state.target_idle)
+            }
+            Ordering::Less => ProxyTcpConnectionPoolSizing::Shrink(state.target_idle - target_idle),
+            Ordering::Equal => ProxyTcpConnectionPoolSizing::Keep,
+        };
+        debug!(
+            "Resize proxy connection pool, take rate: {:.2}/s, hit rate: {:.2}, target idle: {} -> {}",
+            state.take_rate, state.hit_rate, state.target_idle, target_idle
+        );
+        state.target_idle = target_idle;
+        state.takes_since_sizing = 0;
+        state.hits_since_sizing = 0;
+        state.last_sizing_time = now;
+        // Discard the slowest idle proxy connections over the target
+        if state.idle.len() > target_idle {
+            let evicted = state.idle.split_off(target_idle);
+            for proxy_tcp_connection_element in evicted {
+                state.counters.record_eviction(
+                    proxy_tcp_connection_element
+                        .proxy_tcp_connection
+                        .socket_address(),
+                );
+            }
+        }
+    }
+
+    /// Fill the pool with proxy connection without waiting the creation result,
+    /// return true when the proxy connections can not be created since last filling.
+    async fn fill(self: &Arc<Self>) -> bool {
+        let proxy_addresses = match parse_proxy_addresses(&*self.user_info.read().await) {
+            Ok(proxy_addresses) => proxy_addresses,
+            Err(e) => {
+                error!("Fail to fill proxy connection pool: {e:?}");
+                return true;
+            }
+        };
+        if self.proxy_server_selector.all_down(&proxy_addresses) {
+            debug!(
+                "Cancel filling proxy connection pool, because the circuit breaker of all proxy servers are open: {proxy_addresses:?}"
+            );
+            return true;
+        }
+        let mut state = self.state.lock().await;
+        let fill_failed = state.created_since_fill == 0 && state.failed_since_fill > 0;
+        state.created_since_fill = 0;
+        state.failed_since_fill = 0;
+        self.resize(&mut state);
+        // The waiters timed out or cancelled do not need a proxy connection
+        state.waiters.retain(|waiter| !waiter.is_closed());
+        let target_size = state.target_idle.max(state.waiters.len());
+        // The proxy connections under checking come back to idle when they are alive
+        let creating_size =
+            target_size.saturating_sub(state.idle.len() + state.checking + state.creating);
+        if creating_size == 0 {
+            debug!(
+                "Cancel filling proxy connection pool, because the pool size reach target, idle: {}, checking: {}, creating: {}, target idle: {}",
+                state.idle.len(),
+                state.checking,
+                state.creating,
+                state.target_idle
+            );
+            return fill_failed;
+        }
+        debug!(
+            "Begin to fill proxy connection pool, idle: {}, checking: {}, creating: {}, waiting: {}",
+            state.idle.len(),
+            state.checking,
+            state.creating,
+            state.waiters.len()
+        );
+        state.creating += creating_size;
+        drop(state);
+        for _ in 0..creating_size {
+            let inner = self.clone();
+            tokio::spawn(async move { inner.fill_proxy_connection().await });
+        }
+        fill_failed
+    }
+
+    async fn create_proxy_connection(
         &self,
     ) -> Result<FramedConnection<ProxyTcpConnectionTunnelCtlState>, CommonError> {
-        Self::concrete_take_proxy_connection(
-            self.pool.clone(),
-            self.config.clone(),
-            self.user_info.clone(),
+        let user_info = self.user_info.read().await;
+        FramedConnection::<ProxyTcpConnectionNewState>::create(
             &self.username,
+            &user_info,
+            &self.proxy_server_selector,
+            &self.rsa_pool,
+            &self.compression_counter,
+            self.config.as_ref(),
         )
         .await
     }
 
+    /// Check the proxy connection with heartbeat, the proxy connection
+    /// failed on heartbeat is counted as evicted.
     async fn check_proxy_connection(
+        &self,
         proxy_tcp_connection_pool_element: &mut ProxyTcpConnectionPoolElement<C>,
-        config: &C,
     ) -> Result<(), CommonError> {
-        let check_duration = proxy_tcp_connection_pool_element
+        let proxy_address = proxy_tcp_connection_pool_element
+            .proxy_tcp_connection
+            .socket_address();
+        let check_duration = match proxy_tcp_connection_pool_element
             .proxy_tcp_connection
-            .heartbeat(config.heartbeat_timeout())
-            .await?;
+            .heartbeat(self.config.heartbeat_timeout())
+            .await
+        {
+            Ok(check_duration) => check_duration,
+            Err(e) => {
+                self.proxy_server_selector
+                    .record_heartbeat_failure(proxy_address);
+                self.state
+                    .lock()
+                    .await
+                    .counters
+                    .record_eviction(proxy_address);
+                return Err(e);
+            }
+        };
+        self.proxy_server_selector
+            .record_heartbeat(proxy_address, check_duration);
+        self.state
+            .lock()
+            .await
+            .counters
+            .record_heartbeat_rtt(proxy_address, check_duration);
         proxy_tcp_connection_pool_element.last_check_duration = check_duration;
+        proxy_tcp_connection_pool_element.last_check_time = Utc::now();
         Ok(())
     }
 
-    fn start_connection_check_task(
-        config: Arc<C>,
-        pool: Arc<Mutex<Vec<ProxyTcpConnectionPoolElement<C>>>>,
-    ) {
-        tokio::spawn(async move {
-            loop {
-                let mut pool_lock = pool.lock().await;
-                debug!(
-                    "Start checking connection pool loop, current pool size: {} ",
-                    pool_lock.len()
-                );
-                let (checking_tx, mut checking_rx) =
-                    channel::<ProxyTcpConnectionPoolElement<C>>(config.max_pool_size());
-                channel::<ProxyTcpConnectionPoolElement<C>>(config.max_pool_size());
-                'for_each_connection: loop {
-                    let mut proxy_tcp_connection_pool_element = match pool_lock.pop() {
-                        None => break 'for_each_connection,
-                        Some(proxy_tcp_connection_pool_element) => {
-                            proxy_tcp_connection_pool_element
-                        }
-                    };
-                    if !proxy_tcp_connection_pool_element.need_check() {
-                        if let Err(e) = checking_tx.send(proxy_tcp_connection_pool_element).await {
-                            error!("Fail to push proxy connection back to pool: {}", e);
-                        }
-                        continue 'for_each_connection;
-                    }
-                    if proxy_tcp_connection_pool_element.need_close() {
-                        continue 'for_each_connection;
-                    }
-                    let checking_tx = checking_tx.clone();
-                    let config = config.clone();
-                    tokio::spawn(async move {
-                        if let Err(e) = Self::check_proxy_connection(
-                            &mut proxy_tcp_connection_pool_element,
-                            &config,
-                        )
-                        .await
-                        {
-                            error!("Failed to check proxy connection: {}", e);
-                            return;
-                        };
-                        if let Err(e) = checking_tx.send(proxy_tcp_connection_pool_element).await {
-                            error!("Fail to push proxy connection back to pool: {}", e);
-                        };
-                    });
+    async fn fill_proxy_connection(&self) {
+        let create_result = self.create_proxy_connection().await;
+        {
+            let mut state = self.state.lock().await;
+            state.creating -= 1;
+            match &create_result {
+                Ok(proxy_tcp_connection) => {
+                    state.created_since_fill += 1;
+                    state
+                        .counters
+                        .record_created(proxy_tcp_connection.socket_address());
                 }
-                drop(checking_tx);
-                while let Some(mut proxy_connection) = checking_rx.recv().await {
-                    if pool_lock.len() >= config.max_pool_size() {
-                        tokio::spawn(async move {
-                            if let Err(e) = proxy_connection.proxy_tcp_connection.close().await {
-                                error!("Failed to close proxy connection: {}", e);
-                            };
-                        });
-                        continue;
-                    }
-                    pool_lock.push(proxy_connection);
+                Err(_) => {
+                    state.failed_since_fill += 1;
+                    state.counters.failed_creations += 1;
                 }
-                pool_lock.sort_by(|a, b| {
-                    let comp = a.last_check_duration.cmp(&b.last_check_duration);
-                    if Ordering::Equal == comp {
-                        a.last_check_time.cmp(&b.last_check_time)
-                    } else {
-                        comp
-                    }
-                });
-                drop(pool_lock);
-                sleep(Duration::from_secs(config.check_interval())).await;
             }
-        });
+        }
+        match create_result {
+            Ok(proxy_tcp_connection) => {
+                self.put(ProxyTcpConnectionPoolElement::new(
+                    proxy_tcp_connection,
+                    self.config.clone(),
+                ))
+                .await
+            }
+            Err(e) => {
+                error!("Failed to create proxy connection: {e}");
+            }
+        }
     }
-    /// The concrete take proxy connection implementation
-    async fn concrete_take_proxy_connection(
-        pool: Arc<Mutex<Vec<ProxyTcpConnectionPoolElement<C>>>>,
+}
+
+/// The connection pool for proxy connection.
+pub struct ProxyTcpConnectionPool<C>
+where
+    C: RetrieveConnectionPoolConfig + RetrieveConnectionConfig + Debug + Send + Sync + 'static,
+{
+    inner: Arc<ProxyTcpConnectionPoolInner<C>>,
+}
+impl<C> ProxyTcpConnectionPool<C>
+where
+    C: RetrieveConnectionPoolConfig + RetrieveConnectionConfig + Debug + Send + Sync + 'static,
+{
+    /// Create the proxy connection pool
+    pub async fn new(
         config: Arc<C>,
-        user_info: Arc<RwLock<UserInfo>>,
         username: &str,
-    ) -> Result<FramedConnection<ProxyTcpConnectionTunnelCtlState>, CommonError> {
+        user_info: Arc<RwLock<UserInfo>>,
+        proxy_server_selector: Arc<ProxyServerSelector>,
+        rsa_pool: Arc<RsaPool>,
+        compression_counter: Arc<CompressionCounter>,
+    ) -> Result<Self, CommonError> {
+        let inner = Arc::new(ProxyTcpConnectionPoolInner {
+            state: Mutex::new(ProxyTcpConnectionPoolState {
+                idle: VecDeque::new(),
+                waiters: VecDeque::new(),
+                creating: 0,
+                checking: 0,
+                created_since_fill: 0,
+                failed_since_fill: 0,
+                target_idle: config.min_idle().min(config.max_idle()),
+                takes_since_sizing: 0,
+                hits_since_sizing: 0,
+                last_sizing_time: Utc::now(),
+                take_rate: 0f64,
+                hit_rate: 1f64,
+                last_sizing: ProxyTcpConnectionPoolSizing::Keep,
+                counters: ProxyTcpConnectionPoolCounters::default(),
+            }),
+            fill_notify: Notify::new(),
+            config,
+            user_info,
+            username: username.to_owned(),
+            proxy_server_selector,
+            rsa_pool,
+            compression_counter,
+        });
+        Self::start_fill_task(inner.clone());
+        Self::start_connection_check_task(inner.clone());
+        Ok(Self { inner })
+    }
+
+    /// Take the first usable idle proxy connection, the expired idle proxy
+    /// connections are discarded, and the idle proxy connection not checked
+    /// in `take_check_threshold` seconds is checked with heartbeat before it
+    /// is returned as a hit.
+    async fn take_idle_proxy_connection(
+        &self,
+    ) -> Option<FramedConnection<ProxyTcpConnectionTunnelCtlState>> {
         loop {
-            let mut pool_lock = pool.lock().await;
-            debug!(
-                "Taking proxy connection, current pool size: {}",
-                pool_lock.len()
-            );
-            let proxy_tcp_connection_element = pool_lock.pop();
-            match proxy_tcp_connection_element {
-                None => {
-                    drop(pool_lock);
-                    Self::fill_pool(pool.clone(), config.clone(), user_info.clone(), username)
-                        .await;
-                    sleep(Duration::from_secs(config.retake_interval())).await;
-                    continue;
-                }
-                Some(proxy_tcp_connection_element) => {
-                    debug!(
-                        "Proxy connection available, current pool size before take: {}",
-                        pool_lock.len()
-                    );
-                    return Ok(proxy_tcp_connection_element.proxy_tcp_connection);
-                }
+            let mut proxy_tcp_connection_element = {
+                let mut state = self.inner.state.lock().await;
+                let proxy_tcp_connection_element = state.idle.pop_front()?;
+                debug!(
+                    "Proxy connection available, current pool size after take: {}",
+                    state.idle.len()
+                );
+                proxy_tcp_connection_element
+            };
+            if proxy_tcp_connection_element.need_close() {
+                debug!("Discard expired proxy connection on take.");
+                self.inner.state.lock().await.counters.record_eviction(
+                    proxy_tcp_connection_element
+                        .proxy_tcp_connection
+                        .socket_address(),
+                );
+                continue;
+            }
+            if proxy_tcp_connection_element.need_check_on_take()
+                && let Err(e) = self
+                    .inner
+                    .check_proxy_connection(&mut proxy_tcp_connection_element)
+                    .await
+            {
+                error!("Discard dead proxy connection on take: {e}");
+                continue;
             }
+            self.inner.state.lock().await.hits_since_sizing += 1;
+            return Some(proxy_tcp_connection_element.proxy_tcp_connection);
         }
     }
-    /// Fill the pool with proxy connection
-    async fn fill_pool(
-        pool: Arc<Mutex<Vec<ProxyTcpConnectionPoolElement<C>>>>,
-        config: Arc<C>,
-        user_info: Arc<RwLock<UserInfo>>,
-        username: &str,
-    ) {
-        let max_pool_size = config.max_pool_size();
-        let mut pool_lock = pool.lock().await;
-        if pool_lock.len() >= max_pool_size {
+
+    /// Take a proxy connection from the pool, when the pool is empty, wait
+    /// in FIFO order for a new proxy connection at most `max_take_wait` seconds.
+    ///
+    /// The idle proxy connection is validated as [`Self::take_idle_proxy_connection`]
+    /// before it is returned.
+    pub async fn take_proxy_connection(
+        &self,
+    ) -> Result<FramedConnection<ProxyTcpConnectionTunnelCtlState>, CommonError> {
+        {
+            let mut state = self.inner.state.lock().await;
+            state.takes_since_sizing += 1;
+            state.counters.takes += 1;
+        }
+        let max_take_wait = self.inner.config.max_take_wait();
+        let waiter = loop {
+            if let Some(proxy_tcp_connection) = self.take_idle_proxy_connection().await {
+                return Ok(proxy_tcp_connection);
+            }
+            let mut state = self.inner.state.lock().await;
+            if !state.idle.is_empty() {
+                // Just put back by the checking or filling task, validate it as well
+                continue;
+            }
+            let (waiter_tx, waiter_rx) = oneshot::channel();
+            state.waiters.push_back(waiter_tx);
+            state.counters.misses += 1;
             debug!(
-                "Cancel filling proxy connection pool, because the pool size exceed max, current pool size: {}, max pool size: {}",
-                pool_lock.len(),
-                max_pool_size
+                "No proxy connection available, waiting takers: {}",
+                state.waiters.len()
             );
-            return;
+            break waiter_rx;
+        };
+        self.inner.fill_notify.notify_one();
+        match timeout(Duration::from_secs(max_take_wait), waiter).await {
+            Ok(Ok(proxy_tcp_connection_element)) => {
+                Ok(proxy_tcp_connection_element.proxy_tcp_connection)
+            }
+            Ok(Err(_)) => Err(CommonError::Other(
+                "Proxy connection pool stopped when waiting for proxy connection.".to_string(),
+            )),
+            Err(_) => Err(CommonError::ProxyConnectionUnavailable(max_take_wait)),
         }
-        debug!(
-            "Begin to fill proxy connection pool, current pool size:{}",
-            pool_lock.len()
-        );
-        let (proxy_tcp_connection_tx, mut proxy_tcp_connection_rx) =
-            channel::<FramedConnection<ProxyTcpConnectionTunnelCtlState>>(max_pool_size);
-
-        for _ in pool_lock.len()..max_pool_size {
-            let proxy_tcp_connection_tx = proxy_tcp_connection_tx.clone();
+    }
 
-            let user_info = user_info.clone();
-            let config = config.clone();
-            let username = username.to_owned();
-            tokio::spawn(async move {
-                let user_info = user_info.read().await;
-                match FramedConnection::<ProxyTcpConnectionNewState>::create(
-                    &username,
-                    &user_info,
-                    config.frame_size(),
-                    config.connect_timeout(),
+    /// The snapshot of the pool statistics
+    pub async fn stats(&self) -> ProxyTcpConnectionPoolStats {
+        let proxy_server_health = self.inner.proxy_server_selector.health();
+        let state = self.inner.state.lock().await;
+        let mut proxy_server_idle = HashMap::new();
+        for proxy_tcp_connection_element in state.idle.iter() {
+            *proxy_server_idle
+                .entry(
+                    proxy_tcp_connection_element
+                        .proxy_tcp_connection
+                        .socket_address(),
                 )
-                .await
-                {
-                    Ok(proxy_tcp_connection) => {
-                        if let Err(e) = proxy_tcp_connection_tx.send(proxy_tcp_connection).await {
-                            error!("Fail to send proxy tcp connection: {e:?}")
-                        }
-                    }
-                    Err(e) => {
-                        error!("Failed to create proxy connection: {e}");
+                .or_insert(0) += 1;
+        }
+        ProxyTcpConnectionPoolStats {
+            idle: state.idle.len(),
+            creating: state.creating,
+            waiting: state.waiters.len(),
+            takes: state.counters.takes,
+            misses: state.counters.misses,
+            created: state.counters.created,
+            failed_creations: state.counters.failed_creations,
+            evictions: state.counters.evictions,
+            heartbeat_rtt: state.counters.heartbeat_rtt(),
+            target_idle: state.target_idle,
+            take_rate: state.take_rate,
+            hit_rate: state.hit_rate,
+            last_sizing: state.last_sizing,
+            proxy_servers: state
+                .counters
+                .proxy_servers(&proxy_server_idle, &proxy_server_health),
+        }
+    }
+
+    /// Publish the pool statistics as event when the pool changed,
+    /// the pool is sampled in every filling interval.
+    pub fn start_publish_events(self: &Arc<Self>, event_sender: Sender<ConnectionPoolEvent>) {
+        let pool = self.clone();
+        tokio::spawn(async move {
+            let mut last_published = None;
+            loop {
+                let stats = pool.stats().await;
+                let change = (
+                    stats.idle,
+                    stats.creating,
+                    stats.waiting,
+                    stats.takes,
+                    stats.created,
+                    stats.failed_creations,
+                    stats.evictions,
+                    stats.target_idle,
+                );
+                if last_published != Some(change) {
+                    last_published = Some(change);
+                    if let Err(e) = event_sender
+                        .send(ConnectionPoolEvent {
+                            timestamp: Local::now(),
+                            stats,
+                        })
+                        .await
+                    {
+                        error!("Fail to send connection pool event, stop publishing: {e:?}");
+                        return;
                     }
                 }
-            });
-        }
+                sleep(Duration::from_secs(
+                    pool.inner.config.fill_interval().max(1),
+                ))
+                .await;
+            }
+        });
+    }
+
+    /// Create a fresh proxy connection without going through the pool,
+    /// used to retry when the proxy connection taken from pool is dead.
+    pub async fn create_proxy_connection(
+        &self,
+    ) -> Result<FramedConnection<ProxyTcpConnectionTunnelCtlState>, CommonError> {
+        self.inner.create_proxy_connection().await
+    }
 
-        drop(proxy_tcp_connection_tx);
-        debug!("Waiting for proxy connection creation result.");
-        while let Some(proxy_tcp_connection) = proxy_tcp_connection_rx.recv().await {
-            let proxy_tcp_connection_element_to_push =
-                ProxyTcpConnectionPoolElement::new(proxy_tcp_connection, config.clone());
-            pool_lock.push(proxy_tcp_connection_element_to_push)
+    /// Initialize the tunnel on a proxy connection taken from the pool, the
+    /// pooled proxy connection may be dead already, so the initialization is
+    /// retried once on a fresh proxy connection unless the proxy rejected it.
+    pub async fn tunnel_init(
+        &self,
+        tunnel_init_request: TunnelInitRequest,
+    ) -> Result<
+        FramedConnection<
+            SinkWriter<StreamReader<CryptoLengthDelimitedFramed<TcpStream>, BytesMut>>,
+        >,
+        CommonError,
+    > {
+        match self
+            .take_proxy_connection()
+            .await?
+            .tunnel_init(tunnel_init_request.clone())
+            .await
+        {
+            Ok(proxy_tcp_connection) => Ok(proxy_tcp_connection),
+            Err(e @ CommonError::TunnelInitRejected(_)) => Err(e),
+            Err(e) => {
+                debug!(
+                    "Fail to initialize tunnel on pooled proxy connection, retry on a fresh one: {e:?}"
+                );
+                self.create_proxy_connection()
+                    .await?
+                    .tunnel_init(tunnel_init_request)
+                    .await
+            }
         }
     }
+
+    fn start_fill_task(inner: Arc<ProxyTcpConnectionPoolInner<C>>) {
+        tokio::spawn(async move {
+            let interval = inner.config.fill_interval();
+            let mut fill_interval = interval;
+            loop {
+                debug!("Starting connection pool auto filling loop.");
+                let fill_failed = inner.fill().await;
+                fill_interval = if fill_failed {
+                    // Exponential backoff when proxy connection can not be created
+                    (fill_interval.max(1) * 2).min(inner.config.max_fill_backoff().max(interval))
+                } else {
+                    interval
+                };
+                tokio::select! {
+                    _ = sleep(Duration::from_secs(fill_interval)) => {}
+                    _ = inner.fill_notify.notified() => {}
+                }
+            }
+        });
+    }
+
+    /// Check the idle proxy connections with heartbeat, the pool is only
+    /// locked when the connections are taken out and put back.
+    fn start_connection_check_task(inner: Arc<ProxyTcpConnectionPoolInner<C>>) {
+        tokio::spawn(async move {
+            loop {
+                let proxy_tcp_connections_to_check = {
+                    let mut state = inner.state.lock().await;
+                    debug!(
+                        "Start checking connection pool loop, current pool size: {} ",
+                        state.idle.len()
+                    );
+                    let mut proxy_tcp_connections_to_check = Vec::new();
+                    let mut proxy_tcp_connections_to_keep = VecDeque::new();
+                    while let Some(proxy_tcp_connection_pool_element) = state.idle.pop_front() {
+                        if proxy_tcp_connection_pool_element.need_close() {
+                            state.counters.record_eviction(
+                                proxy_tcp_connection_pool_element
+                                    .proxy_tcp_connection
+                                    .socket_address(),
+                            );
+                            continue;
+                        }
+                        if proxy_tcp_connection_pool_element.need_check() {
+                            proxy_tcp_connections_to_check.push(proxy_tcp_connection_pool_element);
+                        } else {
+                            proxy_tcp_connections_to_keep
+                                .push_back(proxy_tcp_connection_pool_element);
+                        }
+                    }
+                    state.idle = proxy_tcp_connections_to_keep;
+                    state.checking += proxy_tcp_connections_to_check.len();
+                    proxy_tcp_connections_to_check
+                };
+                let mut checking_tasks = JoinSet::new();
+                for mut proxy_tcp_connection_pool_element in proxy_tcp_connections_to_check {
+                    let inner = inner.clone();
+                    checking_tasks.spawn(async move {
+                        match inner
+                            .check_proxy_connection(&mut proxy_tcp_connection_pool_element)
+                            .await
+                        {
+                            Ok(()) => inner.put(proxy_tcp_connection_pool_element).await,
+                            Err(e) => error!("Failed to check proxy connection: {}", e),
+                        }
+                        inner.state.lock().await.checking -= 1;
+                    });
+                }
+                while checking_tasks.join_next().await.is_some() {}
+                {
+                    let mut state = inner.state.lock().await;
+                    state.idle.make_contiguous().sort_by(|a, b| {
+                        let comp = a.last_check_duration.cmp(&b.last_check_duration);
+                        if Ordering::Equal == comp {
+                            a.last_check_time.cmp(&b.last_check_time)
+                        } else {
+                            comp
+                        }
+                    });
+                }
+                sleep(Duration::from_secs(inner.config.check_interval())).await;
+            }
+        });
+    }
+}
+
+#[test]
+fn test() {
+    let (min_idle, max_idle, target_hit_rate) = (2, 5, 0.8);
+    // Grow with the missed takes
+    assert_eq!(
+        4,
+        next_target_idle(2, 0, 3, 1, min_idle, max_idle, target_hit_rate)
+    );
+    // Clamp to max idle
+    assert_eq!(
+        5,
+        next_target_idle(4, 0, 10, 0, min_idle, max_idle, target_hit_rate)
+    );
+    // Keep when the hit rate reaches the target
+    assert_eq!(
+        4,
+        next_target_idle(4, 1, 5, 4, min_idle, max_idle, target_hit_rate)
+    );
+    // Shrink when the idle proxy connections are more than the takes
+    assert_eq!(
+        4,
+        next_target_idle(5, 3, 1, 1, min_idle, max_idle, target_hit_rate)
+    );
+    // Clamp to min idle
+    assert_eq!(
+        2,
+        next_target_idle(2, 3, 0, 0, min_idle, max_idle, target_hit_rate)
+    );
+    assert_eq!(
+        2,
+        next_target_idle(0, 0, 0, 0, min_idle, max_idle, target_hit_rate)
+    );
 }
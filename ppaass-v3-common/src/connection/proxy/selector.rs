@@ -0,0 +1,282 @@
+use crate::config::{ProxySelectPolicy, ProxySelectorConfig};
+use chrono::{DateTime, TimeDelta, Utc};
+use rand::random;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use tracing::{debug, error};
+/// The latency used when a proxy server has not been measured.
+const DEFAULT_LATENCY: i64 = 100;
+/// The weight of the newest sample in the latency moving average.
+const LATENCY_SMOOTHING_FACTOR: f64 = 0.3;
+
+/// The health of one proxy server
+#[derive(Debug, Default, Clone)]
+pub struct ProxyServerHealth {
+    /// How many connections created to the proxy server successfully
+    pub connect_success: u64,
+    /// How many connections failed to create to the proxy server
+    pub connect_failure: u64,
+    /// How many heartbeats failed on the connections to the proxy server
+    pub heartbeat_failure: u64,
+    /// The failures happened since last success
+    pub consecutive_failure: u32,
+    /// How many times the circuit breaker opened since last success
+    pub open_count: u32,
+    /// The moving average of the handshake latency in milliseconds
+    pub handshake_latency: Option<i64>,
+    /// The moving average of the heartbeat round trip time in milliseconds
+    pub heartbeat_rtt: Option<i64>,
+    /// The circuit breaker is open until this time, the proxy
+    /// server will be skipped, after this time one more failure
+    /// opens the breaker again with a doubled duration
+    pub down_until: Option<DateTime<Utc>>,
+}
+
+impl ProxyServerHealth {
+    fn is_down(&self, now: DateTime<Utc>) -> bool {
+        match self.down_until {
+            None => false,
+            Some(down_until) => now < down_until,
+        }
+    }
+
+    /// The latency used to compare proxy servers, the heartbeat
+    /// round trip time is preferred because it is the latest
+    fn latency(&self) -> Option<i64> {
+        self.heartbeat_rtt.or(self.handshake_latency)
+    }
+
+    fn weight(&self) -> f64 {
+        let success_rate = (self.connect_success + 1) as f64
+            / (self.connect_success + self.connect_failure + 2) as f64;
+        let latency = self.latency().unwrap_or(DEFAULT_LATENCY).max(1);
+        success_rate * 1000f64 / latency as f64
+    }
+}
+
+fn moving_average(previous: Option<i64>, sample: i64) -> i64 {
+    match previous {
+        None => sample,
+        Some(previous) => {
+            (previous as f64 * (1f64 - LATENCY_SMOOTHING_FACTOR)
+                + sample as f64 * LATENCY_SMOOTHING_FACTOR) as i64
+        }
+    }
+}
+
+/// Select the proxy server to connect with the health of each
+/// proxy server, the proxy server whose circuit breaker is open
+/// will be skipped.
+#[derive(Debug)]
+pub struct ProxyServerSelector {
+    config: ProxySelectorConfig,
+    health: Mutex<HashMap<SocketAddr, ProxyServerHealth>>,
+    round_robin_index: AtomicUsize,
+}
+
+impl ProxyServerSelector {
+    pub fn new(config: ProxySelectorConfig) -> Self {
+        Self {
+            config,
+            health: Mutex::new(HashMap::new()),
+            round_robin_index: AtomicUsize::new(0),
+        }
+    }
+
+    /// Select one proxy server from the candidates, when all the
+    /// candidates are down, select from all of them.
+    pub fn select(&self, candidates: &[SocketAddr]) -> Option<SocketAddr> {
+        if candidates.is_empty() {
+            return None;
+        }
+        let health = match self.health.lock() {
+            Ok(health) => health,
+            Err(e) => {
+                error!("Fail to lock proxy server health: {e:?}");
+                return Some(candidates[random::<u64>() as usize % candidates.len()]);
+            }
+        };
+        let now = Utc::now();
+        let mut available = candidates
+            .iter()
+            .filter(|candidate| {
+                health
+                    .get(candidate)
+                    .map(|health| !health.is_down(now))
+                    .unwrap_or(true)
+            })
+            .map(|candidate| {
+                let candidate_health = health.get(candidate).cloned().unwrap_or_default();
+                (*candidate, candidate_health)
+            })
+            .collect::<Vec<_>>();
+        if available.is_empty() {
+            debug!("All proxy servers are marked down, select from all of them: {candidates:?}");
+            available = candidates
+                .iter()
+                .map(|candidate| {
+                    let candidate_health = health.get(candidate).cloned().unwrap_or_default();
+                    (*candidate, candidate_health)
+                })
+                .collect();
+        }
+        drop(health);
+        let selected = match self.config.policy {
+            ProxySelectPolicy::RoundRobin => {
+                let index = self.round_robin_index.fetch_add(1, Ordering::Relaxed);
+                available[index % available.len()].0
+            }
+            ProxySelectPolicy::LeastLatency => {
+                // The proxy server never measured is tried first
+                available
+                    .iter()
+                    .min_by_key(|(_, health)| health.latency().unwrap_or(0))
+                    .map(|(candidate, _)| *candidate)?
+            }
+            ProxySelectPolicy::WeightedRandom => {
+                let total_weight = available
+                    .iter()
+                    .map(|(_, health)| health.weight())
+                    .sum::<f64>();
+                let mut point = random::<f64>() * total_weight;
+                let mut selected = available[available.len() - 1].0;
+                for (candidate, health) in available.iter() {
+                    point -= health.weight();
+                    if point <= 0f64 {
+                        selected = *candidate;
+                        break;
+                    }
+                }
+                selected
+            }
+        };
+        Some(selected)
+    }
+
+    fn update_health<F>(&self, proxy_address: SocketAddr, update: F)
+    where
+        F: FnOnce(&mut ProxyServerHealth),
+    {
+        match self.health.lock() {
+            Ok(mut health) => update(health.entry(proxy_address).or_default()),
+            Err(e) => error!("Fail to lock proxy server health: {e:?}"),
+        }
+    }
+
+    /// Record a connection created to the proxy server successfully
+    pub fn record_connect_success(&self, proxy_address: SocketAddr, handshake_latency: i64) {
+        self.update_health(proxy_address, |health| {
+            health.connect_success += 1;
+            health.consecutive_failure = 0;
+            health.open_count = 0;
+            health.down_until = None;
+            health.handshake_latency =
+                Some(moving_average(health.handshake_latency, handshake_latency));
+        });
+    }
+
+    /// Record a connection failed to create to the proxy server
+    pub fn record_connect_failure(&self, proxy_address: SocketAddr) {
+        self.record_failure(proxy_address, |health| health.connect_failure += 1);
+    }
+
+    /// Record a heartbeat failure on a connection to the proxy server, it
+    /// does not change the connect success rate used by the weight but
+    /// still counts for the circuit breaker
+    pub fn record_heartbeat_failure(&self, proxy_address: SocketAddr) {
+        self.record_failure(proxy_address, |health| health.heartbeat_failure += 1);
+    }
+
+    fn record_failure<F>(&self, proxy_address: SocketAddr, count_failure: F)
+    where
+        F: FnOnce(&mut ProxyServerHealth),
+    {
+        let ProxySelectorConfig {
+            down_failure_threshold,
+            down_duration,
+            max_down_duration,
+            ..
+        } = self.config;
+        self.update_health(proxy_address, |health| {
+            let now = Utc::now();
+            count_failure(health);
+            health.consecutive_failure += 1;
+            if health.consecutive_failure < down_failure_threshold || health.is_down(now) {
+                return;
+            }
+            // Exponential backoff on the breaker open duration
+            let down_duration = down_duration
+                .saturating_mul(1u64 << health.open_count.min(16))
+                .min(max_down_duration);
+            health.open_count += 1;
+            debug!(
+                "Open circuit breaker of proxy server for {down_duration} seconds: {proxy_address}"
+            );
+            health.down_until = Some(now + TimeDelta::seconds(down_duration as i64));
+        });
+    }
+
+    /// Check if all the candidates are down
+    pub fn all_down(&self, candidates: &[SocketAddr]) -> bool {
+        let now = Utc::now();
+        match self.health.lock() {
+            Ok(health) => candidates.iter().all(|candidate| {
+                health
+                    .get(candidate)
+                    .map(|health| health.is_down(now))
+                    .unwrap_or(false)
+            }),
+            Err(e) => {
+                error!("Fail to lock proxy server health: {e:?}");
+                false
+            }
+        }
+    }
+
+    /// Record the heartbeat round trip time of a connection to the proxy server
+    pub fn record_heartbeat(&self, proxy_address: SocketAddr, heartbeat_rtt: i64) {
+        self.update_health(proxy_address, |health| {
+            health.heartbeat_rtt = Some(moving_average(health.heartbeat_rtt, heartbeat_rtt));
+        });
+    }
+
+    /// The health of all the proxy servers selected before
+    pub fn health(&self) -> HashMap<SocketAddr, ProxyServerHealth> {
+        match self.health.lock() {
+            Ok(health) => health.clone(),
+            Err(e) => {
+                error!("Fail to lock proxy server health: {e:?}");
+                HashMap::new()
+            }
+        }
+    }
+}
+
+#[test]
+fn test() {
+    let proxy_a: SocketAddr = "10.0.0.1:80".parse().unwrap();
+    let proxy_b: SocketAddr = "10.0.0.2:80".parse().unwrap();
+    let selector = ProxyServerSelector::new(ProxySelectorConfig {
+        policy: ProxySelectPolicy::LeastLatency,
+        down_failure_threshold: 2,
+        down_duration: 60,
+        max_down_duration: 600,
+    });
+    selector.record_connect_success(proxy_a, 200);
+    selector.record_connect_success(proxy_b, 20);
+    assert_eq!(Some(proxy_b), selector.select(&[proxy_a, proxy_b]));
+    selector.record_connect_failure(proxy_b);
+    assert_eq!(Some(proxy_b), selector.select(&[proxy_a, proxy_b]));
+    selector.record_heartbeat_failure(proxy_b);
+    assert_eq!(Some(proxy_a), selector.select(&[proxy_a, proxy_b]));
+    // All the proxy servers are down, still select one of them
+    assert_eq!(Some(proxy_b), selector.select(&[proxy_b]));
+    assert!(selector.all_down(&[proxy_b]));
+    assert!(!selector.all_down(&[proxy_a, proxy_b]));
+    let proxy_b_health = &selector.health()[&proxy_b];
+    assert_eq!(1, proxy_b_health.open_count);
+    assert_eq!(1, proxy_b_health.connect_failure);
+    assert_eq!(1, proxy_b_health.heartbeat_failure);
+}
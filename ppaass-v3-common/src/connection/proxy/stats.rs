@@ -0,0 +1,188 @@
+use crate::ProxyServerHealth;
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+/// How many heartbeat round trip time samples are kept for percentiles
+const MAX_HEARTBEAT_RTT_SAMPLES: usize = 1024;
+
+/// How the pool changed the target idle size at the last sizing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyTcpConnectionPoolSizing {
+    Keep,
+    /// Grow the target idle size by the given number
+    Grow(usize),
+    /// Shrink the target idle size by the given number
+    Shrink(usize),
+}
+
+/// The percentiles of the recent heartbeat round trip time in milliseconds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeartbeatRttPercentiles {
+    pub p50: i64,
+    pub p90: i64,
+    pub p99: i64,
+}
+
+/// The statistics of the pooled proxy connections to one proxy server
+#[derive(Debug, Clone, Default)]
+pub struct ProxyServerPoolStats {
+    /// The idle proxy connections to the proxy server
+    pub idle: usize,
+    /// The proxy connections created to the proxy server
+    pub created: u64,
+    /// The proxy connections to the proxy server discarded by the pool
+    pub evictions: u64,
+    pub heartbeat_rtt: Option<HeartbeatRttPercentiles>,
+    /// The health recorded by the proxy server selector
+    pub health: ProxyServerHealth,
+}
+
+/// The statistics of the proxy connection pool
+#[derive(Debug, Clone)]
+pub struct ProxyTcpConnectionPoolStats {
+    /// The idle proxy connections in the pool
+    pub idle: usize,
+    /// The proxy connections under creating
+    pub creating: usize,
+    /// The takers waiting for a proxy connection
+    pub waiting: usize,
+    /// The total takes from the pool
+    pub takes: u64,
+    /// The takes not served by an idle proxy connection
+    pub misses: u64,
+    /// The proxy connections created by the pool
+    pub created: u64,
+    /// The proxy connections the pool failed to create
+    pub failed_creations: u64,
+    /// The proxy connections discarded by the pool because they are
+    /// expired, dead on heartbeat or over the target idle size
+    pub evictions: u64,
+    pub heartbeat_rtt: Option<HeartbeatRttPercentiles>,
+    /// The idle size the pool is filling to
+    pub target_idle: usize,
+    /// The takes per second in the last sizing window
+    pub take_rate: f64,
+    /// The ratio of takes served by idle proxy connection
+    /// without waiting in the last sizing window
+    pub hit_rate: f64,
+    pub last_sizing: ProxyTcpConnectionPoolSizing,
+    pub proxy_servers: HashMap<SocketAddr, ProxyServerPoolStats>,
+}
+
+#[derive(Debug, Default)]
+struct ProxyServerPoolCounters {
+    created: u64,
+    evictions: u64,
+    heartbeat_rtt_samples: VecDeque<i64>,
+}
+
+/// The accumulated counters of the pool since it is created
+#[derive(Debug, Default)]
+pub(crate) struct ProxyTcpConnectionPoolCounters {
+    pub takes: u64,
+    pub misses: u64,
+    pub created: u64,
+    pub failed_creations: u64,
+    pub evictions: u64,
+    heartbeat_rtt_samples: VecDeque<i64>,
+    proxy_servers: HashMap<SocketAddr, ProxyServerPoolCounters>,
+}
+
+fn push_sample(samples: &mut VecDeque<i64>, sample: i64) {
+    if samples.len() >= MAX_HEARTBEAT_RTT_SAMPLES {
+        samples.pop_front();
+    }
+    samples.push_back(sample);
+}
+
+fn percentiles(samples: &VecDeque<i64>) -> Option<HeartbeatRttPercentiles> {
+    if samples.is_empty() {
+        return None;
+    }
+    let mut sorted = samples.iter().copied().collect::<Vec<_>>();
+    sorted.sort_unstable();
+    let percentile = |p: usize| sorted[((sorted.len() - 1) * p) / 100];
+    Some(HeartbeatRttPercentiles {
+        p50: percentile(50),
+        p90: percentile(90),
+        p99: percentile(99),
+    })
+}
+
+impl ProxyTcpConnectionPoolCounters {
+    pub fn record_created(&mut self, proxy_address: SocketAddr) {
+        self.created += 1;
+        self.proxy_servers.entry(proxy_address).or_default().created += 1;
+    }
+
+    pub fn record_eviction(&mut self, proxy_address: SocketAddr) {
+        self.evictions += 1;
+        self.proxy_servers
+            .entry(proxy_address)
+            .or_default()
+            .evictions += 1;
+    }
+
+    pub fn record_heartbeat_rtt(&mut self, proxy_address: SocketAddr, heartbeat_rtt: i64) {
+        push_sample(&mut self.heartbeat_rtt_samples, heartbeat_rtt);
+        push_sample(
+            &mut self
+                .proxy_servers
+                .entry(proxy_address)
+                .or_default()
+                .heartbeat_rtt_samples,
+            heartbeat_rtt,
+        );
+    }
+
+    pub fn heartbeat_rtt(&self) -> Option<HeartbeatRttPercentiles> {
+        percentiles(&self.heartbeat_rtt_samples)
+    }
+
+    /// The breakdown by proxy server with the idle count of each one
+    pub fn proxy_servers(
+        &self,
+        idle: &HashMap<SocketAddr, usize>,
+        health: &HashMap<SocketAddr, ProxyServerHealth>,
+    ) -> HashMap<SocketAddr, ProxyServerPoolStats> {
+        let mut proxy_servers = self
+            .proxy_servers
+            .iter()
+            .map(|(proxy_address, counters)| {
+                (
+                    *proxy_address,
+                    ProxyServerPoolStats {
+                        idle: 0,
+                        created: counters.created,
+                        evictions: counters.evictions,
+                        heartbeat_rtt: percentiles(&counters.heartbeat_rtt_samples),
+                        health: health.get(proxy_address).cloned().unwrap_or_default(),
+                    },
+                )
+            })
+            .collect::<HashMap<_, _>>();
+        for (proxy_address, idle) in idle {
+            proxy_servers.entry(*proxy_address).or_default().idle = *idle;
+        }
+        proxy_servers
+    }
+}
+
+#[test]
+fn test() {
+    let proxy_address: SocketAddr = "10.0.0.1:80".parse().unwrap();
+    let mut counters = ProxyTcpConnectionPoolCounters::default();
+    for heartbeat_rtt in 1..=100 {
+        counters.record_heartbeat_rtt(proxy_address, heartbeat_rtt);
+    }
+    counters.record_created(proxy_address);
+    counters.record_eviction(proxy_address);
+    let heartbeat_rtt = counters.heartbeat_rtt().unwrap();
+    assert_eq!(50, heartbeat_rtt.p50);
+    assert_eq!(90, heartbeat_rtt.p90);
+    assert_eq!(99, heartbeat_rtt.p99);
+    let proxy_servers =
+        counters.proxy_servers(&HashMap::from([(proxy_address, 2)]), &HashMap::new());
+    assert_eq!(2, proxy_servers[&proxy_address].idle);
+    assert_eq!(1, proxy_servers[&proxy_address].created);
+    assert_eq!(1, proxy_servers[&proxy_address].evictions);
+}
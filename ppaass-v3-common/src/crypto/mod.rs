@@ -2,10 +2,12 @@ mod aes;
 mod blowfish;
 mod rsa;
 
+use crate::error::CommonError;
 pub use aes::*;
 pub use blowfish::*;
 use hyper::body::Bytes;
 use rand::random;
+use ring::hkdf;
 pub use rsa::*;
 
 #[inline(always)]
@@ -13,3 +15,27 @@ fn random_n_bytes<const N: usize>() -> Bytes {
     let random_n_bytes = random::<[u8; N]>();
     random_n_bytes.to_vec().into()
 }
+
+/// The length of the key derived with HKDF
+struct DerivedKeyLength(usize);
+
+impl hkdf::KeyType for DerivedKeyLength {
+    fn len(&self) -> usize {
+        self.0
+    }
+}
+
+/// Fill the key with HKDF-SHA256, the label is used as the salt so the keys
+/// derived from the same secret for different usages are independent.
+pub(crate) fn hkdf_derive(
+    label: &[u8],
+    secret: &[u8],
+    info: &[&[u8]],
+    key: &mut [u8],
+) -> Result<(), CommonError> {
+    hkdf::Salt::new(hkdf::HKDF_SHA256, label)
+        .extract(secret)
+        .expand(info, DerivedKeyLength(key.len()))
+        .and_then(|okm| okm.fill(key))
+        .map_err(|_| CommonError::Other(format!("Fail to derive {} bytes key", key.len())))
+}
@@ -5,7 +5,7 @@ pub use rsa::pkcs8::EncodePublicKey;
 pub use rsa::pkcs8::LineEnding;
 pub use rsa::rand_core::OsRng;
 use rsa::{
-    Pkcs1v15Encrypt,
+    Pkcs1v15Encrypt, Pkcs1v15Sign,
     pkcs8::{DecodePrivateKey, DecodePublicKey},
 };
 pub use rsa::{RsaPrivateKey, RsaPublicKey};
@@ -14,6 +14,19 @@ pub const DEFAULT_AGENT_PRIVATE_KEY_PATH: &str = "AgentPrivateKey.pem";
 pub const DEFAULT_AGENT_PUBLIC_KEY_PATH: &str = "AgentPublicKey.pem";
 pub const DEFAULT_PROXY_PRIVATE_KEY_PATH: &str = "ProxyPrivateKey.pem";
 pub const DEFAULT_PROXY_PUBLIC_KEY_PATH: &str = "ProxyPublicKey.pem";
+/// The DER prefix of the SHA-256 DigestInfo in PKCS#1 v1.5 signature
+const SHA256_DIGEST_INFO_PREFIX: [u8; 19] = [
+    0x30, 0x31, 0x30, 0x0d, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x01, 0x05,
+    0x00, 0x04, 0x20,
+];
+const SHA256_DIGEST_SIZE: usize = 32;
+
+fn sha256_pkcs1v15_sign() -> Pkcs1v15Sign {
+    Pkcs1v15Sign {
+        hash_len: Some(SHA256_DIGEST_SIZE),
+        prefix: Box::new(SHA256_DIGEST_INFO_PREFIX),
+    }
+}
 
 /// The util to do RSA encryption and decryption.
 #[derive(Debug)]
@@ -58,4 +71,20 @@ impl RsaCrypto {
             .map_err(|e| CommonError::Rsa(format!("Fail to decrypt with agent_user: {e:?}")))?;
         Ok(result.into())
     }
+
+    /// Sign the SHA-256 digest with RSA private key
+    pub fn sign(&self, digest: &[u8]) -> Result<Bytes, CommonError> {
+        let result = self
+            .private_key
+            .sign(sha256_pkcs1v15_sign(), digest)
+            .map_err(|e| CommonError::Rsa(format!("Fail to sign with agent_user: {e:?}")))?;
+        Ok(result.into())
+    }
+
+    /// Verify the signature of the SHA-256 digest with RSA public key
+    pub fn verify(&self, digest: &[u8], signature: &[u8]) -> Result<(), CommonError> {
+        self.public_key
+            .verify(sha256_pkcs1v15_sign(), digest, signature)
+            .map_err(|e| CommonError::Rsa(format!("Fail to verify with agent_user: {e:?}")))
+    }
 }
@@ -0,0 +1,161 @@
+use crate::config::{DnsConfig, DnsIpStrategy};
+use crate::dns::{DnsLookup, DnsResolver};
+use crate::error::CommonError;
+use hickory_resolver::config::{LookupIpStrategy, NameServerConfig, ResolverConfig, ResolverOpts};
+use hickory_resolver::name_server::TokioConnectionProvider;
+use hickory_resolver::proto::xfer::Protocol;
+use hickory_resolver::proto::ProtoErrorKind;
+use hickory_resolver::TokioResolver;
+use std::time::{Duration, Instant};
+
+/// The DNS resolver based on hickory, query the configured upstream
+/// DNS servers or the servers in system configuration.
+pub struct HickoryDnsResolver {
+    resolver: TokioResolver,
+}
+
+impl HickoryDnsResolver {
+    pub fn new(config: &DnsConfig) -> Result<Self, CommonError> {
+        let mut builder = if config.upstream_servers.is_empty() {
+            TokioResolver::builder_tokio().map_err(|e| {
+                CommonError::Other(format!("Fail to read system dns configuration: {e}"))
+            })?
+        } else {
+            let mut resolver_config = ResolverConfig::new();
+            for upstream_server in config.upstream_servers.iter() {
+                for protocol in [Protocol::Udp, Protocol::Tcp] {
+                    let mut name_server_config = NameServerConfig::new(*upstream_server, protocol);
+                    name_server_config.trust_negative_responses = true;
+                    resolver_config.add_name_server(name_server_config);
+                }
+            }
+            TokioResolver::builder_with_config(resolver_config, TokioConnectionProvider::default())
+                .with_options(ResolverOpts::default())
+        };
+        let options = builder.options_mut();
+        options.timeout = Duration::from_secs(config.timeout);
+        options.ip_strategy = match config.ip_strategy {
+            DnsIpStrategy::Ipv4Only => LookupIpStrategy::Ipv4Only,
+            DnsIpStrategy::Ipv6Only => LookupIpStrategy::Ipv6Only,
+            DnsIpStrategy::Ipv4AndIpv6 => LookupIpStrategy::Ipv4AndIpv6,
+            DnsIpStrategy::Ipv6ThenIpv4 => LookupIpStrategy::Ipv6thenIpv4,
+            DnsIpStrategy::Ipv4ThenIpv6 => LookupIpStrategy::Ipv4thenIpv6,
+        };
+        // The lookup result is cached by the caller
+        options.cache_size = 0;
+        Ok(Self {
+            resolver: builder.build(),
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl DnsResolver for HickoryDnsResolver {
+    async fn lookup(&self, domain: &str) -> Result<DnsLookup, CommonError> {
+        match self.resolver.lookup_ip(domain).await {
+            Ok(lookup_ip) => Ok(DnsLookup {
+                addresses: lookup_ip.iter().collect(),
+                ttl: lookup_ip
+                    .valid_until()
+                    .saturating_duration_since(Instant::now()),
+            }),
+            Err(e) if e.is_no_records_found() => {
+                let negative_ttl = match e.proto().map(|e| e.kind()) {
+                    Some(ProtoErrorKind::NoRecordsFound {
+                        negative_ttl: Some(negative_ttl),
+                        ..
+                    }) => Duration::from_secs(*negative_ttl as u64),
+                    _ => Duration::MAX,
+                };
+                Ok(DnsLookup {
+                    addresses: Vec::new(),
+                    ttl: negative_ttl,
+                })
+            }
+            Err(e) => Err(CommonError::DnsResolve(domain.to_owned(), e.to_string())),
+        }
+    }
+}
+
+#[tokio::test]
+async fn test() {
+    use crate::dns::CachedDnsResolver;
+    use hickory_resolver::proto::op::{Message, MessageType, ResponseCode};
+    use hickory_resolver::proto::rr::rdata::A;
+    use hickory_resolver::proto::rr::{RData, Record, RecordType};
+    use std::collections::HashMap;
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use tokio::net::UdpSocket;
+    // The stub DNS server answers stub.test with 10.1.2.3 and NXDOMAIN for others
+    let stub_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let stub_address = stub_socket.local_addr().unwrap();
+    let query_count = Arc::new(AtomicUsize::new(0));
+    let stub_query_count = query_count.clone();
+    tokio::spawn(async move {
+        let mut buf = [0u8; 512];
+        loop {
+            let (size, client_address) = stub_socket.recv_from(&mut buf).await.unwrap();
+            let request = Message::from_vec(&buf[..size]).unwrap();
+            stub_query_count.fetch_add(1, Ordering::SeqCst);
+            let mut response = Message::new();
+            response
+                .set_id(request.id())
+                .set_message_type(MessageType::Response)
+                .set_op_code(request.op_code())
+                .set_recursion_desired(true)
+                .set_recursion_available(true)
+                .set_authoritative(true)
+                .add_queries(request.queries().to_vec());
+            let query = &request.queries()[0];
+            if query.name().to_ascii() == "stub.test." && query.query_type() == RecordType::A {
+                response.add_answer(Record::from_rdata(
+                    query.name().clone(),
+                    60,
+                    RData::A(A(Ipv4Addr::new(10, 1, 2, 3))),
+                ));
+            } else {
+                response.set_response_code(ResponseCode::NXDomain);
+            }
+            stub_socket
+                .send_to(&response.to_vec().unwrap(), client_address)
+                .await
+                .unwrap();
+        }
+    });
+    let config = DnsConfig {
+        upstream_servers: vec![stub_address],
+        ip_strategy: DnsIpStrategy::Ipv4Only,
+        static_overrides: HashMap::from([(
+            "Override.TEST.".to_string(),
+            vec![IpAddr::V4(Ipv4Addr::new(10, 3, 2, 1))],
+        )]),
+        ..DnsConfig::default()
+    };
+    let resolver = CachedDnsResolver::new(HickoryDnsResolver::new(&config).unwrap(), config);
+    let expected = vec![IpAddr::V4(Ipv4Addr::new(10, 1, 2, 3))];
+    assert_eq!(expected, *resolver.resolve("stub.test").await.unwrap());
+    assert_eq!(expected, *resolver.resolve("STUB.test.").await.unwrap());
+    assert_eq!(1, query_count.load(Ordering::SeqCst));
+    // The domain without address is cached as well
+    assert!(resolver.resolve("missing.test").await.is_err());
+    let query_count_after_missing = query_count.load(Ordering::SeqCst);
+    assert!(resolver.resolve("missing.test").await.is_err());
+    assert_eq!(
+        query_count_after_missing,
+        query_count.load(Ordering::SeqCst)
+    );
+    assert_eq!(
+        vec![IpAddr::V4(Ipv4Addr::new(10, 3, 2, 1))],
+        *resolver.resolve("override.test").await.unwrap()
+    );
+    assert_eq!(
+        vec![IpAddr::V4(Ipv4Addr::new(10, 3, 2, 1))],
+        *resolver.resolve("OVERRIDE.test.").await.unwrap()
+    );
+    assert_eq!(
+        query_count_after_missing,
+        query_count.load(Ordering::SeqCst)
+    );
+}
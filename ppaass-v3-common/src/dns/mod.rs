@@ -0,0 +1,195 @@
+mod hickory;
+use crate::config::DnsConfig;
+use crate::error::CommonError;
+pub use hickory::*;
+use ppaass_protocol::UnifiedAddress;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{debug, error};
+
+/// The addresses looked up for a domain, empty addresses
+/// means the domain has no address (NXDOMAIN or no record)
+#[derive(Debug, Clone)]
+pub struct DnsLookup {
+    pub addresses: Vec<IpAddr>,
+    /// How long the lookup result can be cached
+    pub ttl: Duration,
+}
+
+/// The resolver to look up the addresses of a domain without
+/// blocking the async runtime.
+#[async_trait::async_trait]
+pub trait DnsResolver: Send + Sync {
+    async fn lookup(&self, domain: &str) -> Result<DnsLookup, CommonError>;
+}
+
+/// The domain is case-insensitive and the trailing dot of the fully
+/// qualified domain is optional
+fn normalize_domain(domain: &str) -> String {
+    domain.trim_end_matches('.').to_lowercase()
+}
+
+struct DnsCacheEntry {
+    addresses: Arc<Vec<IpAddr>>,
+    expire_time: Instant,
+}
+
+/// Cache the lookup results of the inner resolver in their TTL, the
+/// domain without address is cached as well, the static overrides
+/// are returned without looking up.
+pub struct CachedDnsResolver<R>
+where
+    R: DnsResolver,
+{
+    inner: R,
+    config: DnsConfig,
+    cache: Mutex<HashMap<String, DnsCacheEntry>>,
+}
+
+impl<R> CachedDnsResolver<R>
+where
+    R: DnsResolver,
+{
+    pub fn new(inner: R, mut config: DnsConfig) -> Self {
+        config.static_overrides = config
+            .static_overrides
+            .into_iter()
+            .map(|(domain, addresses)| (normalize_domain(&domain), addresses))
+            .collect();
+        Self {
+            inner,
+            config,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn get_cached(&self, domain: &str) -> Option<Arc<Vec<IpAddr>>> {
+        let cache = match self.cache.lock() {
+            Ok(cache) => cache,
+            Err(e) => {
+                error!("Fail to lock dns cache: {e:?}");
+                return None;
+            }
+        };
+        let entry = cache.get(domain)?;
+        if entry.expire_time <= Instant::now() {
+            return None;
+        }
+        Some(entry.addresses.clone())
+    }
+
+    fn put_cached(&self, domain: &str, addresses: Arc<Vec<IpAddr>>, ttl: Duration) {
+        if self.config.cache_size == 0 || ttl.is_zero() {
+            return;
+        }
+        let mut cache = match self.cache.lock() {
+            Ok(cache) => cache,
+            Err(e) => {
+                error!("Fail to lock dns cache: {e:?}");
+                return;
+            }
+        };
+        let now = Instant::now();
+        if cache.len() >= self.config.cache_size {
+            cache.retain(|_, entry| entry.expire_time > now);
+        }
+        if cache.len() >= self.config.cache_size {
+            // Still full, drop the entry expiring first
+            let expiring_domain = cache
+                .iter()
+                .min_by_key(|(_, entry)| entry.expire_time)
+                .map(|(domain, _)| domain.clone());
+            if let Some(expiring_domain) = expiring_domain {
+                cache.remove(&expiring_domain);
+            }
+        }
+        cache.insert(
+            domain.to_owned(),
+            DnsCacheEntry {
+                addresses,
+                expire_time: now + ttl,
+            },
+        );
+    }
+
+    /// Resolve the domain into addresses, fail when the domain has no address
+    pub async fn resolve(&self, domain: &str) -> Result<Arc<Vec<IpAddr>>, CommonError> {
+        let domain = normalize_domain(domain);
+        let addresses = match self.config.static_overrides.get(&domain) {
+            Some(addresses) => Arc::new(addresses.clone()),
+            None => match self.get_cached(&domain) {
+                Some(addresses) => addresses,
+                None => {
+                    let DnsLookup { addresses, ttl } = self.inner.lookup(&domain).await?;
+                    let ttl = if addresses.is_empty() {
+                        ttl.min(Duration::from_secs(self.config.negative_ttl))
+                    } else {
+                        ttl.min(Duration::from_secs(self.config.max_ttl))
+                    };
+                    debug!("Resolve domain [{domain}] to {addresses:?}, ttl: {ttl:?}");
+                    let addresses = Arc::new(addresses);
+                    self.put_cached(&domain, addresses.clone(), ttl);
+                    addresses
+                }
+            },
+        };
+        if addresses.is_empty() {
+            return Err(CommonError::DnsResolve(
+                domain,
+                "No address found".to_string(),
+            ));
+        }
+        Ok(addresses)
+    }
+}
+
+#[async_trait::async_trait]
+impl<R> DnsResolver for CachedDnsResolver<R>
+where
+    R: DnsResolver,
+{
+    async fn lookup(&self, domain: &str) -> Result<DnsLookup, CommonError> {
+        let addresses = self.resolve(domain).await?;
+        Ok(DnsLookup {
+            addresses: addresses.as_ref().clone(),
+            ttl: Duration::ZERO,
+        })
+    }
+}
+
+/// Create the default resolver, the hickory resolver with cache
+pub fn create_dns_resolver(config: &DnsConfig) -> Result<Arc<dyn DnsResolver>, CommonError> {
+    let hickory_dns_resolver = HickoryDnsResolver::new(config)?;
+    Ok(Arc::new(CachedDnsResolver::new(
+        hickory_dns_resolver,
+        config.clone(),
+    )))
+}
+
+/// Resolve the unified address into socket addresses with the resolver
+pub async fn resolve_unified_address(
+    dns_resolver: &dyn DnsResolver,
+    unified_address: &UnifiedAddress,
+) -> Result<Vec<SocketAddr>, CommonError> {
+    match unified_address {
+        UnifiedAddress::SocketAddress(socket_address) => Ok(vec![*socket_address]),
+        UnifiedAddress::Domain { host, port } => {
+            if let Ok(ip_address) = host.parse::<IpAddr>() {
+                return Ok(vec![SocketAddr::new(ip_address, *port)]);
+            }
+            let DnsLookup { addresses, .. } = dns_resolver.lookup(host).await?;
+            if addresses.is_empty() {
+                return Err(CommonError::DnsResolve(
+                    host.clone(),
+                    "No address found".to_string(),
+                ));
+            }
+            Ok(addresses
+                .into_iter()
+                .map(|ip_address| SocketAddr::new(ip_address, *port))
+                .collect())
+        }
+    }
+}
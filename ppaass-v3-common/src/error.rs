@@ -1,4 +1,4 @@
-use ppaass_protocol::ProtocolError;
+use ppaass_protocol::{ProtocolError, TunnelInitFailureReason};
 use std::net::SocketAddr;
 use thiserror::Error;
 use tokio::time::error::Elapsed;
@@ -18,8 +18,38 @@ pub enum CommonError {
     RsaCryptoNotFound(String),
     #[error("User expired: {0}")]
     UserExpired(String),
+    #[error("Fail to resolve domain [{0}]: {1}")]
+    DnsResolve(String, String),
     #[error("Connection exhausted: {0}")]
     ConnectionExhausted(SocketAddr),
+    #[error("Handshake request not finished in {1} seconds: {0}")]
+    HandshakeRequestTimeout(SocketAddr, u64),
+    #[error("Handshake request replayed: {0}")]
+    HandshakeReplay(String),
+    #[error("Handshake response signature invalid: {0}")]
+    HandshakeSignature(String),
+    #[error("RSA pool overflow with {0} pending handshakes")]
+    RsaPoolOverflow(usize),
+    #[error("Handshake replay cache full with {0} nonces")]
+    ReplayCacheFull(usize),
+    #[error("Handshake response not finished in {1} seconds: {0}")]
+    HandshakeResponseTimeout(SocketAddr, u64),
+    #[error("Tunnel init not finished in {1} seconds: {0}")]
+    TunnelInitTimeout(SocketAddr, u64),
+    #[error("Connection idle for {1} seconds: {0}")]
+    IdleTimeout(SocketAddr, u64),
+    #[error("No proxy connection available after waiting {0} seconds")]
+    ProxyConnectionUnavailable(u64),
+    #[error("Connection limit exceeded: {0}")]
+    ConnectionLimitExceeded(String),
+    #[error("Traffic quota exceeded: {0}")]
+    QuotaExceeded(String),
+    #[error("Destination address blocked: {0}")]
+    DestinationBlocked(SocketAddr),
+    #[error("Destination forbidden by policy of user [{0}]: {1}")]
+    ForbiddenByPolicy(String, String),
+    #[error("Tunnel init rejected by proxy: {0:?}")]
+    TunnelInitRejected(TunnelInitFailureReason),
     #[error(transparent)]
     BincodeEncode(#[from] bincode::error::EncodeError),
     #[error(transparent)]
@@ -1,3 +1,4 @@
+use crate::ProxyTcpConnectionPoolStats;
 use chrono::{DateTime, Local};
 #[derive(Debug)]
 pub struct UploadSpeedEvent {
@@ -24,3 +25,10 @@ pub struct LogEvent {
     pub timestamp: DateTime<Local>,
     pub message: String,
 }
+
+/// The statistics published when the proxy connection pool changed
+#[derive(Debug)]
+pub struct ConnectionPoolEvent {
+    pub timestamp: DateTime<Local>,
+    pub stats: ProxyTcpConnectionPoolStats,
+}
@@ -0,0 +1,167 @@
+use crate::config::{AddressFamilyPreference, HappyEyeballsConfig};
+use crate::error::CommonError;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::task::JoinSet;
+use tokio::time::{sleep, timeout};
+use tracing::debug;
+
+/// Sort the addresses as RFC 8305 section 4, the first addresses are
+/// of the preferred family, then the two families are interleaved.
+pub fn sort_addresses(addresses: &[SocketAddr], config: &HappyEyeballsConfig) -> Vec<SocketAddr> {
+    let (preferred, other): (Vec<SocketAddr>, Vec<SocketAddr>) =
+        addresses
+            .iter()
+            .partition(|address| match config.prefer_family {
+                AddressFamilyPreference::Ipv6 => address.is_ipv6(),
+                AddressFamilyPreference::Ipv4 => address.is_ipv4(),
+            });
+    let first_count = config.first_address_family_count.max(1);
+    let mut preferred = preferred.into_iter();
+    let mut other = other.into_iter();
+    let mut sorted = Vec::with_capacity(addresses.len());
+    sorted.extend(preferred.by_ref().take(first_count));
+    loop {
+        match (other.next(), preferred.next()) {
+            (None, None) => break,
+            (other_address, preferred_address) => {
+                sorted.extend(other_address);
+                sorted.extend(preferred_address);
+            }
+        }
+    }
+    sorted
+}
+
+/// Connect to the first reachable address with Happy Eyeballs (RFC 8305),
+/// the connection attempts are staggered by the configured delay and a
+/// failed attempt starts the next one immediately, the whole procedure
+/// is limited by the connect timeout in seconds.
+pub async fn happy_eyeballs_connect(
+    addresses: &[SocketAddr],
+    config: &HappyEyeballsConfig,
+    connect_timeout: u64,
+) -> Result<(TcpStream, SocketAddr), CommonError> {
+    staggered_connect(
+        sort_addresses(addresses, config),
+        config,
+        connect_timeout,
+        &mut Vec::new(),
+    )
+    .await
+}
+
+/// Connect to the first reachable address as [`happy_eyeballs_connect`] but
+/// keep the order of the addresses, the addresses whose connection attempt
+/// started are pushed into `attempted`, the attempts not started because of
+/// an earlier success or the connect timeout are not in it.
+pub async fn staggered_connect(
+    addresses: Vec<SocketAddr>,
+    config: &HappyEyeballsConfig,
+    connect_timeout: u64,
+    attempted: &mut Vec<SocketAddr>,
+) -> Result<(TcpStream, SocketAddr), CommonError> {
+    if addresses.is_empty() {
+        return Err(CommonError::Other(
+            "No address to connect with happy eyeballs.".to_string(),
+        ));
+    }
+    timeout(
+        Duration::from_secs(connect_timeout),
+        race_connect(
+            addresses,
+            Duration::from_millis(config.connection_attempt_delay),
+            attempted,
+        ),
+    )
+    .await?
+}
+
+async fn race_connect(
+    addresses: Vec<SocketAddr>,
+    connection_attempt_delay: Duration,
+    attempted: &mut Vec<SocketAddr>,
+) -> Result<(TcpStream, SocketAddr), CommonError> {
+    let mut pending = addresses.into_iter().peekable();
+    let mut attempts = JoinSet::new();
+    let mut last_error = None;
+    loop {
+        if let Some(address) = pending.next() {
+            debug!("Start connection attempt to: {address}");
+            attempted.push(address);
+            attempts.spawn(async move { (address, TcpStream::connect(address).await) });
+        }
+        if attempts.is_empty() {
+            return Err(last_error.unwrap_or(CommonError::Other(
+                "All happy eyeballs connection attempts failed.".to_string(),
+            )));
+        }
+        // A failed attempt or the attempt delay starts the next attempt
+        tokio::select! {
+            Some(attempt) = attempts.join_next() => match attempt {
+                Ok((address, Ok(tcp_stream))) => {
+                    debug!("Connection attempt to [{address}] success.");
+                    return Ok((tcp_stream, address));
+                }
+                Ok((address, Err(e))) => {
+                    debug!("Connection attempt to [{address}] fail: {e:?}");
+                    last_error = Some(e.into());
+                }
+                Err(e) => {
+                    last_error = Some(CommonError::Other(format!(
+                        "Connection attempt task fail: {e:?}"
+                    )));
+                }
+            },
+            _ = sleep(connection_attempt_delay), if pending.peek().is_some() => {}
+            else => {}
+        }
+    }
+}
+
+#[tokio::test]
+async fn test() {
+    let v4_a: SocketAddr = "10.0.0.1:80".parse().unwrap();
+    let v4_b: SocketAddr = "10.0.0.2:80".parse().unwrap();
+    let v6_a: SocketAddr = "[fd00::1]:80".parse().unwrap();
+    let v6_b: SocketAddr = "[fd00::2]:80".parse().unwrap();
+    let v6_c: SocketAddr = "[fd00::3]:80".parse().unwrap();
+    let mut config = HappyEyeballsConfig::default();
+    assert_eq!(
+        vec![v6_a, v4_a, v6_b, v4_b, v6_c],
+        sort_addresses(&[v4_a, v4_b, v6_a, v6_b, v6_c], &config)
+    );
+    config.prefer_family = AddressFamilyPreference::Ipv4;
+    config.first_address_family_count = 2;
+    assert_eq!(
+        vec![v4_a, v4_b, v6_a, v6_b, v6_c],
+        sort_addresses(&[v4_a, v4_b, v6_a, v6_b, v6_c], &config)
+    );
+
+    // A refused address starts the next attempt without waiting for the delay
+    let refused_listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let refused_address = refused_listener.local_addr().unwrap();
+    drop(refused_listener);
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let listening_address = listener.local_addr().unwrap();
+    config.connection_attempt_delay = 10_000;
+    let mut attempted = Vec::new();
+    let (_, connected_address) = staggered_connect(
+        vec![refused_address, listening_address],
+        &config,
+        5,
+        &mut attempted,
+    )
+    .await
+    .unwrap();
+    assert_eq!(listening_address, connected_address);
+    assert_eq!(vec![refused_address, listening_address], attempted);
+    let mut attempted = Vec::new();
+    assert!(
+        staggered_connect(vec![refused_address], &config, 5, &mut attempted)
+            .await
+            .is_err()
+    );
+    assert_eq!(vec![refused_address], attempted);
+}
@@ -1,15 +1,24 @@
+pub mod ban;
+pub mod compression;
 pub mod config;
 mod connection;
 pub mod crypto;
+pub mod dns;
 pub mod error;
 pub mod event;
+mod happy_eyeballs;
+pub mod relay;
+pub mod replay;
+pub mod rsa_pool;
 pub mod server;
+mod transcript;
 pub mod user;
 use crate::crypto::{generate_aes_encryption_token, generate_blowfish_encryption_token, RsaCrypto};
 use crate::error::CommonError;
 use crate::event::{LogEvent, LogEventLevel};
 use chrono::{DateTime, Local, NaiveDateTime, NaiveTime};
 pub use connection::*;
+pub use happy_eyeballs::*;
 pub use ppaass_protocol::*;
 use rand::random;
 use std::borrow::Cow;
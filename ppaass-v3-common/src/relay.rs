@@ -0,0 +1,190 @@
+use std::future::pending;
+use std::io::Error;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{copy_bidirectional_with_sizes, AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::sleep;
+
+/// The buffer size used by `copy_bidirectional` of tokio
+pub const DEFAULT_RELAY_BUFFER_SIZE: usize = 8 * 1024;
+
+/// The result of a relay, the data sizes are the bytes read from each side
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RelayResult {
+    pub a_data_size: u64,
+    pub b_data_size: u64,
+    /// The relay is closed because no data is read from both sides
+    pub idle_timeout: bool,
+}
+
+/// Record the time of the last read with data on the stream
+struct IdleTrackedStream<'a, S> {
+    inner: &'a mut S,
+    relay_start_time: Instant,
+    last_read_time: &'a AtomicU64,
+    read_size: u64,
+}
+
+impl<S> AsyncRead for IdleTrackedStream<'_, S>
+where
+    S: AsyncRead + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let filled_before_read = buf.filled().len();
+        ready!(Pin::new(&mut *this.inner).poll_read(cx, buf))?;
+        let read_size = (buf.filled().len() - filled_before_read) as u64;
+        if read_size > 0 {
+            this.read_size += read_size;
+            this.last_read_time.store(
+                this.relay_start_time.elapsed().as_millis() as u64,
+                Ordering::Relaxed,
+            );
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S> AsyncWrite for IdleTrackedStream<'_, S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, Error>> {
+        Pin::new(&mut *self.get_mut().inner).poll_write(cx, buf)
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Pin::new(&mut *self.get_mut().inner).poll_flush(cx)
+    }
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Pin::new(&mut *self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// Copy data between the two streams in both directions, the relay is closed
+/// when no data is read from both sides for the idle timeout in seconds,
+/// 0 means the relay never times out.
+///
+/// The data sizes are returned together with the I/O error that stopped the
+/// relay, so the data relayed before a reset is still counted.
+pub async fn copy_bidirectional_with_idle_timeout<A, B>(
+    a: &mut A,
+    b: &mut B,
+    a_to_b_buffer_size: usize,
+    b_to_a_buffer_size: usize,
+    idle_timeout: u64,
+) -> (RelayResult, Option<Error>)
+where
+    A: AsyncRead + AsyncWrite + Unpin,
+    B: AsyncRead + AsyncWrite + Unpin,
+{
+    let idle_timeout = Duration::from_secs(idle_timeout);
+    let relay_start_time = Instant::now();
+    let last_read_time = AtomicU64::new(0);
+    let mut a = IdleTrackedStream {
+        inner: a,
+        relay_start_time,
+        last_read_time: &last_read_time,
+        read_size: 0,
+    };
+    let mut b = IdleTrackedStream {
+        inner: b,
+        relay_start_time,
+        last_read_time: &last_read_time,
+        read_size: 0,
+    };
+    let idle_check = async {
+        if idle_timeout.is_zero() {
+            return pending().await;
+        }
+        loop {
+            let idle_time = relay_start_time
+                .elapsed()
+                .saturating_sub(Duration::from_millis(
+                    last_read_time.load(Ordering::Relaxed),
+                ));
+            if idle_time >= idle_timeout {
+                return;
+            }
+            sleep(idle_timeout - idle_time).await;
+        }
+    };
+    let copy_result = tokio::select! {
+        copy_result = copy_bidirectional_with_sizes(
+            &mut a,
+            &mut b,
+            a_to_b_buffer_size,
+            b_to_a_buffer_size,
+        ) => Some(copy_result),
+        _ = idle_check => None,
+    };
+    match copy_result {
+        Some(Ok((a_data_size, b_data_size))) => (
+            RelayResult {
+                a_data_size,
+                b_data_size,
+                idle_timeout: false,
+            },
+            None,
+        ),
+        Some(Err(e)) => (
+            RelayResult {
+                a_data_size: a.read_size,
+                b_data_size: b.read_size,
+                idle_timeout: false,
+            },
+            Some(e),
+        ),
+        None => (
+            RelayResult {
+                a_data_size: a.read_size,
+                b_data_size: b.read_size,
+                idle_timeout: true,
+            },
+            None,
+        ),
+    }
+}
+
+#[tokio::test]
+async fn test() {
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+    let (mut a, mut a_peer) = duplex(1024);
+    let (mut b, mut b_peer) = duplex(1024);
+    let relay = tokio::spawn(async move {
+        copy_bidirectional_with_idle_timeout(&mut a, &mut b, 1024, 1024, 1).await
+    });
+    a_peer.write_all(b"hello").await.unwrap();
+    let mut buf = [0u8; 5];
+    b_peer.read_exact(&mut buf).await.unwrap();
+    assert_eq!(b"hello", &buf);
+    // Both peers are alive but no data for the idle timeout
+    let (relay_result, relay_error) = relay.await.unwrap();
+    assert!(relay_error.is_none());
+    assert!(relay_result.idle_timeout);
+    assert_eq!(5, relay_result.a_data_size);
+    assert_eq!(0, relay_result.b_data_size);
+    // The data relayed before the error is still counted
+    let (mut a, mut a_peer) = duplex(1024);
+    let (mut b, mut b_peer) = duplex(1024);
+    let relay = tokio::spawn(async move {
+        copy_bidirectional_with_idle_timeout(&mut a, &mut b, 1024, 1024, 0).await
+    });
+    a_peer.write_all(b"hello").await.unwrap();
+    b_peer.read_exact(&mut buf).await.unwrap();
+    drop(b_peer);
+    a_peer.write_all(b"world").await.unwrap();
+    let (relay_result, relay_error) = relay.await.unwrap();
+    assert!(relay_error.is_some());
+    assert!(!relay_result.idle_timeout);
+    assert_eq!(10, relay_result.a_data_size);
+}
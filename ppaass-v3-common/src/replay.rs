@@ -0,0 +1,226 @@
+use crate::config::ReplayConfig;
+use crate::error::CommonError;
+use bytes::{BufMut, Bytes, BytesMut};
+use chrono::Utc;
+use ppaass_protocol::Encryption;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+/// The size of the random nonce in the handshake request
+pub const HANDSHAKE_NONCE_SIZE: usize = 16;
+const TIMESTAMP_SIZE: usize = 8;
+
+/// Append the timestamp and the nonce to the encryption token, so they are
+/// protected by RSA together with the token.
+pub(crate) fn seal_encryption(encryption: &Encryption, timestamp: i64, nonce: &[u8]) -> Encryption {
+    let seal = |token: &Bytes| -> Bytes {
+        let mut sealed_token = BytesMut::with_capacity(token.len() + TIMESTAMP_SIZE + nonce.len());
+        sealed_token.put_slice(token);
+        sealed_token.put_i64(timestamp);
+        sealed_token.put_slice(nonce);
+        sealed_token.freeze()
+    };
+    match encryption {
+        Encryption::Plain => Encryption::Plain,
+        Encryption::Aes(token) => Encryption::Aes(seal(token)),
+        Encryption::Blowfish(token) => Encryption::Blowfish(seal(token)),
+    }
+}
+
+/// Remove the timestamp and the nonce from the RSA decrypted token, fail
+/// when they are different from the ones in the handshake request. The plain
+/// encryption can not protect them, it is never sent by the agents.
+pub(crate) fn unseal_encryption(
+    encryption: Encryption,
+    timestamp: i64,
+    nonce: &[u8],
+) -> Result<Encryption, CommonError> {
+    let unseal = |mut token: Bytes| -> Result<Bytes, CommonError> {
+        let seal_size = TIMESTAMP_SIZE + nonce.len();
+        if token.len() <= seal_size {
+            return Err(CommonError::HandshakeReplay(
+                "sealed encryption token is too short".to_string(),
+            ));
+        }
+        let seal = token.split_off(token.len() - seal_size);
+        let (sealed_timestamp, sealed_nonce) = seal.split_at(TIMESTAMP_SIZE);
+        if sealed_timestamp != timestamp.to_be_bytes() || sealed_nonce != nonce {
+            return Err(CommonError::HandshakeReplay(
+                "timestamp or nonce is different from the sealed one".to_string(),
+            ));
+        }
+        Ok(token)
+    };
+    match encryption {
+        Encryption::Plain => Err(CommonError::HandshakeReplay(
+            "plain encryption can not seal timestamp and nonce".to_string(),
+        )),
+        Encryption::Aes(token) => Ok(Encryption::Aes(unseal(token)?)),
+        Encryption::Blowfish(token) => Ok(Encryption::Blowfish(unseal(token)?)),
+    }
+}
+
+#[derive(Default)]
+struct SeenNonces {
+    timestamps: HashMap<Bytes, i64>,
+    /// The nonces in the order they are reserved, used to remove the ones out
+    /// of the clock skew window, the released nonces are skipped
+    order: VecDeque<(Bytes, i64)>,
+}
+
+/// Reject the handshake requests out of the clock skew window or with a
+/// nonce already seen. The nonce is reserved before any expensive work, so
+/// the copies of one request running at the same time are rejected, and it
+/// is released when the request is not authenticated, so the cache can not
+/// be filled by the requests of unknown users. The nonces in the clock skew
+/// window are never removed, the handshakes are rejected when the cache is
+/// full of them.
+pub struct ReplayGuard {
+    config: ReplayConfig,
+    seen_nonces: Mutex<SeenNonces>,
+}
+
+/// The nonce reserved by a handshake request, it is released on drop unless
+/// the request is authenticated
+pub struct NonceReservation<'a> {
+    replay_guard: &'a ReplayGuard,
+    nonce: Bytes,
+    timestamp: i64,
+    authenticated: bool,
+}
+
+impl NonceReservation<'_> {
+    /// Keep the nonce in the cache until it is out of the clock skew window
+    pub fn authenticated(mut self) {
+        self.authenticated = true;
+    }
+}
+
+impl Drop for NonceReservation<'_> {
+    fn drop(&mut self) {
+        if !self.authenticated {
+            self.replay_guard.release(&self.nonce, self.timestamp);
+        }
+    }
+}
+
+impl ReplayGuard {
+    pub fn new(config: ReplayConfig) -> Self {
+        Self {
+            config,
+            seen_nonces: Mutex::new(SeenNonces::default()),
+        }
+    }
+
+    fn check_timestamp(&self, timestamp: i64, now: i64) -> Result<(), CommonError> {
+        if timestamp.abs_diff(now) > self.config.max_clock_skew {
+            return Err(CommonError::HandshakeReplay(format!(
+                "timestamp {timestamp} is out of the {} seconds clock skew",
+                self.config.max_clock_skew
+            )));
+        }
+        Ok(())
+    }
+
+    /// The cheap check before any RSA operation, the nonce is reserved
+    /// for the request until the returned reservation is dropped
+    pub fn reserve(
+        &self,
+        timestamp: i64,
+        nonce: &[u8],
+    ) -> Result<NonceReservation<'_>, CommonError> {
+        if nonce.len() != HANDSHAKE_NONCE_SIZE {
+            return Err(CommonError::HandshakeReplay(format!(
+                "nonce size {} is not {HANDSHAKE_NONCE_SIZE}",
+                nonce.len()
+            )));
+        }
+        let now = Utc::now().timestamp();
+        self.check_timestamp(timestamp, now)?;
+        let mut seen_nonces = self
+            .seen_nonces
+            .lock()
+            .map_err(|e| CommonError::Other(format!("Fail to lock replay guard: {e}")))?;
+        let SeenNonces { timestamps, order } = &mut *seen_nonces;
+        // The nonces out of the clock skew window are rejected by the timestamp check
+        while let Some((oldest_nonce, oldest_timestamp)) = order.front() {
+            if self.check_timestamp(*oldest_timestamp, now).is_ok() {
+                break;
+            }
+            if timestamps.get(oldest_nonce) == Some(oldest_timestamp) {
+                timestamps.remove(oldest_nonce);
+            }
+            order.pop_front();
+        }
+        if timestamps.contains_key(nonce) {
+            return Err(CommonError::HandshakeReplay(
+                "nonce is replayed".to_string(),
+            ));
+        }
+        if timestamps.len() >= self.config.nonce_cache_size {
+            return Err(CommonError::ReplayCacheFull(timestamps.len()));
+        }
+        // Drop the released nonces from the order when they pile up
+        if order.len() >= self.config.nonce_cache_size.saturating_mul(2) {
+            order.retain(|(nonce, timestamp)| timestamps.get(nonce) == Some(timestamp));
+        }
+        let nonce = Bytes::copy_from_slice(nonce);
+        timestamps.insert(nonce.clone(), timestamp);
+        order.push_back((nonce.clone(), timestamp));
+        Ok(NonceReservation {
+            replay_guard: self,
+            nonce,
+            timestamp,
+            authenticated: false,
+        })
+    }
+
+    fn release(&self, nonce: &Bytes, timestamp: i64) {
+        let Ok(mut seen_nonces) = self.seen_nonces.lock() else {
+            return;
+        };
+        if seen_nonces.timestamps.get(nonce) == Some(&timestamp) {
+            seen_nonces.timestamps.remove(nonce);
+        }
+    }
+}
+
+#[test]
+fn test() {
+    let replay_guard = ReplayGuard::new(ReplayConfig {
+        max_clock_skew: 60,
+        nonce_cache_size: 2,
+    });
+    let now = Utc::now().timestamp();
+    let nonce = [1u8; HANDSHAKE_NONCE_SIZE];
+    assert!(replay_guard.reserve(now - 120, &nonce).is_err());
+    assert!(replay_guard.reserve(now, &nonce[..8]).is_err());
+    // The nonce is released when the request is not authenticated
+    drop(replay_guard.reserve(now, &nonce).unwrap());
+    let nonce_reservation = replay_guard.reserve(now, &nonce).unwrap();
+    // The copy of the request running at the same time is rejected
+    assert!(replay_guard.reserve(now, &nonce).is_err());
+    nonce_reservation.authenticated();
+    assert!(replay_guard.reserve(now, &nonce).is_err());
+    replay_guard
+        .reserve(now, &[2u8; HANDSHAKE_NONCE_SIZE])
+        .unwrap()
+        .authenticated();
+    // The nonces in the window are kept when the cache is full
+    assert!(matches!(
+        replay_guard.reserve(now, &[3u8; HANDSHAKE_NONCE_SIZE]),
+        Err(CommonError::ReplayCacheFull(2))
+    ));
+    assert!(matches!(
+        replay_guard.reserve(now, &nonce),
+        Err(CommonError::HandshakeReplay(_))
+    ));
+    let encryption = Encryption::Aes(Bytes::from_static(b"token"));
+    let sealed_encryption = seal_encryption(&encryption, now, &nonce);
+    assert!(unseal_encryption(sealed_encryption.clone(), now + 1, &nonce).is_err());
+    let Encryption::Aes(token) = unseal_encryption(sealed_encryption, now, &nonce).unwrap() else {
+        panic!("The unsealed encryption should be AES");
+    };
+    assert_eq!(Bytes::from_static(b"token"), token);
+    assert!(unseal_encryption(Encryption::Plain, now, &nonce).is_err());
+}
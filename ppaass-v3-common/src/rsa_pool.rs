@@ -0,0 +1,154 @@
+use crate::config::RsaPoolConfig;
+use crate::error::CommonError;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Semaphore;
+use tokio::task::spawn_blocking;
+use tokio::time::sleep;
+use tracing::info;
+
+/// The handshakes in the RSA pool
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RsaPoolStats {
+    /// The handshakes running the RSA operations on the blocking threads
+    pub running: usize,
+    /// The handshakes waiting for a blocking thread
+    pub queued: usize,
+    /// The handshakes rejected because the pool is full
+    pub rejected: u64,
+}
+
+/// Run the RSA operations of the handshakes on the blocking threads, so a
+/// connection storm does not stall the tunnels on the async worker threads.
+/// At most `max_concurrency` handshakes run at the same time, the others
+/// wait in the queue, and the handshake fails with
+/// [`CommonError::RsaPoolOverflow`] when `max_pending` handshakes are
+/// already waiting or running.
+pub struct RsaPool {
+    config: RsaPoolConfig,
+    semaphore: Arc<Semaphore>,
+    pending: AtomicUsize,
+    rejected: AtomicU64,
+}
+
+/// Release the pending handshake when it finishes or is dropped while waiting
+struct PendingGuard<'a>(&'a AtomicUsize);
+
+impl Drop for PendingGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+impl RsaPool {
+    pub fn new(config: RsaPoolConfig) -> Self {
+        Self {
+            semaphore: Arc::new(Semaphore::new(config.max_concurrency.max(1))),
+            config,
+            pending: AtomicUsize::new(0),
+            rejected: AtomicU64::new(0),
+        }
+    }
+
+    /// Run the RSA operations of one handshake on a blocking thread
+    pub async fn run<F, T>(&self, operation: F) -> Result<T, CommonError>
+    where
+        F: FnOnce() -> Result<T, CommonError> + Send + 'static,
+        T: Send + 'static,
+    {
+        let pending = self.pending.fetch_add(1, Ordering::AcqRel);
+        let _pending_guard = PendingGuard(&self.pending);
+        if pending >= self.config.max_pending {
+            self.rejected.fetch_add(1, Ordering::Relaxed);
+            return Err(CommonError::RsaPoolOverflow(pending));
+        }
+        let permit = self
+            .semaphore
+            .clone()
+            .acquire_owned()
+            .await
+            .map_err(|e| CommonError::Other(format!("Fail to acquire RSA pool permit: {e}")))?;
+        spawn_blocking(move || {
+            // The blocking thread keeps the permit even when the handshake is dropped
+            let _permit = permit;
+            operation()
+        })
+        .await
+        .map_err(|e| CommonError::Other(format!("Fail to run RSA operations: {e}")))?
+    }
+
+    pub fn stats(&self) -> RsaPoolStats {
+        let running = self.config.max_concurrency.max(1) - self.semaphore.available_permits();
+        RsaPoolStats {
+            running,
+            queued: self.pending.load(Ordering::Acquire).saturating_sub(running),
+            rejected: self.rejected.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn start_log_stats(self: &Arc<Self>) {
+        let pool = self.clone();
+        tokio::spawn(async move {
+            let mut last_stats = RsaPoolStats::default();
+            loop {
+                sleep(Duration::from_secs(pool.config.stats_interval)).await;
+                let stats = pool.stats();
+                if stats == last_stats {
+                    continue;
+                }
+                info!("Handshakes in RSA pool: {stats:?}");
+                last_stats = stats;
+            }
+        });
+    }
+}
+
+#[tokio::test]
+async fn test() {
+    let pool = Arc::new(RsaPool::new(RsaPoolConfig {
+        max_concurrency: 1,
+        max_pending: 2,
+        stats_interval: 60,
+    }));
+    assert_eq!(2, pool.run(|| Ok(1 + 1)).await.unwrap());
+    let (started_sender, started_receiver) = std::sync::mpsc::channel();
+    let (finish_sender, finish_receiver) = std::sync::mpsc::channel::<()>();
+    let running = tokio::spawn({
+        let pool = pool.clone();
+        async move {
+            pool.run(move || {
+                started_sender.send(()).unwrap();
+                finish_receiver.recv().unwrap();
+                Ok(())
+            })
+            .await
+        }
+    });
+    let queued = tokio::spawn({
+        let pool = pool.clone();
+        async move { pool.run(|| Ok(())).await }
+    });
+    spawn_blocking(move || started_receiver.recv().unwrap())
+        .await
+        .unwrap();
+    while pool.stats().queued == 0 {
+        tokio::task::yield_now().await;
+    }
+    assert!(matches!(
+        pool.run(|| Ok(())).await,
+        Err(CommonError::RsaPoolOverflow(2))
+    ));
+    assert_eq!(
+        RsaPoolStats {
+            running: 1,
+            queued: 1,
+            rejected: 1,
+        },
+        pool.stats()
+    );
+    finish_sender.send(()).unwrap();
+    running.await.unwrap().unwrap();
+    queued.await.unwrap().unwrap();
+    assert_eq!(0, pool.stats().running + pool.stats().queued);
+}
@@ -1,7 +1,11 @@
-use crate::config::RetrieveServerConfig;
+use crate::ban::BanTracker;
+use crate::config::{RetrieveServerConfig, ServerListenAddress};
 use crate::error::CommonError;
-use crate::event::{DownloadSpeedEvent, LogEvent, LogEventLevel, UploadSpeedEvent};
+use crate::event::{
+    ConnectionPoolEvent, DownloadSpeedEvent, LogEvent, LogEventLevel, UploadSpeedEvent,
+};
 use crate::publish_server_log_event;
+use socket2::{Domain, Protocol, Socket, Type};
 use std::any::{Any, TypeId};
 use std::collections::HashMap;
 use std::future::Future;
@@ -9,7 +13,9 @@ use std::net::SocketAddr;
 use std::sync::Arc;
 use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::task::JoinSet;
 use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, trace, warn};
 pub struct ServerState {
     values: HashMap<TypeId, Arc<dyn Any + Send + Sync + 'static>>,
 }
@@ -38,9 +44,39 @@ pub struct ServerGuard {
     pub upload_speed_event_receiver: Receiver<UploadSpeedEvent>,
     pub download_speed_event_receiver: Receiver<DownloadSpeedEvent>,
     pub log_event_receiver: Receiver<LogEvent>,
+    pub connection_pool_event_receiver: Receiver<ConnectionPoolEvent>,
     pub stop_signal: CancellationToken,
 }
 
+/// Write the server events into the log
+pub fn consume_server_events(server_guard: ServerGuard) {
+    let ServerGuard {
+        mut upload_speed_event_receiver,
+        mut download_speed_event_receiver,
+        mut log_event_receiver,
+        mut connection_pool_event_receiver,
+        ..
+    } = server_guard;
+    tokio::spawn(async move { while upload_speed_event_receiver.recv().await.is_some() {} });
+    tokio::spawn(async move { while download_speed_event_receiver.recv().await.is_some() {} });
+    tokio::spawn(async move {
+        while let Some(log_event) = log_event_receiver.recv().await {
+            match log_event.level {
+                LogEventLevel::Error => error!("{}", log_event.message),
+                LogEventLevel::Warning => warn!("{}", log_event.message),
+                LogEventLevel::Info => info!("{}", log_event.message),
+                LogEventLevel::Debug => debug!("{}", log_event.message),
+                LogEventLevel::Trace => trace!("{}", log_event.message),
+            }
+        }
+    });
+    tokio::spawn(async move {
+        while let Some(connection_pool_event) = connection_pool_event_receiver.recv().await {
+            debug!("Connection pool changed: {:?}", connection_pool_event.stats);
+        }
+    });
+}
+
 pub struct Server<C>
 where
     C: RetrieveServerConfig + Send + Sync + 'static,
@@ -50,6 +86,7 @@ where
     upload_speed_event_sender: Sender<UploadSpeedEvent>,
     download_speed_event_sender: Sender<DownloadSpeedEvent>,
     log_event_sender: Sender<LogEvent>,
+    connection_pool_event_sender: Sender<ConnectionPoolEvent>,
     stop_signal: CancellationToken,
 }
 
@@ -63,6 +100,8 @@ where
         let (download_speed_event_sender, download_speed_event_receiver) =
             channel::<DownloadSpeedEvent>(1024);
         let (log_event_sender, log_event_receiver) = channel::<LogEvent>(1024);
+        let (connection_pool_event_sender, connection_pool_event_receiver) =
+            channel::<ConnectionPoolEvent>(1024);
         let stop_signal = CancellationToken::new();
         (
             Self {
@@ -71,12 +110,14 @@ where
                 upload_speed_event_sender,
                 download_speed_event_sender,
                 log_event_sender,
+                connection_pool_event_sender,
                 stop_signal: stop_signal.clone(),
             },
             ServerGuard {
                 upload_speed_event_receiver,
                 download_speed_event_receiver,
                 log_event_receiver,
+                connection_pool_event_receiver,
                 stop_signal,
             },
         )
@@ -87,15 +128,23 @@ where
     fn server_state(&self) -> Arc<ServerState> {
         self.server_state.clone()
     }
+    /// The sender to publish the server log events
+    pub fn log_event_sender(&self) -> Sender<LogEvent> {
+        self.log_event_sender.clone()
+    }
+    /// The sender for the connection pools to publish their statistics
+    pub fn connection_pool_event_sender(&self) -> Sender<ConnectionPoolEvent> {
+        self.connection_pool_event_sender.clone()
+    }
 
     pub async fn run<F1, Fut1, F2, Fut2>(
         self,
-        create_listener: F1,
+        create_listeners: F1,
         connection_handler: F2,
     ) -> Result<(), CommonError>
     where
         F1: Fn(Arc<C>) -> Fut1 + Send + Sync + 'static,
-        Fut1: Future<Output = Result<TcpListener, CommonError>> + Send + 'static,
+        Fut1: Future<Output = Result<Vec<TcpListener>, CommonError>> + Send + 'static,
         F2: Fn(Arc<C>, Arc<ServerState>, TcpStream, SocketAddr) -> Fut2
             + Send
             + Sync
@@ -105,43 +154,102 @@ where
     {
         let config = self.config();
         let server_state = self.server_state();
-        let listener = create_listener(config.clone()).await?;
-        publish_server_log_event(
-            &self.log_event_sender,
-            LogEventLevel::Info,
-            format!("Server listening on port: {}", config.server_port()),
-        )
-        .await;
+        let listeners = create_listeners(config.clone()).await?;
+        if listeners.is_empty() {
+            return Err(CommonError::Other(
+                "No listen address configured for server.".to_string(),
+            ));
+        }
+        let mut accept_tasks = JoinSet::new();
+        for listener in listeners {
+            let listen_address = listener.local_addr()?;
+            publish_server_log_event(
+                &self.log_event_sender,
+                LogEventLevel::Info,
+                format!("Server listening on: {listen_address}"),
+            )
+            .await;
+            accept_tasks.spawn(Self::accept_connections(
+                listener,
+                listen_address,
+                config.clone(),
+                server_state.clone(),
+                connection_handler.clone(),
+                self.log_event_sender.clone(),
+                self.stop_signal.clone(),
+            ));
+        }
+        while let Some(accept_result) = accept_tasks.join_next().await {
+            if let Err(e) = accept_result {
+                return Err(CommonError::Other(format!(
+                    "Server listener task fail: {e:?}"
+                )));
+            }
+        }
+        Ok(())
+    }
 
+    /// Accept the connections from one listener until the stop signal
+    async fn accept_connections<F2, Fut2>(
+        listener: TcpListener,
+        listen_address: SocketAddr,
+        config: Arc<C>,
+        server_state: Arc<ServerState>,
+        connection_handler: F2,
+        log_event_sender: Sender<LogEvent>,
+        stop_signal: CancellationToken,
+    ) where
+        F2: Fn(Arc<C>, Arc<ServerState>, TcpStream, SocketAddr) -> Fut2
+            + Send
+            + Sync
+            + Clone
+            + 'static,
+        Fut2: Future<Output = Result<(), CommonError>> + Send + 'static,
+    {
         loop {
             tokio::select! {
-                _ = self.stop_signal.cancelled()=>{
-                    return Ok(())
+                _ = stop_signal.cancelled()=>{
+                    return;
                 }
                 accept_result=listener.accept()=>{
                     let (tcp_stream, socket_address) = match accept_result {
                         Ok(agent_tcp_accept_result) => agent_tcp_accept_result,
                         Err(e) => {
                             publish_server_log_event(
-                                &self.log_event_sender,
+                                &log_event_sender,
                                 LogEventLevel::Error,
-                                format!("Failed to accept connection with IPv4 on port: {}", e),
+                                format!("Failed to accept connection on [{listen_address}]: {e}"),
                             )
                             .await;
                             continue;
                         }
                     };
+                    // Drop the connection from the banned source without handling it
+                    if let Some(ban_tracker) = server_state.get_value::<Arc<BanTracker>>()
+                        && ban_tracker.is_banned(socket_address.ip())
+                    {
+                        debug!("Drop connection from banned source: {socket_address}");
+                        continue;
+                    }
                     publish_server_log_event(
-                        &self.log_event_sender,
+                        &log_event_sender,
                         LogEventLevel::Info,
-                        format!("Accept connection: {}", socket_address),
+                        format!("Accept connection: {socket_address} on [{listen_address}]"),
                     )
                     .await;
-                    tcp_stream.set_nodelay(true)?;
+                    if let Err(e) = tcp_stream.set_nodelay(true) {
+                        publish_server_log_event(
+                            &log_event_sender,
+                            LogEventLevel::Error,
+                            format!("Fail to set nodelay on connection [{socket_address}]: {e}"),
+                        )
+                        .await;
+                        continue;
+                    }
                     let config = config.clone();
                     let server_state = server_state.clone();
                     let connection_handler = connection_handler.clone();
-                    let log_event_sender = self.log_event_sender.clone();
+                    let log_event_sender = log_event_sender.clone();
                     tokio::spawn(async move {
                         if let Err(e) =
                             connection_handler(config, server_state, tcp_stream, socket_address)
@@ -163,3 +271,38 @@ where
         }
     }
 }
+
+/// Create the listeners for all the listen addresses in the configuration.
+pub async fn create_server_listeners<C>(config: Arc<C>) -> Result<Vec<TcpListener>, CommonError>
+where
+    C: RetrieveServerConfig + Send + Sync + 'static,
+{
+    config
+        .listen_addresses()
+        .iter()
+        .map(create_server_listener)
+        .collect()
+}
+
+fn create_server_listener(
+    listen_address: &ServerListenAddress,
+) -> Result<TcpListener, CommonError> {
+    let ServerListenAddress {
+        address,
+        ip_v6_only,
+    } = listen_address;
+    debug!("Starting server listener on: {address}, ip v6 only: {ip_v6_only}");
+    let socket = Socket::new(
+        Domain::for_address(*address),
+        Type::STREAM,
+        Some(Protocol::TCP),
+    )?;
+    if address.is_ipv6() {
+        socket.set_only_v6(*ip_v6_only)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&(*address).into())?;
+    socket.listen(1024)?;
+    Ok(TcpListener::from_std(socket.into())?)
+}
@@ -0,0 +1,115 @@
+use crate::crypto::RsaCrypto;
+use crate::error::CommonError;
+use bytes::Bytes;
+use ppaass_protocol::{HandshakeRequest, HandshakeResponse};
+use ring::digest::{Context, Digest, SHA256};
+
+/// Separate the handshake transcript from any other data signed by the keys
+const TRANSCRIPT_LABEL: &[u8] = b"ppaass-v3 handshake transcript";
+
+/// The SHA-256 hash of the handshake messages. It covers the whole request
+/// (the agent nonce and the sealed agent encryption included) and the response
+/// except the signature (the proxy nonce and the proxy encryption included).
+/// The proxy signs it with its private key and the agent verifies it with the
+/// proxy public key, so a response can not be made without the proxy private
+/// key or be reused for another request.
+pub(crate) struct HandshakeTranscript(Context);
+
+impl HandshakeTranscript {
+    pub(crate) fn new(handshake_request: &HandshakeRequest) -> Result<Self, CommonError> {
+        let mut context = Context::new(&SHA256);
+        context.update(TRANSCRIPT_LABEL);
+        context.update(&bincode::serde::encode_to_vec(
+            handshake_request,
+            bincode::config::standard(),
+        )?);
+        Ok(Self(context))
+    }
+
+    fn finish(mut self, handshake_response: &HandshakeResponse) -> Result<Digest, CommonError> {
+        let unsigned_handshake_response = HandshakeResponse {
+            signature: Bytes::new(),
+            ..handshake_response.clone()
+        };
+        self.0.update(&bincode::serde::encode_to_vec(
+            &unsigned_handshake_response,
+            bincode::config::standard(),
+        )?);
+        Ok(self.0.finish())
+    }
+
+    /// Sign the transcript with the proxy private key
+    pub(crate) fn sign(
+        self,
+        handshake_response: &HandshakeResponse,
+        rsa_crypto: &RsaCrypto,
+    ) -> Result<Bytes, CommonError> {
+        rsa_crypto.sign(self.finish(handshake_response)?.as_ref())
+    }
+
+    /// Verify the signature in the response with the proxy public key
+    pub(crate) fn verify(
+        self,
+        handshake_response: &HandshakeResponse,
+        rsa_crypto: &RsaCrypto,
+    ) -> Result<(), CommonError> {
+        let digest = self.finish(handshake_response)?;
+        rsa_crypto
+            .verify(digest.as_ref(), &handshake_response.signature)
+            .map_err(|e| CommonError::HandshakeSignature(e.to_string()))
+    }
+}
+
+#[test]
+fn test() {
+    use crate::crypto::{EncodePrivateKey, EncodePublicKey, LineEnding, OsRng, RsaPrivateKey};
+    use ppaass_protocol::Encryption;
+    let private_key = RsaPrivateKey::new(&mut OsRng, 1024).unwrap();
+    let rsa_crypto = RsaCrypto::new(
+        private_key
+            .to_public_key()
+            .to_public_key_pem(LineEnding::LF)
+            .unwrap(),
+        private_key
+            .to_pkcs8_pem(LineEnding::LF)
+            .unwrap()
+            .to_string(),
+    )
+    .unwrap();
+    let handshake_request = HandshakeRequest {
+        authentication: "user1".to_string(),
+        encryption: Encryption::Plain,
+        obfuscation: false,
+        compressions: Vec::new(),
+        rekey: false,
+        timestamp: 0,
+        nonce: Bytes::from_static(&[1u8; 16]),
+    };
+    let mut handshake_response = HandshakeResponse {
+        encryption: Encryption::Plain,
+        obfuscation: false,
+        compression: None,
+        rekey: false,
+        nonce: Bytes::from_static(&[2u8; 16]),
+        signature: Bytes::new(),
+    };
+    handshake_response.signature = HandshakeTranscript::new(&handshake_request)
+        .unwrap()
+        .sign(&handshake_response, &rsa_crypto)
+        .unwrap();
+    HandshakeTranscript::new(&handshake_request)
+        .unwrap()
+        .verify(&handshake_response, &rsa_crypto)
+        .unwrap();
+    // The signature can not be reused for another request
+    let replayed_handshake_request = HandshakeRequest {
+        nonce: Bytes::from_static(&[3u8; 16]),
+        ..handshake_request
+    };
+    assert!(matches!(
+        HandshakeTranscript::new(&replayed_handshake_request)
+            .unwrap()
+            .verify(&handshake_response, &rsa_crypto),
+        Err(CommonError::HandshakeSignature(_))
+    ));
+}
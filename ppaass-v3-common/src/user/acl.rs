@@ -0,0 +1,198 @@
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// The port range in the form of `443` or `8000-8999`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct PortRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl PortRange {
+    pub fn contains(&self, port: u16) -> bool {
+        self.start <= port && port <= self.end
+    }
+}
+
+impl FromStr for PortRange {
+    type Err = String;
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let parse_port = |port: &str| {
+            port.trim()
+                .parse::<u16>()
+                .map_err(|e| format!("Invalid port [{port}]: {e}"))
+        };
+        let (start, end) = match value.split_once('-') {
+            None => {
+                let port = parse_port(value)?;
+                (port, port)
+            }
+            Some((start, end)) => (parse_port(start)?, parse_port(end)?),
+        };
+        if start > end {
+            return Err(format!("Invalid port range [{value}]"));
+        }
+        Ok(Self { start, end })
+    }
+}
+
+impl TryFrom<String> for PortRange {
+    type Error = String;
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<PortRange> for String {
+    fn from(value: PortRange) -> Self {
+        value.to_string()
+    }
+}
+
+impl Display for PortRange {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.start == self.end {
+            write!(f, "{}", self.start)
+        } else {
+            write!(f, "{}-{}", self.start, self.end)
+        }
+    }
+}
+
+/// Match the domain with the glob, `*` matches any characters
+/// and `?` matches one character, case insensitive.
+fn match_domain_glob(glob: &str, domain: &str) -> bool {
+    let glob = glob.to_ascii_lowercase().into_bytes();
+    let domain = domain
+        .trim_end_matches('.')
+        .to_ascii_lowercase()
+        .into_bytes();
+    let (mut glob_index, mut domain_index) = (0, 0);
+    let mut backtrack = None;
+    while domain_index < domain.len() {
+        match glob.get(glob_index) {
+            Some(b'*') => {
+                backtrack = Some((glob_index, domain_index));
+                glob_index += 1;
+            }
+            Some(c) if *c == b'?' || *c == domain[domain_index] => {
+                glob_index += 1;
+                domain_index += 1;
+            }
+            _ => match backtrack {
+                Some((star_glob_index, star_domain_index)) => {
+                    glob_index = star_glob_index + 1;
+                    domain_index = star_domain_index + 1;
+                    backtrack = Some((star_glob_index, star_domain_index + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    glob[glob_index..].iter().all(|c| *c == b'*')
+}
+
+/// The destinations matched by a rule, the host matches when it matches
+/// any of the domain globs or networks, empty domains and networks match
+/// any host, empty ports match any port.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DestinationRule {
+    #[serde(default)]
+    pub domains: Vec<String>,
+    #[serde(default)]
+    pub networks: Vec<IpNet>,
+    #[serde(default)]
+    pub ports: Vec<PortRange>,
+}
+
+impl DestinationRule {
+    fn matches(&self, domain: Option<&str>, ip_address: Option<IpAddr>, port: u16) -> bool {
+        let host_matched = (self.domains.is_empty() && self.networks.is_empty())
+            || domain.is_some_and(|domain| {
+                self.domains
+                    .iter()
+                    .any(|glob| match_domain_glob(glob, domain))
+            })
+            || ip_address.is_some_and(|ip_address| {
+                let ip_address = ip_address.to_canonical();
+                self.networks
+                    .iter()
+                    .any(|network| network.contains(&ip_address))
+            });
+        host_matched
+            && (self.ports.is_empty() || self.ports.iter().any(|ports| ports.contains(port)))
+    }
+}
+
+/// The destinations a user can reach, a destination matching any deny
+/// rule is forbidden, when there are allow rules the destination must
+/// match one of them.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DestinationAcl {
+    #[serde(default)]
+    pub allow: Vec<DestinationRule>,
+    #[serde(default)]
+    pub deny: Vec<DestinationRule>,
+}
+
+impl DestinationAcl {
+    /// Check the destination with the requested domain and the address
+    /// to connect, either of them can be absent.
+    pub fn is_allowed(&self, domain: Option<&str>, ip_address: Option<IpAddr>, port: u16) -> bool {
+        if self
+            .deny
+            .iter()
+            .any(|rule| rule.matches(domain, ip_address, port))
+        {
+            return false;
+        }
+        self.allow.is_empty()
+            || self
+                .allow
+                .iter()
+                .any(|rule| rule.matches(domain, ip_address, port))
+    }
+
+    /// Check if any rule matches the host with networks, the domain
+    /// destination must be resolved to check these rules.
+    pub fn has_network_rules(&self) -> bool {
+        self.allow
+            .iter()
+            .chain(self.deny.iter())
+            .any(|rule| !rule.networks.is_empty())
+    }
+}
+
+#[test]
+fn test() {
+    let acl = toml::from_str::<DestinationAcl>(
+        r#"
+        [[allow]]
+        domains = ["*.example.com"]
+        ports = ["443", "8000-8999"]
+        [[allow]]
+        networks = ["203.0.113.0/24"]
+        [[deny]]
+        domains = ["admin.example.com"]
+        "#,
+    )
+    .unwrap();
+    let ip_address = Some("203.0.113.7".parse().unwrap());
+    assert!(acl.is_allowed(Some("www.Example.com."), None, 443));
+    assert!(acl.is_allowed(Some("a.b.example.com"), None, 8080));
+    assert!(!acl.is_allowed(Some("www.example.com"), None, 80));
+    assert!(!acl.is_allowed(Some("example.com"), None, 443));
+    assert!(!acl.is_allowed(Some("admin.example.com"), None, 443));
+    assert!(acl.is_allowed(None, ip_address, 22));
+    assert!(acl.is_allowed(Some("other.test"), ip_address, 22));
+    assert!(!acl.is_allowed(None, Some("198.51.100.1".parse().unwrap()), 443));
+    assert!(DestinationAcl::default().is_allowed(Some("any.test"), None, 1));
+    assert!(acl.has_network_rules());
+    assert!(!DestinationAcl::default().has_network_rules());
+    assert!(match_domain_glob("w?w.*", "www.example.com"));
+    assert!("9000-80".parse::<PortRange>().is_err());
+}
@@ -0,0 +1,101 @@
+use chrono::{DateTime, Datelike, Days, Months, NaiveTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// The bandwidth limit in bytes per second, the burst is the bytes can be
+/// relayed at once after idle and default to one second of the rate.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BandwidthLimit {
+    /// The rate from agent to destination
+    #[serde(default)]
+    pub upload_rate: Option<u64>,
+    /// The rate from destination to agent
+    #[serde(default)]
+    pub download_rate: Option<u64>,
+    #[serde(default)]
+    pub burst: Option<u64>,
+}
+
+/// The limits of the tunnels a user can open at the same time
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConnectionLimit {
+    /// The tunnels of the user from all the addresses
+    #[serde(default)]
+    pub max_concurrent_tunnels: Option<usize>,
+    /// The tunnels of the user from one address
+    #[serde(default)]
+    pub max_connections_per_ip: Option<usize>,
+}
+
+/// How often the traffic quota is reset
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QuotaPeriod {
+    Daily,
+    Weekly,
+    #[default]
+    Monthly,
+}
+
+/// The bytes a user can relay in both directions in a period, the period
+/// starts at 00:00 UTC of the reset day.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TrafficQuota {
+    pub bytes: u64,
+    #[serde(default)]
+    pub period: QuotaPeriod,
+    /// The day of week (1 is Monday) for weekly period, or the day of
+    /// month (up to 28) for monthly period
+    #[serde(default = "default_quota_reset_day")]
+    pub reset_day: u32,
+}
+
+fn default_quota_reset_day() -> u32 {
+    1
+}
+
+impl TrafficQuota {
+    /// The start time of the period including the given time
+    pub fn period_start(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        let today = now.date_naive();
+        let start_date = match self.period {
+            QuotaPeriod::Daily => today,
+            QuotaPeriod::Weekly => {
+                let reset_weekday = self.reset_day.clamp(1, 7) - 1;
+                let weekday = today.weekday().num_days_from_monday();
+                today - Days::new(((weekday + 7 - reset_weekday) % 7) as u64)
+            }
+            QuotaPeriod::Monthly => {
+                let reset_day = self.reset_day.clamp(1, 28);
+                let reset_date = today.with_day(reset_day).unwrap_or(today);
+                if today.day() >= reset_day {
+                    reset_date
+                } else {
+                    reset_date - Months::new(1)
+                }
+            }
+        };
+        start_date.and_time(NaiveTime::MIN).and_utc()
+    }
+}
+
+#[test]
+fn test() {
+    let now = "2025-03-05T10:00:00Z".parse::<DateTime<Utc>>().unwrap();
+    let quota = |period, reset_day| TrafficQuota {
+        bytes: 0,
+        period,
+        reset_day,
+    };
+    let period_start = |period, reset_day| {
+        quota(period, reset_day)
+            .period_start(now)
+            .format("%Y-%m-%d %H:%M")
+            .to_string()
+    };
+    assert_eq!("2025-03-05 00:00", period_start(QuotaPeriod::Daily, 1));
+    // 2025-03-05 is Wednesday
+    assert_eq!("2025-03-03 00:00", period_start(QuotaPeriod::Weekly, 1));
+    assert_eq!("2025-02-27 00:00", period_start(QuotaPeriod::Weekly, 4));
+    assert_eq!("2025-03-01 00:00", period_start(QuotaPeriod::Monthly, 1));
+    assert_eq!("2025-02-15 00:00", period_start(QuotaPeriod::Monthly, 15));
+}
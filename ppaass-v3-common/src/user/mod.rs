@@ -1,3 +1,5 @@
+pub mod acl;
+pub mod limit;
 pub mod repo;
 use crate::crypto::RsaCrypto;
 use crate::error::CommonError;
@@ -7,19 +9,20 @@ use std::sync::Arc;
 use tokio::sync::RwLock;
 #[derive(Debug)]
 pub struct UserInfo {
-    rsa_crypto: RsaCrypto,
+    /// Shared with the RSA operations running on the blocking threads
+    rsa_crypto: Arc<RsaCrypto>,
     additional_info: HashMap<String, Arc<dyn Any + Send + Sync + 'static>>,
 }
 
 impl UserInfo {
     pub fn new(rsa_crypto: RsaCrypto) -> Self {
         Self {
-            rsa_crypto,
+            rsa_crypto: Arc::new(rsa_crypto),
             additional_info: Default::default(),
         }
     }
 
-    pub fn rsa_crypto(&self) -> &RsaCrypto {
+    pub fn rsa_crypto(&self) -> &Arc<RsaCrypto> {
         &self.rsa_crypto
     }
 
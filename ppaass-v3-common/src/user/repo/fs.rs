@@ -1,5 +1,7 @@
 use crate::crypto::RsaCrypto;
 use crate::error::CommonError;
+use crate::user::acl::DestinationAcl;
+use crate::user::limit::{BandwidthLimit, ConnectionLimit, TrafficQuota};
 use crate::user::{UserInfo, UserInfoRepository};
 use accessory::Accessors;
 use chrono::{DateTime, Utc};
@@ -18,6 +20,10 @@ use tracing::error;
 use zip::ZipArchive;
 pub const USER_INFO_ADDITION_INFO_EXPIRED_DATE_TIME: &str = "expired_date_time";
 pub const USER_INFO_ADDITION_INFO_PROXY_SERVERS: &str = "proxy_servers";
+pub const USER_INFO_ADDITION_INFO_DESTINATION_ACL: &str = "destination_acl";
+pub const USER_INFO_ADDITION_INFO_BANDWIDTH_LIMIT: &str = "bandwidth_limit";
+pub const USER_INFO_ADDITION_INFO_TRAFFIC_QUOTA: &str = "traffic_quota";
+pub const USER_INFO_ADDITION_INFO_CONNECTION_LIMIT: &str = "connection_limit";
 pub const FS_USER_INFO_CONFIG_FILE_NAME: &str = "userinfo.toml";
 pub trait FsUserInfoContent {
     fn public_key_file_relative_path(&self) -> &str;
@@ -61,8 +67,38 @@ pub struct FsProxyUserInfoContent {
     public_key_file_relative_path: String,
     #[access(get)]
     private_key_file_relative_path: String,
+    /// The destinations the user can reach, no limit when absent
+    #[serde(default)]
+    #[access(get)]
+    destination_acl: Option<DestinationAcl>,
+    /// The bandwidth shared by all the tunnels of the user, no limit when absent
+    #[serde(default)]
+    #[access(get)]
+    bandwidth_limit: Option<BandwidthLimit>,
+    /// The traffic the user can relay in a period, no limit when absent
+    #[serde(default)]
+    #[access(get)]
+    traffic_quota: Option<TrafficQuota>,
+    /// The tunnels the user can open at the same time, no limit when absent
+    #[serde(default)]
+    #[access(get)]
+    max_concurrent_tunnels: Option<usize>,
+    /// The tunnels the user can open from one address, no limit when absent
+    #[serde(default)]
+    #[access(get)]
+    max_connections_per_ip: Option<usize>,
 }
 impl FsProxyUserInfoContent {
+    /// The connection limit when any of the limits is set
+    pub fn connection_limit(&self) -> Option<ConnectionLimit> {
+        if self.max_concurrent_tunnels.is_none() && self.max_connections_per_ip.is_none() {
+            return None;
+        }
+        Some(ConnectionLimit {
+            max_concurrent_tunnels: self.max_concurrent_tunnels,
+            max_connections_per_ip: self.max_connections_per_ip,
+        })
+    }
     pub fn new(
         expired_date_time: Option<DateTime<Utc>>,
         public_key_file_relative_path: String,
@@ -72,6 +108,11 @@ impl FsProxyUserInfoContent {
             expired_date_time,
             public_key_file_relative_path,
             private_key_file_relative_path,
+            destination_acl: None,
+            bandwidth_limit: None,
+            traffic_quota: None,
+            max_concurrent_tunnels: None,
+            max_connections_per_ip: None,
         }
     }
 }
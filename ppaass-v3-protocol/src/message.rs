@@ -14,6 +14,15 @@ pub enum Encryption {
     Blowfish(#[serde(with = "crate::hex")] Bytes),
 }
 
+/// The compression algorithm of the frames, it is negotiated in
+/// the handshake and the data is compressed before encryption
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Compression {
+    Zstd,
+    Lz4,
+}
+
 /// The handshake message between agent and proxy.
 /// When the tcp connection created between agent and proxy,
 /// the handshake will happen as the first message used to
@@ -30,6 +39,21 @@ pub struct HandshakeRequest {
     pub authentication: String,
     /// The encryption used to carry the **encryption key**
     pub encryption: Encryption,
+    /// The agent asks to obfuscate the frames after the handshake
+    pub obfuscation: bool,
+    /// The compression algorithms supported by the agent in the order
+    /// of preference, empty when the agent disables compression
+    pub compressions: Vec<Compression>,
+    /// The agent asks to switch the keys in relay after a byte or time threshold
+    pub rekey: bool,
+    /// The unix seconds when the request is created, the proxy rejects
+    /// the request out of its clock skew window
+    pub timestamp: i64,
+    /// The random bytes used once, the proxy rejects the request with a
+    /// nonce already seen. Both the timestamp and the nonce are also sealed
+    /// in the **encryption key** so they can not be changed.
+    #[serde(with = "crate::hex")]
+    pub nonce: Bytes,
 }
 
 /// The handshake response, exchange the proxy side encryption
@@ -38,6 +62,24 @@ pub struct HandshakeRequest {
 pub struct HandshakeResponse {
     /// The encryption used to carry the **encryption key**
     pub encryption: Encryption,
+    /// The proxy accepts the obfuscation asked by the agent, the
+    /// frames after the handshake are obfuscated on both sides
+    pub obfuscation: bool,
+    /// The compression algorithm selected by the proxy from the agent
+    /// supported ones, `None` means the frames are not compressed
+    pub compression: Option<Compression>,
+    /// The proxy accepts the rekey asked by the agent, both sides
+    /// can switch the keys in relay
+    pub rekey: bool,
+    /// The random bytes generated by the proxy for this handshake
+    #[serde(with = "crate::hex")]
+    pub nonce: Bytes,
+    /// The signature of the handshake transcript hash made with the
+    /// **RSA private key** of the proxy, the transcript covers the whole
+    /// request and this response except the signature, so the agent can
+    /// verify the response is made by the proxy for this request.
+    #[serde(with = "crate::hex")]
+    pub signature: Bytes,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -62,12 +104,30 @@ pub struct TunnelInitRequest {
 }
 
 /// The failure reason for destination init
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
 pub enum TunnelInitFailureReason {
     /// Authenticate the user fail
     AuthenticateFail,
     /// Initialize destination with destination fail
     InitWithDestinationFail,
+    /// The destination is in the networks blocked by the proxy
+    DestinationBlocked,
+    /// The destination is forbidden by the access control list of the user
+    ForbiddenByPolicy,
+    /// Fail to resolve the destination domain
+    DnsResolveFail,
+    /// The destination refused the connection
+    ConnectionRefused,
+    /// Timeout when connecting to the destination
+    ConnectTimeout,
+    /// The destination host or network is unreachable
+    DestinationUnreachable,
+    /// The user exceeded the quota
+    QuotaExceeded,
+    /// The user has too many tunnels
+    ConnectionLimitExceeded,
+    /// The user is expired
+    UserExpired,
 }
 
 /// The tcp destination initialize message used to initialize the destination
@@ -1,21 +1,31 @@
 use clap::Parser;
 use command::Command;
+use ppaass_common::ban::BanTracker;
+use ppaass_common::compression::CompressionCounter;
+use ppaass_common::config::{RetrieveAgentConnectionConfig, RetrieveConnectionConfig};
+use ppaass_common::dns::create_dns_resolver;
 use ppaass_common::error::CommonError;
-use ppaass_common::server::{CommonServer, Server, ServerListener, ServerState};
+use ppaass_common::replay::ReplayGuard;
+use ppaass_common::rsa_pool::RsaPool;
+use ppaass_common::server::{consume_server_events, create_server_listeners, Server, ServerState};
 use ppaass_common::user::repo::create_fs_user_repository;
 use ppaass_common::user::repo::fs::{
-    FileSystemUserInfoRepository, FsProxyUserInfoContent, USER_INFO_ADDITION_INFO_EXPIRED_DATE_TIME,
+    FileSystemUserInfoRepository, FsProxyUserInfoContent, USER_INFO_ADDITION_INFO_BANDWIDTH_LIMIT,
+    USER_INFO_ADDITION_INFO_CONNECTION_LIMIT, USER_INFO_ADDITION_INFO_DESTINATION_ACL,
+    USER_INFO_ADDITION_INFO_EXPIRED_DATE_TIME, USER_INFO_ADDITION_INFO_TRAFFIC_QUOTA,
 };
 use ppaass_common::user::UserInfoRepository;
-use ppaass_common::{init_logger, ProxyTcpConnectionPool};
+use ppaass_common::{init_logger, ProxyServerSelector, ProxyTcpConnectionPool};
+use ppaass_proxy_core::bandwidth::BandwidthManager;
 pub use ppaass_proxy_core::config::*;
-use ppaass_proxy_core::tunnel::handle_agent_connection;
+use ppaass_proxy_core::connection_limit::ConnectionLimiter;
+use ppaass_proxy_core::quota::TrafficQuotaManager;
+use ppaass_proxy_core::tunnel::{handle_agent_connection, ForwardCompressionCounter};
 use ppaass_proxy_core::user::ForwardProxyUserRepository;
 use std::fs::read_to_string;
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::path::PathBuf;
 use std::sync::Arc;
-use tokio::{net::TcpListener, runtime::Builder};
+use tokio::runtime::Builder;
 use tracing::{debug, error, trace};
 pub mod command;
 #[global_allocator]
@@ -23,40 +33,34 @@ static GLOBAL: mimalloc::MiMalloc = mimalloc::MiMalloc;
 
 const DEFAULT_CONFIG_FILE: &str = "resources/config.toml";
 
-async fn create_server_listener(config: Arc<ProxyConfig>) -> Result<ServerListener, CommonError> {
-    if config.ip_v6() {
-        debug!(
-            "Starting server listener with IPv6 on port: {}",
-            config.server_port()
-        );
-        Ok(ServerListener::TcpListener(
-            TcpListener::bind(SocketAddr::new(
-                IpAddr::V6(Ipv6Addr::UNSPECIFIED),
-                config.server_port(),
-            ))
-            .await?,
-        ))
-    } else {
-        debug!(
-            "Starting server listener with IPv4 on port: {}",
-            config.server_port()
-        );
-        Ok(ServerListener::TcpListener(
-            TcpListener::bind(SocketAddr::new(
-                IpAddr::V4(Ipv4Addr::UNSPECIFIED),
-                config.server_port(),
-            ))
-            .await?,
-        ))
-    }
-}
-
 async fn start_server<T: UserInfoRepository + Send + Sync + 'static>(
     config: Arc<ProxyConfig>,
     agent_user_repo: Arc<T>,
 ) -> Result<(), CommonError> {
     let mut server_state = ServerState::new();
     server_state.add_value(agent_user_repo.clone());
+    server_state.add_value(create_dns_resolver(config.dns())?);
+    server_state.add_value(Arc::new(BandwidthManager::new(
+        config.global_bandwidth_limit().clone(),
+    )));
+    let traffic_quota_manager = Arc::new(TrafficQuotaManager::new(config.traffic_usage())?);
+    traffic_quota_manager.start_save(config.traffic_usage().save_interval);
+    server_state.add_value(traffic_quota_manager);
+    let connection_limiter = Arc::new(ConnectionLimiter::new());
+    connection_limiter.start_log_stats(config.connection_stats_interval());
+    server_state.add_value(connection_limiter);
+    let compression_counter = Arc::new(CompressionCounter::new("agent"));
+    if config.agent_compression().enabled {
+        compression_counter.start_log_stats(config.agent_compression().stats_interval);
+    }
+    server_state.add_value(compression_counter);
+    let ban_tracker = Arc::new(BanTracker::new(config.ban().clone()));
+    server_state.add_value(ban_tracker.clone());
+    server_state.add_value(Arc::new(ReplayGuard::new(*config.handshake_replay())));
+    let rsa_pool = Arc::new(RsaPool::new(*config.rsa_pool()));
+    rsa_pool.start_log_stats();
+    server_state.add_value(rsa_pool.clone());
+    let mut connection_pool = None;
     if let Some(forward_config) = config.forward() {
         let forward_config = Arc::new(forward_config.clone());
         let forward_fs_user_repo = ForwardProxyUserRepository::new(
@@ -79,23 +83,43 @@ async fn start_server<T: UserInfoRepository + Send + Sync + 'static>(
             (username, user_info)
         };
         server_state.add_value((username.to_owned(), forward_proxy_user_info.clone()));
+        let proxy_server_selector = Arc::new(ProxyServerSelector::new(
+            forward_config.proxy_selector().clone(),
+        ));
+        server_state.add_value(proxy_server_selector.clone());
+        let compression_counter = Arc::new(CompressionCounter::new("forward"));
+        if forward_config.compression().enabled {
+            compression_counter.start_log_stats(forward_config.compression().stats_interval);
+        }
+        server_state.add_value(ForwardCompressionCounter(compression_counter.clone()));
         if forward_config.connection_pool().is_some() {
             let proxy_tcp_connection_pool = ProxyTcpConnectionPool::new(
                 forward_config.clone(),
                 username,
                 forward_proxy_user_info,
+                proxy_server_selector,
+                rsa_pool,
+                compression_counter,
             )
             .await?;
-            server_state.add_value(Arc::new(proxy_tcp_connection_pool));
+            let proxy_tcp_connection_pool = Arc::new(proxy_tcp_connection_pool);
+            server_state.add_value(proxy_tcp_connection_pool.clone());
+            connection_pool = Some(proxy_tcp_connection_pool);
         }
     }
 
-    let server = CommonServer::new(config.clone(), server_state);
+    let (server, server_guard) = Server::new(config.clone(), server_state);
+    ban_tracker.set_log_event_sender(server.log_event_sender());
+    if let Some(connection_pool) = connection_pool {
+        connection_pool.start_publish_events(server.connection_pool_event_sender());
+    }
+    consume_server_events(server_guard);
     server
-        .run(create_server_listener, handle_agent_connection)
+        .run(create_server_listeners, handle_agent_connection)
         .await?;
     Ok(())
 }
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let command = Command::parse();
     let config_file_path = command
@@ -119,13 +143,37 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             config.user_info_repository_refresh_interval(),
             &user_dir,
             |user_info, content| async move {
+                let mut user_info = user_info.write().await;
                 if let Some(expired_date_time) = content.expired_date_time() {
-                    let mut user_info = user_info.write().await;
                     user_info.add_additional_info(
                         USER_INFO_ADDITION_INFO_EXPIRED_DATE_TIME,
                         expired_date_time.to_owned(),
                     );
                 }
+                if let Some(destination_acl) = content.destination_acl() {
+                    user_info.add_additional_info(
+                        USER_INFO_ADDITION_INFO_DESTINATION_ACL,
+                        destination_acl.to_owned(),
+                    );
+                }
+                if let Some(bandwidth_limit) = content.bandwidth_limit() {
+                    user_info.add_additional_info(
+                        USER_INFO_ADDITION_INFO_BANDWIDTH_LIMIT,
+                        bandwidth_limit.to_owned(),
+                    );
+                }
+                if let Some(traffic_quota) = content.traffic_quota() {
+                    user_info.add_additional_info(
+                        USER_INFO_ADDITION_INFO_TRAFFIC_QUOTA,
+                        traffic_quota.to_owned(),
+                    );
+                }
+                if let Some(connection_limit) = content.connection_limit() {
+                    user_info.add_additional_info(
+                        USER_INFO_ADDITION_INFO_CONNECTION_LIMIT,
+                        connection_limit,
+                    );
+                }
             },
         )
         .await
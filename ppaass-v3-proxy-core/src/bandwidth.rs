@@ -0,0 +1,377 @@
+use crate::config::GlobalBandwidthLimitConfig;
+use ppaass_common::user::limit::BandwidthLimit;
+use std::collections::HashMap;
+use std::future::Future;
+use std::io::Error;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{sleep, Sleep};
+use tracing::error;
+
+struct TokenBucketState {
+    tokens: f64,
+    last_refill_time: Instant,
+}
+
+/// The token bucket refilled with the rate in bytes per second up to the
+/// burst, the tokens can go below zero so a read larger than the left
+/// tokens is paid back by the following reads.
+pub struct TokenBucket {
+    rate: AtomicU64,
+    burst: u64,
+    state: Mutex<TokenBucketState>,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u64, burst: u64) -> Self {
+        let burst = burst.max(1);
+        Self {
+            rate: AtomicU64::new(rate.max(1)),
+            burst,
+            state: Mutex::new(TokenBucketState {
+                tokens: burst as f64,
+                last_refill_time: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn set_rate(&self, rate: u64) {
+        self.rate.store(rate.max(1), Ordering::Relaxed);
+    }
+
+    fn refill(&self, state: &mut TokenBucketState) -> f64 {
+        let rate = self.rate.load(Ordering::Relaxed) as f64;
+        let now = Instant::now();
+        let elapsed = now.duration_since(state.last_refill_time).as_secs_f64();
+        state.tokens = (state.tokens + elapsed * rate).min(self.burst as f64);
+        state.last_refill_time = now;
+        rate
+    }
+
+    /// How long to wait before the bucket has token again
+    pub fn delay(&self) -> Option<Duration> {
+        let mut state = match self.state.lock() {
+            Ok(state) => state,
+            Err(e) => {
+                error!("Fail to lock token bucket: {e:?}");
+                return None;
+            }
+        };
+        let rate = self.refill(&mut state);
+        if state.tokens > 0f64 {
+            return None;
+        }
+        Some(Duration::from_secs_f64((1f64 - state.tokens) / rate))
+    }
+
+    pub fn consume(&self, amount: u64) {
+        let mut state = match self.state.lock() {
+            Ok(state) => state,
+            Err(e) => {
+                error!("Fail to lock token bucket: {e:?}");
+                return;
+            }
+        };
+        self.refill(&mut state);
+        state.tokens -= amount as f64;
+    }
+}
+
+/// The buckets a relay direction must pass, e.g. the user bucket
+/// and the global bucket.
+#[derive(Clone, Default)]
+pub struct BandwidthLimiter {
+    buckets: Vec<Arc<TokenBucket>>,
+}
+
+impl BandwidthLimiter {
+    fn push(&mut self, bucket: Option<&Arc<TokenBucket>>) {
+        if let Some(bucket) = bucket {
+            self.buckets.push(bucket.clone());
+        }
+    }
+
+    fn delay(&self) -> Option<Duration> {
+        self.buckets
+            .iter()
+            .filter_map(|bucket| bucket.delay())
+            .max()
+    }
+
+    fn consume(&self, amount: u64) {
+        self.buckets
+            .iter()
+            .for_each(|bucket| bucket.consume(amount));
+    }
+}
+
+/// Limit the read side of the stream with the limiter, the write side is
+/// not touched because the other direction is limited by its own read side.
+pub struct BandwidthLimitedStream<S> {
+    inner: S,
+    read_limiter: Option<BandwidthLimiter>,
+    read_delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> BandwidthLimitedStream<S> {
+    pub fn new(inner: S, read_limiter: Option<BandwidthLimiter>) -> Self {
+        Self {
+            inner,
+            read_limiter,
+            read_delay: None,
+        }
+    }
+}
+
+impl<S> AsyncRead for BandwidthLimitedStream<S>
+where
+    S: AsyncRead + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let Some(read_limiter) = &this.read_limiter else {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        };
+        loop {
+            if let Some(read_delay) = this.read_delay.as_mut() {
+                ready!(read_delay.as_mut().poll(cx));
+                this.read_delay = None;
+            }
+            match read_limiter.delay() {
+                None => break,
+                Some(delay) => this.read_delay = Some(Box::pin(sleep(delay))),
+            }
+        }
+        let filled_before_read = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        read_limiter.consume((buf.filled().len() - filled_before_read) as u64);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S> AsyncWrite for BandwidthLimitedStream<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, Error>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+fn create_bucket(rate: Option<u64>, burst: Option<u64>) -> Option<Arc<TokenBucket>> {
+    rate.map(|rate| Arc::new(TokenBucket::new(rate, burst.unwrap_or(rate))))
+}
+
+/// The buckets shared by all the tunnels of a user
+struct UserBandwidth {
+    limit: Option<BandwidthLimit>,
+    upload: Option<Arc<TokenBucket>>,
+    download: Option<Arc<TokenBucket>>,
+    /// The fair share of the global bandwidth
+    fair_upload: Option<Arc<TokenBucket>>,
+    fair_download: Option<Arc<TokenBucket>>,
+}
+
+struct BandwidthManagerInner {
+    global_limit: Option<GlobalBandwidthLimitConfig>,
+    global_upload: Option<Arc<TokenBucket>>,
+    global_download: Option<Arc<TokenBucket>>,
+    users: Mutex<HashMap<String, Weak<UserBandwidth>>>,
+}
+
+impl BandwidthManagerInner {
+    /// Split the global rate evenly between the users having active tunnels
+    fn rebalance(&self, users: &mut HashMap<String, Weak<UserBandwidth>>) {
+        users.retain(|_, user_bandwidth| user_bandwidth.strong_count() > 0);
+        let Some(global_limit) = &self.global_limit else {
+            return;
+        };
+        if !global_limit.fair_share || users.is_empty() {
+            return;
+        }
+        let active_users = users.len() as u64;
+        for user_bandwidth in users.values().filter_map(Weak::upgrade) {
+            if let (Some(fair_upload), Some(upload_rate)) =
+                (&user_bandwidth.fair_upload, global_limit.limit.upload_rate)
+            {
+                fair_upload.set_rate(upload_rate / active_users);
+            }
+            if let (Some(fair_download), Some(download_rate)) = (
+                &user_bandwidth.fair_download,
+                global_limit.limit.download_rate,
+            ) {
+                fair_download.set_rate(download_rate / active_users);
+            }
+        }
+    }
+}
+
+/// Keep the user buckets alive while the tunnel is running
+pub struct UserBandwidthGuard {
+    user_bandwidth: Option<Arc<UserBandwidth>>,
+    manager: Arc<BandwidthManagerInner>,
+}
+
+impl UserBandwidthGuard {
+    pub fn upload_limiter(&self) -> Option<BandwidthLimiter> {
+        let user_bandwidth = self.user_bandwidth.as_ref()?;
+        let mut limiter = BandwidthLimiter::default();
+        limiter.push(user_bandwidth.upload.as_ref());
+        limiter.push(user_bandwidth.fair_upload.as_ref());
+        limiter.push(self.manager.global_upload.as_ref());
+        (!limiter.buckets.is_empty()).then_some(limiter)
+    }
+
+    pub fn download_limiter(&self) -> Option<BandwidthLimiter> {
+        let user_bandwidth = self.user_bandwidth.as_ref()?;
+        let mut limiter = BandwidthLimiter::default();
+        limiter.push(user_bandwidth.download.as_ref());
+        limiter.push(user_bandwidth.fair_download.as_ref());
+        limiter.push(self.manager.global_download.as_ref());
+        (!limiter.buckets.is_empty()).then_some(limiter)
+    }
+}
+
+impl Drop for UserBandwidthGuard {
+    fn drop(&mut self) {
+        // Release the user buckets before rebalance so the user is not counted
+        self.user_bandwidth.take();
+        match self.manager.users.lock() {
+            Ok(mut users) => self.manager.rebalance(&mut users),
+            Err(e) => error!("Fail to lock user bandwidth: {e:?}"),
+        }
+    }
+}
+
+/// Manage the bandwidth buckets of the users and the global buckets
+pub struct BandwidthManager {
+    inner: Arc<BandwidthManagerInner>,
+}
+
+impl BandwidthManager {
+    pub fn new(global_limit: Option<GlobalBandwidthLimitConfig>) -> Self {
+        let (global_upload, global_download) = match &global_limit {
+            // With fair share the global rate is enforced by the user shares
+            Some(global_limit) if !global_limit.fair_share => (
+                create_bucket(global_limit.limit.upload_rate, global_limit.limit.burst),
+                create_bucket(global_limit.limit.download_rate, global_limit.limit.burst),
+            ),
+            _ => (None, None),
+        };
+        Self {
+            inner: Arc::new(BandwidthManagerInner {
+                global_limit,
+                global_upload,
+                global_download,
+                users: Mutex::new(HashMap::new()),
+            }),
+        }
+    }
+
+    /// Get the buckets of the user for a new tunnel, all the tunnels of the
+    /// user share the same buckets, the buckets are created again when the
+    /// limit of the user changed.
+    pub fn acquire(&self, username: &str, limit: Option<BandwidthLimit>) -> UserBandwidthGuard {
+        let mut users = match self.inner.users.lock() {
+            Ok(users) => users,
+            Err(e) => {
+                error!("Fail to lock user bandwidth: {e:?}");
+                return UserBandwidthGuard {
+                    user_bandwidth: None,
+                    manager: self.inner.clone(),
+                };
+            }
+        };
+        let existing = users
+            .get(username)
+            .and_then(Weak::upgrade)
+            .filter(|user_bandwidth| user_bandwidth.limit == limit);
+        let user_bandwidth = match existing {
+            Some(user_bandwidth) => user_bandwidth,
+            None => {
+                let fair_share = self
+                    .inner
+                    .global_limit
+                    .as_ref()
+                    .filter(|global_limit| global_limit.fair_share);
+                let user_bandwidth = Arc::new(UserBandwidth {
+                    limit,
+                    upload: limit.and_then(|limit| create_bucket(limit.upload_rate, limit.burst)),
+                    download: limit
+                        .and_then(|limit| create_bucket(limit.download_rate, limit.burst)),
+                    fair_upload: fair_share.and_then(|global_limit| {
+                        create_bucket(global_limit.limit.upload_rate, global_limit.limit.burst)
+                    }),
+                    fair_download: fair_share.and_then(|global_limit| {
+                        create_bucket(global_limit.limit.download_rate, global_limit.limit.burst)
+                    }),
+                });
+                users.insert(username.to_owned(), Arc::downgrade(&user_bandwidth));
+                user_bandwidth
+            }
+        };
+        self.inner.rebalance(&mut users);
+        UserBandwidthGuard {
+            user_bandwidth: Some(user_bandwidth),
+            manager: self.inner.clone(),
+        }
+    }
+}
+
+#[tokio::test]
+async fn test() {
+    use tokio::io::AsyncReadExt;
+    let manager = BandwidthManager::new(Some(GlobalBandwidthLimitConfig {
+        limit: BandwidthLimit {
+            upload_rate: Some(20000),
+            download_rate: None,
+            burst: Some(1000),
+        },
+        fair_share: true,
+    }));
+    let user_limit = Some(BandwidthLimit {
+        upload_rate: Some(1000),
+        download_rate: None,
+        burst: Some(1000),
+    });
+    let guard_a = manager.acquire("a", user_limit);
+    let guard_b = manager.acquire("b", None);
+    assert!(guard_a.download_limiter().is_none());
+    let fair_upload = guard_a.user_bandwidth.as_ref().unwrap().fair_upload.clone();
+    assert_eq!(
+        10000,
+        fair_upload.as_ref().unwrap().rate.load(Ordering::Relaxed)
+    );
+    drop(guard_b);
+    assert_eq!(
+        20000,
+        fair_upload.as_ref().unwrap().rate.load(Ordering::Relaxed)
+    );
+    // The first 1000 bytes are the burst, the next 500 bytes wait for the refill
+    let start_time = Instant::now();
+    let mut stream = BandwidthLimitedStream::new(&[0u8; 1500][..], guard_a.upload_limiter());
+    let mut buf = [0u8; 1000];
+    assert_eq!(1000, stream.read(&mut buf).await.unwrap());
+    assert_eq!(500, stream.read(&mut buf).await.unwrap());
+    assert!(start_time.elapsed() < Duration::from_millis(100));
+    assert_eq!(0, stream.read(&mut buf).await.unwrap());
+    assert!(start_time.elapsed() >= Duration::from_millis(400));
+}
@@ -1,16 +1,29 @@
 use accessory::Accessors;
+use ipnet::IpNet;
 use ppaass_common::config::{
-    ConnectionPoolConfig, RetrieveConnectionConfig, RetrieveConnectionPoolConfig,
-    RetrieveServerConfig,
+    BanConfig, CompressionConfig, ConnectionPoolConfig, DeadlineConfig, DnsConfig,
+    HappyEyeballsConfig, ObfuscationConfig, ProxySelectorConfig, RekeyConfig, ReplayConfig,
+    RetrieveAgentConnectionConfig, RetrieveConnectionConfig, RetrieveConnectionPoolConfig,
+    RetrieveServerConfig, RsaPoolConfig, ServerListenAddress,
 };
+use ppaass_common::user::limit::BandwidthLimit;
 use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
 use std::path::PathBuf;
 #[derive(Serialize, Deserialize, Accessors, Debug)]
 pub struct ProxyConfig {
-    #[access(get(cp))]
+    /// All the addresses the proxy listens on, e.g. an IPv4 and an IPv6
+    /// address, or the 443 port together with a fallback port
+    #[serde(default)]
+    listen_addresses: Vec<ServerListenAddress>,
+    /// The legacy listen address family, used with `server_port` when
+    /// `listen_addresses` is absent
+    #[serde(default)]
     ip_v6: bool,
-    #[access(get(cp))]
-    server_port: u16,
+    /// The legacy listen port, mapped to a single listen address on the
+    /// unspecified address when `listen_addresses` is absent
+    #[serde(default)]
+    server_port: Option<u16>,
     #[access(get(cp))]
     worker_thread_number: usize,
     #[access(get)]
@@ -23,27 +36,215 @@ pub struct ProxyConfig {
     user_dir: PathBuf,
     #[access(get(cp))]
     destination_connect_timeout: u64,
-    #[access(get(cp))]
+    /// The resolver configuration for the destination domains
+    #[serde(default)]
+    #[access(get)]
+    dns: DnsConfig,
+    /// The guard to block the destinations in the internal networks
+    #[serde(default)]
+    #[access(get)]
+    destination_guard: DestinationGuardConfig,
+    /// The Happy Eyeballs configuration when connecting to the destinations
+    #[serde(default)]
+    #[access(get)]
+    destination_happy_eyeballs: HappyEyeballsConfig,
     agent_frame_buffer_size: usize,
+    /// The deadlines of the handshake and the tunnel init with the agents
+    #[serde(default)]
+    agent_deadline: DeadlineConfig,
+    /// Accept the obfuscation requested by the agents in the handshake
+    #[serde(default)]
+    agent_obfuscation: ObfuscationConfig,
+    /// The compression algorithms the agents can select in the handshake
+    #[serde(default)]
+    agent_compression: CompressionConfig,
+    /// Accept the rekey requested by the agents in the handshake
+    #[serde(default)]
+    agent_rekey: RekeyConfig,
     #[access(get(cp))]
     proxy_to_destination_data_relay_buffer_size: usize,
     #[access(get(cp))]
     destination_to_proxy_data_relay_buffer_size: usize,
+    /// The seconds without data in both directions before a relay is closed, 0 to disable
+    #[serde(default = "default_relay_idle_timeout")]
+    #[access(get(cp))]
+    relay_idle_timeout: u64,
+    /// The seconds an agent connection can wait for tunnel init without
+    /// any frame (heartbeat included) before it is closed, 0 to disable
+    #[serde(default = "default_agent_idle_timeout")]
+    #[access(get(cp))]
+    agent_idle_timeout: u64,
     #[access(get)]
     forward: Option<ForwardConfig>,
+    /// The bandwidth shared by all the users, no limit when absent
+    #[serde(default)]
+    #[access(get)]
+    global_bandwidth_limit: Option<GlobalBandwidthLimitConfig>,
+    /// Where and how often the traffic usage of the users is saved
+    #[serde(default)]
+    #[access(get)]
+    traffic_usage: TrafficUsageConfig,
+    /// The server to relay the connections failing the handshake to, so the
+    /// clients other than the agents see it as a normal server
+    #[serde(default)]
+    #[access(get(cp))]
+    fallback_address: Option<SocketAddr>,
+    /// Ban the agent addresses failing the handshake repeatedly
+    #[serde(default)]
+    #[access(get)]
+    ban: BanConfig,
+    /// Reject the replayed handshake requests of the agents
+    #[serde(default)]
+    #[access(get)]
+    handshake_replay: ReplayConfig,
+    /// Run the RSA operations of the agent handshakes on the blocking threads
+    #[serde(default)]
+    #[access(get)]
+    rsa_pool: RsaPoolConfig,
+    /// The interval in seconds to log the open tunnels of the users
+    #[serde(default = "default_connection_stats_interval")]
+    #[access(get(cp))]
+    connection_stats_interval: u64,
     #[access(get(cp))]
     user_info_repository_refresh_interval: u64,
 }
 
+impl RetrieveAgentConnectionConfig for ProxyConfig {
+    fn agent_frame_buffer_size(&self) -> usize {
+        self.agent_frame_buffer_size
+    }
+    fn agent_deadline(&self) -> &DeadlineConfig {
+        &self.agent_deadline
+    }
+    fn agent_obfuscation(&self) -> &ObfuscationConfig {
+        &self.agent_obfuscation
+    }
+    fn agent_compression(&self) -> &CompressionConfig {
+        &self.agent_compression
+    }
+    fn agent_rekey(&self) -> &RekeyConfig {
+        &self.agent_rekey
+    }
+}
+
 impl RetrieveServerConfig for ProxyConfig {
     fn worker_thread_number(&self) -> usize {
         self.worker_thread_number
     }
-    fn server_port(&self) -> u16 {
-        self.server_port
+    fn listen_addresses(&self) -> Vec<ServerListenAddress> {
+        match self.server_port {
+            Some(server_port) if self.listen_addresses.is_empty() => {
+                vec![ServerListenAddress::unspecified(self.ip_v6, server_port)]
+            }
+            _ => self.listen_addresses.clone(),
+        }
+    }
+}
+
+/// The destination guard blocks the tunnels to the addresses in the deny
+/// networks unless they are also in the allow networks, the addresses
+/// resolved from domain are checked one by one.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DestinationGuardConfig {
+    #[serde(default = "default_destination_guard_enabled")]
+    pub enabled: bool,
+    /// Default to the loopback, private, link-local (cloud metadata),
+    /// shared, multicast and reserved networks, and the NAT64 and 6to4
+    /// networks embedding IPv4 addresses in IPv6 addresses
+    #[serde(default = "default_destination_guard_deny_networks")]
+    pub deny_networks: Vec<IpNet>,
+    /// The exceptions of the deny networks
+    #[serde(default)]
+    pub allow_networks: Vec<IpNet>,
+}
+
+fn default_destination_guard_enabled() -> bool {
+    true
+}
+
+fn default_destination_guard_deny_networks() -> Vec<IpNet> {
+    [
+        "0.0.0.0/8",
+        "10.0.0.0/8",
+        "100.64.0.0/10",
+        "127.0.0.0/8",
+        "169.254.0.0/16",
+        "172.16.0.0/12",
+        "192.0.0.0/24",
+        "192.168.0.0/16",
+        "198.18.0.0/15",
+        "224.0.0.0/4",
+        "240.0.0.0/4",
+        "::/128",
+        "::1/128",
+        "64:ff9b::/96",
+        "64:ff9b:1::/48",
+        "2002::/16",
+        "fc00::/7",
+        "fe80::/10",
+        "ff00::/8",
+    ]
+    .iter()
+    .filter_map(|network| network.parse().ok())
+    .collect()
+}
+
+impl Default for DestinationGuardConfig {
+    fn default() -> Self {
+        Self {
+            enabled: default_destination_guard_enabled(),
+            deny_networks: default_destination_guard_deny_networks(),
+            allow_networks: Vec::new(),
+        }
     }
-    fn ip_v6(&self) -> bool {
-        self.ip_v6
+}
+
+/// The global bandwidth limit, when fair share is enabled the global rate
+/// is split evenly between the users having active tunnels.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct GlobalBandwidthLimitConfig {
+    #[serde(flatten)]
+    pub limit: BandwidthLimit,
+    #[serde(default)]
+    pub fair_share: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TrafficUsageConfig {
+    /// The file to keep the traffic usage across restarts
+    #[serde(default = "default_traffic_usage_state_file")]
+    pub state_file: PathBuf,
+    /// The interval in seconds to save the traffic usage
+    #[serde(default = "default_traffic_usage_save_interval")]
+    pub save_interval: u64,
+}
+
+fn default_relay_idle_timeout() -> u64 {
+    300
+}
+
+fn default_agent_idle_timeout() -> u64 {
+    180
+}
+
+fn default_connection_stats_interval() -> u64 {
+    60
+}
+
+fn default_traffic_usage_state_file() -> PathBuf {
+    PathBuf::from("traffic_usage.toml")
+}
+
+fn default_traffic_usage_save_interval() -> u64 {
+    30
+}
+
+impl Default for TrafficUsageConfig {
+    fn default() -> Self {
+        Self {
+            state_file: default_traffic_usage_state_file(),
+            save_interval: default_traffic_usage_save_interval(),
+        }
     }
 }
 
@@ -57,6 +258,24 @@ pub struct ForwardConfig {
     connection_pool: Option<ConnectionPoolConfig>,
     #[access(get(ty=&str))]
     username: String,
+    #[serde(default)]
+    #[access(get)]
+    proxy_selector: ProxySelectorConfig,
+    /// The Happy Eyeballs configuration when connecting to the forward proxy servers
+    #[serde(default)]
+    happy_eyeballs: HappyEyeballsConfig,
+    /// The deadlines of the handshake and the tunnel init with the forward proxy servers
+    #[serde(default)]
+    deadline: DeadlineConfig,
+    /// Request the obfuscation to the forward proxy servers in the handshake
+    #[serde(default)]
+    obfuscation: ObfuscationConfig,
+    /// Request the compression to the forward proxy servers in the handshake
+    #[serde(default)]
+    compression: CompressionConfig,
+    /// Request the rekey to the forward proxy servers in the handshake
+    #[serde(default)]
+    rekey: RekeyConfig,
 }
 
 impl RetrieveConnectionConfig for ForwardConfig {
@@ -66,13 +285,40 @@ impl RetrieveConnectionConfig for ForwardConfig {
     fn connect_timeout(&self) -> u64 {
         self.proxy_connect_timeout
     }
+    fn happy_eyeballs(&self) -> &HappyEyeballsConfig {
+        &self.happy_eyeballs
+    }
+    fn deadline(&self) -> &DeadlineConfig {
+        &self.deadline
+    }
+    fn obfuscation(&self) -> &ObfuscationConfig {
+        &self.obfuscation
+    }
+    fn compression(&self) -> &CompressionConfig {
+        &self.compression
+    }
+    fn rekey(&self) -> &RekeyConfig {
+        &self.rekey
+    }
 }
 
 impl RetrieveConnectionPoolConfig for ForwardConfig {
-    fn max_pool_size(&self) -> usize {
+    fn min_idle(&self) -> usize {
         match &self.connection_pool {
             None => 0,
-            Some(pool_config) => pool_config.max_pool_size(),
+            Some(pool_config) => pool_config.min_idle(),
+        }
+    }
+    fn max_idle(&self) -> usize {
+        match &self.connection_pool {
+            None => 0,
+            Some(pool_config) => pool_config.max_idle(),
+        }
+    }
+    fn target_hit_rate(&self) -> f64 {
+        match &self.connection_pool {
+            None => 0f64,
+            Some(pool_config) => pool_config.target_hit_rate(),
         }
     }
     fn fill_interval(&self) -> u64 {
@@ -99,10 +345,22 @@ impl RetrieveConnectionPoolConfig for ForwardConfig {
             Some(pool_config) => pool_config.heartbeat_timeout(),
         }
     }
-    fn retake_interval(&self) -> u64 {
+    fn max_take_wait(&self) -> u64 {
         match &self.connection_pool {
-            None => 2,
-            Some(pool_config) => pool_config.retake_interval(),
+            None => 0,
+            Some(pool_config) => pool_config.max_take_wait(),
+        }
+    }
+    fn max_fill_backoff(&self) -> u64 {
+        match &self.connection_pool {
+            None => 0,
+            Some(pool_config) => pool_config.max_fill_backoff(),
+        }
+    }
+    fn take_check_threshold(&self) -> u64 {
+        match &self.connection_pool {
+            None => 0,
+            Some(pool_config) => pool_config.take_check_threshold(),
         }
     }
 }
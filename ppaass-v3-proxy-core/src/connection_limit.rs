@@ -0,0 +1,167 @@
+use ppaass_common::error::CommonError;
+use ppaass_common::user::limit::ConnectionLimit;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::sleep;
+use tracing::{error, info};
+
+/// The tunnels a user has open currently
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UserConnectionStats {
+    pub tunnels: usize,
+    pub connections_per_ip: HashMap<IpAddr, usize>,
+}
+
+/// Count the open tunnels of the users, a tunnel over the limit of the user
+/// is rejected and the count is released when the guard drops.
+#[derive(Default)]
+pub struct ConnectionLimiter {
+    users: Mutex<HashMap<String, UserConnectionStats>>,
+}
+
+impl ConnectionLimiter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn acquire(
+        self: &Arc<Self>,
+        username: &str,
+        agent_ip: IpAddr,
+        connection_limit: Option<ConnectionLimit>,
+    ) -> Result<ConnectionLimitGuard, CommonError> {
+        let mut users = self
+            .users
+            .lock()
+            .map_err(|e| CommonError::Other(format!("Fail to lock connection limiter: {e}")))?;
+        // The user is only counted when the tunnel is accepted, a rejected
+        // tunnel leaves no entry
+        let (tunnels, ip_connections) = users
+            .get(username)
+            .map(|user_stats| {
+                (
+                    user_stats.tunnels,
+                    user_stats
+                        .connections_per_ip
+                        .get(&agent_ip)
+                        .copied()
+                        .unwrap_or(0),
+                )
+            })
+            .unwrap_or((0, 0));
+        let connection_limit = connection_limit.unwrap_or_default();
+        if let Some(max_concurrent_tunnels) = connection_limit.max_concurrent_tunnels
+            && tunnels >= max_concurrent_tunnels
+        {
+            return Err(CommonError::ConnectionLimitExceeded(format!(
+                "user [{username}] reached {max_concurrent_tunnels} concurrent tunnels"
+            )));
+        }
+        if let Some(max_connections_per_ip) = connection_limit.max_connections_per_ip
+            && ip_connections >= max_connections_per_ip
+        {
+            return Err(CommonError::ConnectionLimitExceeded(format!(
+                "user [{username}] reached {max_connections_per_ip} connections from {agent_ip}"
+            )));
+        }
+        let user_stats = users.entry(username.to_owned()).or_default();
+        user_stats.tunnels += 1;
+        *user_stats.connections_per_ip.entry(agent_ip).or_insert(0) += 1;
+        Ok(ConnectionLimitGuard {
+            limiter: self.clone(),
+            username: username.to_owned(),
+            agent_ip,
+        })
+    }
+
+    fn release(&self, username: &str, agent_ip: IpAddr) {
+        let mut users = match self.users.lock() {
+            Ok(users) => users,
+            Err(e) => {
+                error!("Fail to lock connection limiter: {e:?}");
+                return;
+            }
+        };
+        let Some(user_stats) = users.get_mut(username) else {
+            return;
+        };
+        user_stats.tunnels = user_stats.tunnels.saturating_sub(1);
+        if let Some(ip_connections) = user_stats.connections_per_ip.get_mut(&agent_ip) {
+            *ip_connections = ip_connections.saturating_sub(1);
+            if *ip_connections == 0 {
+                user_stats.connections_per_ip.remove(&agent_ip);
+            }
+        }
+        if user_stats.tunnels == 0 {
+            users.remove(username);
+        }
+    }
+
+    /// The users with open tunnels
+    pub fn stats(&self) -> HashMap<String, UserConnectionStats> {
+        match self.users.lock() {
+            Ok(users) => users.clone(),
+            Err(e) => {
+                error!("Fail to lock connection limiter: {e:?}");
+                HashMap::new()
+            }
+        }
+    }
+
+    pub fn start_log_stats(self: &Arc<Self>, stats_interval: u64) {
+        let limiter = self.clone();
+        tokio::spawn(async move {
+            loop {
+                sleep(Duration::from_secs(stats_interval)).await;
+                let stats = limiter.stats();
+                if stats.is_empty() {
+                    continue;
+                }
+                info!("Open tunnels of users: {stats:?}");
+            }
+        });
+    }
+}
+
+/// Hold the tunnel count of the user until the tunnel finish
+pub struct ConnectionLimitGuard {
+    limiter: Arc<ConnectionLimiter>,
+    username: String,
+    agent_ip: IpAddr,
+}
+
+impl Drop for ConnectionLimitGuard {
+    fn drop(&mut self) {
+        self.limiter.release(&self.username, self.agent_ip);
+    }
+}
+
+#[test]
+fn test() {
+    let limiter = Arc::new(ConnectionLimiter::new());
+    let limit = Some(ConnectionLimit {
+        max_concurrent_tunnels: Some(3),
+        max_connections_per_ip: Some(2),
+    });
+    let ip1: IpAddr = "10.0.0.1".parse().unwrap();
+    let ip2: IpAddr = "10.0.0.2".parse().unwrap();
+    let guard1 = limiter.acquire("user", ip1, limit).unwrap();
+    let _guard2 = limiter.acquire("user", ip1, limit).unwrap();
+    assert!(limiter.acquire("user", ip1, limit).is_err());
+    let _guard3 = limiter.acquire("user", ip2, limit).unwrap();
+    assert!(limiter.acquire("user", ip2, limit).is_err());
+    assert_eq!(3, limiter.stats()["user"].tunnels);
+    drop(guard1);
+    assert_eq!(1, limiter.stats()["user"].connections_per_ip[&ip1]);
+    assert!(limiter.acquire("user", ip1, limit).is_ok());
+    assert!(limiter.acquire("other", ip1, None).is_ok());
+    assert!(!limiter.stats().contains_key("other"));
+    let zero_limit = Some(ConnectionLimit {
+        max_concurrent_tunnels: Some(0),
+        max_connections_per_ip: None,
+    });
+    assert!(limiter.acquire("blocked", ip1, zero_limit).is_err());
+    assert!(!limiter.stats().contains_key("blocked"));
+}
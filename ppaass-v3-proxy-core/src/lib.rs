@@ -1,5 +1,8 @@
+pub mod bandwidth;
 pub mod config;
+pub mod connection_limit;
 pub mod error;
+pub mod quota;
 
 pub mod tunnel;
 pub mod user;
@@ -0,0 +1,177 @@
+use crate::config::TrafficUsageConfig;
+use chrono::{DateTime, Utc};
+use ppaass_common::error::CommonError;
+use ppaass_common::user::limit::TrafficQuota;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{read_to_string, rename, write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::sleep;
+use tracing::{debug, error};
+
+/// The traffic a user relayed in the current period
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UserTrafficUsage {
+    pub period_start: DateTime<Utc>,
+    pub bytes: u64,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct TrafficUsageState {
+    #[serde(default)]
+    users: HashMap<String, UserTrafficUsage>,
+}
+
+/// Count the traffic of the users against their quotas, the usage is saved
+/// to the state file periodically and loaded again on start.
+pub struct TrafficQuotaManager {
+    state_file: PathBuf,
+    usage: Mutex<TrafficUsageState>,
+    changed: AtomicBool,
+}
+
+impl TrafficQuotaManager {
+    pub fn new(config: &TrafficUsageConfig) -> Result<Self, CommonError> {
+        let usage = match read_to_string(&config.state_file) {
+            Ok(content) => toml::from_str::<TrafficUsageState>(&content).map_err(|e| {
+                CommonError::Other(format!(
+                    "Fail to parse traffic usage state file [{:?}]: {e}",
+                    config.state_file
+                ))
+            })?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => TrafficUsageState::default(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self {
+            state_file: config.state_file.clone(),
+            usage: Mutex::new(usage),
+            changed: AtomicBool::new(false),
+        })
+    }
+
+    /// The usage of the user in the current period of the quota
+    fn current_usage<'a>(
+        usage: &'a mut TrafficUsageState,
+        username: &str,
+        quota: &TrafficQuota,
+    ) -> &'a mut UserTrafficUsage {
+        let period_start = quota.period_start(Utc::now());
+        let user_usage = usage
+            .users
+            .entry(username.to_owned())
+            .or_insert(UserTrafficUsage {
+                period_start,
+                bytes: 0,
+            });
+        if user_usage.period_start != period_start {
+            user_usage.period_start = period_start;
+            user_usage.bytes = 0;
+        }
+        user_usage
+    }
+
+    /// Fail when the user used up the quota in the current period
+    pub fn check(&self, username: &str, quota: Option<&TrafficQuota>) -> Result<(), CommonError> {
+        let Some(quota) = quota else {
+            return Ok(());
+        };
+        let mut usage = self
+            .usage
+            .lock()
+            .map_err(|e| CommonError::Other(format!("Fail to lock traffic usage: {e}")))?;
+        if Self::current_usage(&mut usage, username, quota).bytes >= quota.bytes {
+            return Err(CommonError::QuotaExceeded(username.to_owned()));
+        }
+        Ok(())
+    }
+
+    /// Add the relayed bytes of a tunnel to the usage of the user
+    pub fn record(&self, username: &str, quota: Option<&TrafficQuota>, bytes: u64) {
+        let Some(quota) = quota else {
+            return;
+        };
+        let mut usage = match self.usage.lock() {
+            Ok(usage) => usage,
+            Err(e) => {
+                error!("Fail to lock traffic usage: {e:?}");
+                return;
+            }
+        };
+        let user_usage = Self::current_usage(&mut usage, username, quota);
+        user_usage.bytes = user_usage.bytes.saturating_add(bytes);
+        self.changed.store(true, Ordering::Relaxed);
+    }
+
+    pub fn usage(&self, username: &str) -> Option<UserTrafficUsage> {
+        let usage = self.usage.lock().ok()?;
+        usage.users.get(username).copied()
+    }
+
+    /// Write the usage into a temporary file then replace the state file,
+    /// so a crash in writing does not break the saved usage.
+    pub fn save(&self) -> Result<(), CommonError> {
+        if !self.changed.swap(false, Ordering::Relaxed) {
+            return Ok(());
+        }
+        let content = {
+            let usage = self
+                .usage
+                .lock()
+                .map_err(|e| CommonError::Other(format!("Fail to lock traffic usage: {e}")))?;
+            toml::to_string(&*usage)
+                .map_err(|e| CommonError::Other(format!("Fail to serialize traffic usage: {e}")))?
+        };
+        let mut temp_state_file = self.state_file.clone().into_os_string();
+        temp_state_file.push(".tmp");
+        write(&temp_state_file, content)?;
+        rename(&temp_state_file, &self.state_file)?;
+        debug!("Save traffic usage to: {:?}", self.state_file);
+        Ok(())
+    }
+
+    pub fn start_save(self: &Arc<Self>, save_interval: u64) {
+        let manager = self.clone();
+        tokio::spawn(async move {
+            loop {
+                sleep(Duration::from_secs(save_interval)).await;
+                if let Err(e) = manager.save() {
+                    // Save again in next round
+                    manager.changed.store(true, Ordering::Relaxed);
+                    error!("Fail to save traffic usage: {e:?}");
+                }
+            }
+        });
+    }
+}
+
+#[test]
+fn test() {
+    use ppaass_common::user::limit::QuotaPeriod;
+    let state_file =
+        std::env::temp_dir().join(format!("ppaass-traffic-usage-{}.toml", std::process::id()));
+    let config = TrafficUsageConfig {
+        state_file: state_file.clone(),
+        save_interval: 1,
+    };
+    let quota = TrafficQuota {
+        bytes: 1000,
+        period: QuotaPeriod::Monthly,
+        reset_day: 1,
+    };
+    let manager = TrafficQuotaManager::new(&config).unwrap();
+    assert!(manager.check("user", Some(&quota)).is_ok());
+    manager.record("user", Some(&quota), 600);
+    assert!(manager.check("user", Some(&quota)).is_ok());
+    manager.record("user", Some(&quota), 600);
+    assert!(manager.check("user", Some(&quota)).is_err());
+    assert!(manager.check("user", None).is_ok());
+    manager.save().unwrap();
+    // The usage is loaded after restart
+    let manager = TrafficQuotaManager::new(&config).unwrap();
+    assert_eq!(1200, manager.usage("user").unwrap().bytes);
+    assert!(manager.check("user", Some(&quota)).is_err());
+    std::fs::remove_file(state_file).unwrap();
+}
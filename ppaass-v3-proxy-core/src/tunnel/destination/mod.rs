@@ -1,37 +1,47 @@
 mod tcp;
 use crate::config::ForwardConfig;
-use ppaass_common::config::RetrieveConnectionConfig;
+use crate::tunnel::ForwardCompressionCounter;
+use ppaass_common::config::{HappyEyeballsConfig, RetrieveConnectionConfig};
 use ppaass_common::error::CommonError;
+use ppaass_common::rsa_pool::RsaPool;
 use ppaass_common::server::ServerState;
 use ppaass_common::user::UserInfo;
 use ppaass_common::{
-    CryptoLengthDelimitedFramed, FramedConnection, ProxyTcpConnectionNewState,
+    CryptoLengthDelimitedFramed, FramedConnection, ProxyServerSelector, ProxyTcpConnectionNewState,
     ProxyTcpConnectionPool, TunnelInitRequest, UnifiedAddress,
 };
+use std::net::SocketAddr;
 use std::sync::Arc;
 pub use tcp::*;
 use tokio::net::TcpStream;
 use tokio::sync::RwLock;
 use tokio_util::bytes::BytesMut;
 use tokio_util::io::{SinkWriter, StreamReader};
+type ForwardProxyConnection =
+    FramedConnection<SinkWriter<StreamReader<CryptoLengthDelimitedFramed<TcpStream>, BytesMut>>>;
+
 pub enum DestinationEdge {
     Direct(DestinationTcpEndpoint),
-    Forward(
-        FramedConnection<
-            SinkWriter<StreamReader<CryptoLengthDelimitedFramed<TcpStream>, BytesMut>>,
-        >,
-    ),
+    /// Boxed because the forward proxy connection carries the codec state
+    Forward(Box<ForwardProxyConnection>),
 }
 
 impl DestinationEdge {
     pub async fn start_direct(
         destination_address: UnifiedAddress,
+        destination_socket_addresses: Vec<SocketAddr>,
         keep_alive: bool,
+        happy_eyeballs: &HappyEyeballsConfig,
         connect_timeout: u64,
     ) -> Result<Self, CommonError> {
-        let destination_tcp_connection =
-            DestinationTcpEndpoint::connect(destination_address, keep_alive, connect_timeout)
-                .await?;
+        let destination_tcp_connection = DestinationTcpEndpoint::connect(
+            destination_address,
+            destination_socket_addresses,
+            keep_alive,
+            happy_eyeballs,
+            connect_timeout,
+        )
+        .await?;
         Ok(Self::Direct(destination_tcp_connection))
     }
 
@@ -43,28 +53,42 @@ impl DestinationEdge {
         let (username, user_info) = server_state
             .get_value::<(String, Arc<RwLock<UserInfo>>)>()
             .ok_or(CommonError::Other("Can not find forward user".to_owned()))?;
-        let proxy_tcp_connection_pool =
-            match server_state.get_value::<Arc<ProxyTcpConnectionPool<ForwardConfig>>>() {
-                None => {
-                    let user_info = user_info.read().await;
-                    FramedConnection::<ProxyTcpConnectionNewState>::create(
-                        &username,
-                        &user_info,
-                        forward_config.frame_size(),
-                        forward_config.connect_timeout(),
-                    )
-                    .await?
-                }
-                Some(pool) => pool.take_proxy_connection().await?,
-            };
-
-        let proxy_tcp_connection = proxy_tcp_connection_pool
-            .tunnel_init(TunnelInitRequest {
-                destination_address: destination_address.clone(),
-                keep_alive: false,
-            })
-            .await?;
+        let tunnel_init_request = TunnelInitRequest {
+            destination_address,
+            keep_alive: false,
+        };
+        let proxy_tcp_connection = match server_state
+            .get_value::<Arc<ProxyTcpConnectionPool<ForwardConfig>>>()
+        {
+            None => {
+                let proxy_server_selector =
+                    server_state.get_value::<Arc<ProxyServerSelector>>().ok_or(
+                        CommonError::Other("Can not find forward proxy server selector".to_owned()),
+                    )?;
+                let ForwardCompressionCounter(compression_counter) = server_state
+                    .get_value::<ForwardCompressionCounter>()
+                    .ok_or(CommonError::Other(
+                        "Can not find forward compression counter".to_owned(),
+                    ))?;
+                let rsa_pool = server_state
+                    .get_value::<Arc<RsaPool>>()
+                    .ok_or(CommonError::Other("Can not find RSA pool".to_owned()))?;
+                let user_info = user_info.read().await;
+                FramedConnection::<ProxyTcpConnectionNewState>::create(
+                    username,
+                    &user_info,
+                    proxy_server_selector,
+                    rsa_pool,
+                    compression_counter,
+                    forward_config,
+                )
+                .await?
+                .tunnel_init(tunnel_init_request)
+                .await?
+            }
+            Some(pool) => pool.tunnel_init(tunnel_init_request).await?,
+        };
 
-        Ok(Self::Forward(proxy_tcp_connection))
+        Ok(Self::Forward(Box::new(proxy_tcp_connection)))
     }
 }
@@ -1,12 +1,11 @@
 use futures_util::{Sink, SinkExt, Stream, StreamExt};
+use ppaass_common::config::HappyEyeballsConfig;
 use ppaass_common::error::CommonError;
-use ppaass_common::UnifiedAddress;
+use ppaass_common::{happy_eyeballs_connect, UnifiedAddress};
 use std::net::SocketAddr;
 use std::pin::Pin;
 use std::task::{Context, Poll};
-use std::time::Duration;
 use tokio::net::TcpStream;
-use tokio::time::timeout;
 use tokio_util::bytes::BytesMut;
 use tokio_util::codec::{BytesCodec, Framed};
 use tracing::debug;
@@ -17,20 +16,17 @@ pub struct DestinationTcpEndpoint {
 impl DestinationTcpEndpoint {
     pub async fn connect(
         destination_address: UnifiedAddress,
+        destination_socket_addresses: Vec<SocketAddr>,
         _keep_alive: bool,
+        happy_eyeballs: &HappyEyeballsConfig,
         connect_timeout: u64,
     ) -> Result<Self, CommonError> {
-        let destination_socks_addrs: Vec<SocketAddr> =
-            destination_address.clone().try_into().map_err(|e| {
-                CommonError::Other(format!(
-                    "Fail to convert destination address to socket address: {e}"
-                ))
-            })?;
-        let destination_tcp_stream = timeout(
-            Duration::from_secs(connect_timeout),
-            TcpStream::connect(destination_socks_addrs.as_slice()),
+        let (destination_tcp_stream, _) = happy_eyeballs_connect(
+            &destination_socket_addresses,
+            happy_eyeballs,
+            connect_timeout,
         )
-        .await??;
+        .await?;
         destination_tcp_stream.set_nodelay(true)?;
         debug!("Connected to destination success: {}", destination_address);
         Ok(DestinationTcpEndpoint {
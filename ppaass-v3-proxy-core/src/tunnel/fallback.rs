@@ -0,0 +1,45 @@
+use crate::config::ProxyConfig;
+use ppaass_common::error::CommonError;
+use ppaass_common::relay::{copy_bidirectional_with_idle_timeout, DEFAULT_RELAY_BUFFER_SIZE};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+use tokio_util::bytes::BytesMut;
+use tracing::debug;
+
+/// Relay the connection failing the handshake to the fallback server, the
+/// bytes received in handshake are sent first so the client talks with the
+/// fallback server as if it connected to the fallback server directly.
+pub async fn splice_to_fallback(
+    mut agent_tcp_stream: TcpStream,
+    received_bytes: BytesMut,
+    fallback_address: SocketAddr,
+    config: &ProxyConfig,
+) -> Result<(), CommonError> {
+    let mut fallback_tcp_stream = timeout(
+        Duration::from_secs(config.destination_connect_timeout()),
+        TcpStream::connect(fallback_address),
+    )
+    .await??;
+    fallback_tcp_stream.set_nodelay(true)?;
+    fallback_tcp_stream.write_all(&received_bytes).await?;
+    let (relay_result, relay_error) = copy_bidirectional_with_idle_timeout(
+        &mut agent_tcp_stream,
+        &mut fallback_tcp_stream,
+        DEFAULT_RELAY_BUFFER_SIZE,
+        DEFAULT_RELAY_BUFFER_SIZE,
+        config.relay_idle_timeout(),
+    )
+    .await;
+    debug!(
+        "[FALLBACK] Copy data between client and fallback server [{fallback_address}], client data size: {}, fallback data size: {}",
+        received_bytes.len() as u64 + relay_result.a_data_size,
+        relay_result.b_data_size
+    );
+    if let Some(e) = relay_error {
+        return Err(e.into());
+    }
+    Ok(())
+}
@@ -0,0 +1,62 @@
+use crate::config::DestinationGuardConfig;
+use ppaass_common::error::CommonError;
+use std::net::{IpAddr, SocketAddr};
+use tracing::warn;
+
+fn is_blocked(config: &DestinationGuardConfig, ip_address: IpAddr) -> bool {
+    // The IPv4-mapped IPv6 address is checked as IPv4 address
+    let ip_address = ip_address.to_canonical();
+    if config
+        .allow_networks
+        .iter()
+        .any(|network| network.contains(&ip_address))
+    {
+        return false;
+    }
+    config
+        .deny_networks
+        .iter()
+        .any(|network| network.contains(&ip_address))
+}
+
+/// Check all the resolved destination addresses before connecting, the
+/// tunnel is blocked when any of them is blocked, so a domain resolving
+/// to both public and internal addresses can not reach the internal one.
+pub fn check_destination_addresses(
+    config: &DestinationGuardConfig,
+    destination_addresses: &[SocketAddr],
+) -> Result<(), CommonError> {
+    if !config.enabled {
+        return Ok(());
+    }
+    for destination_address in destination_addresses {
+        if is_blocked(config, destination_address.ip()) {
+            warn!("Block the tunnel to destination: {destination_address}");
+            return Err(CommonError::DestinationBlocked(*destination_address));
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test() {
+    let mut config = DestinationGuardConfig::default();
+    let public: SocketAddr = "93.184.216.34:443".parse().unwrap();
+    let loopback: SocketAddr = "127.0.0.1:80".parse().unwrap();
+    let metadata: SocketAddr = "169.254.169.254:80".parse().unwrap();
+    let mapped_private: SocketAddr = "[::ffff:10.0.0.1]:80".parse().unwrap();
+    let nat64: SocketAddr = "[64:ff9b::a9fe:a9fe]:80".parse().unwrap();
+    let local_nat64: SocketAddr = "[64:ff9b:1::a00:1]:80".parse().unwrap();
+    let six_to_four: SocketAddr = "[2002:a9fe:a9fe::1]:80".parse().unwrap();
+    assert!(check_destination_addresses(&config, &[public]).is_ok());
+    assert!(check_destination_addresses(&config, &[loopback]).is_err());
+    assert!(check_destination_addresses(&config, &[mapped_private]).is_err());
+    assert!(check_destination_addresses(&config, &[nat64]).is_err());
+    assert!(check_destination_addresses(&config, &[local_nat64]).is_err());
+    assert!(check_destination_addresses(&config, &[six_to_four]).is_err());
+    assert!(check_destination_addresses(&config, &[public, metadata]).is_err());
+    config.allow_networks = vec!["127.0.0.0/8".parse().unwrap()];
+    assert!(check_destination_addresses(&config, &[loopback]).is_ok());
+    config.enabled = false;
+    assert!(check_destination_addresses(&config, &[metadata]).is_ok());
+}
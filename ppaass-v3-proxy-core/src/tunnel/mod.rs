@@ -1,21 +1,120 @@
+use crate::bandwidth::{BandwidthLimitedStream, BandwidthManager};
 use crate::config::ProxyConfig;
+use crate::connection_limit::ConnectionLimiter;
+use crate::quota::TrafficQuotaManager;
 use crate::tunnel::destination::DestinationEdge;
+use crate::tunnel::fallback::splice_to_fallback;
+use crate::tunnel::guard::check_destination_addresses;
+use chrono::{DateTime, Utc};
+use ppaass_common::ban::BanTracker;
+use ppaass_common::compression::CompressionCounter;
+use ppaass_common::dns::{resolve_unified_address, DnsResolver};
 use ppaass_common::error::CommonError;
+use ppaass_common::relay::{copy_bidirectional_with_idle_timeout, DEFAULT_RELAY_BUFFER_SIZE};
+use ppaass_common::replay::ReplayGuard;
+use ppaass_common::rsa_pool::RsaPool;
 use ppaass_common::server::ServerState;
-use ppaass_common::user::repo::fs::FileSystemUserInfoRepository;
+use ppaass_common::user::acl::DestinationAcl;
+use ppaass_common::user::limit::{BandwidthLimit, ConnectionLimit, TrafficQuota};
+use ppaass_common::user::repo::fs::{
+    FileSystemUserInfoRepository, USER_INFO_ADDITION_INFO_BANDWIDTH_LIMIT,
+    USER_INFO_ADDITION_INFO_CONNECTION_LIMIT, USER_INFO_ADDITION_INFO_DESTINATION_ACL,
+    USER_INFO_ADDITION_INFO_EXPIRED_DATE_TIME, USER_INFO_ADDITION_INFO_TRAFFIC_QUOTA,
+};
+use ppaass_common::user::UserInfo;
 use ppaass_common::{
-    AgentTcpConnectionNewState, AgentTcpConnectionTunnelCtlState, FramedConnection,
-    TunnelInitFailureReason, TunnelInitRequest, TunnelInitResponse,
+    AgentHandshakeError, AgentTcpConnectionNewState, AgentTcpConnectionTunnelCtlState,
+    FramedConnection, TunnelInitFailureReason, TunnelInitRequest, TunnelInitResponse,
+    UnifiedAddress,
 };
+use std::io::ErrorKind;
 use std::net::SocketAddr;
 use std::sync::Arc;
-use tokio::{
-    io::{copy_bidirectional, copy_bidirectional_with_sizes},
-    net::TcpStream,
-};
+use tokio::net::TcpStream;
 use tokio_util::io::{SinkWriter, StreamReader};
-use tracing::debug;
+use tracing::{debug, info};
 mod destination;
+mod fallback;
+mod guard;
+
+/// The compression counter of the forward proxy connections, kept in the
+/// server state apart from the counter of the agent connections
+pub struct ForwardCompressionCounter(pub Arc<CompressionCounter>);
+
+/// The failure reason answered to the agent for the tunnel init error
+fn tunnel_init_failure_reason(error: &CommonError) -> TunnelInitFailureReason {
+    match error {
+        CommonError::DestinationBlocked(_) => TunnelInitFailureReason::DestinationBlocked,
+        CommonError::ForbiddenByPolicy(..) => TunnelInitFailureReason::ForbiddenByPolicy,
+        CommonError::UserExpired(_) => TunnelInitFailureReason::UserExpired,
+        CommonError::QuotaExceeded(_) => TunnelInitFailureReason::QuotaExceeded,
+        CommonError::ConnectionLimitExceeded(_) => TunnelInitFailureReason::ConnectionLimitExceeded,
+        CommonError::DnsResolve(..) => TunnelInitFailureReason::DnsResolveFail,
+        CommonError::Timeout(_)
+        | CommonError::HandshakeRequestTimeout(..)
+        | CommonError::HandshakeResponseTimeout(..)
+        | CommonError::TunnelInitTimeout(..) => TunnelInitFailureReason::ConnectTimeout,
+        CommonError::Io(e) => match e.kind() {
+            ErrorKind::ConnectionRefused => TunnelInitFailureReason::ConnectionRefused,
+            ErrorKind::TimedOut => TunnelInitFailureReason::ConnectTimeout,
+            ErrorKind::HostUnreachable
+            | ErrorKind::NetworkUnreachable
+            | ErrorKind::AddrNotAvailable => TunnelInitFailureReason::DestinationUnreachable,
+            _ => TunnelInitFailureReason::InitWithDestinationFail,
+        },
+        // The reason from the forward proxy is passed to the agent as it is
+        CommonError::TunnelInitRejected(reason) => *reason,
+        _ => TunnelInitFailureReason::InitWithDestinationFail,
+    }
+}
+
+/// The handshake error caused by the agent, not by the network, the malformed
+/// handshake is not counted when the connection is handed to the fallback server
+fn is_handshake_failure(error: &CommonError, fallback: bool) -> bool {
+    match error {
+        CommonError::RsaCryptoNotFound(_)
+        | CommonError::Rsa(_)
+        | CommonError::UserExpired(_)
+        | CommonError::HandshakeReplay(_) => true,
+        CommonError::BincodeDecode(_)
+        | CommonError::Protocol(_)
+        | CommonError::HandshakeRequestTimeout(..) => !fallback,
+        CommonError::Io(e) if e.kind() == ErrorKind::InvalidData => !fallback,
+        _ => false,
+    }
+}
+
+/// The user info used by the tunnel, copied out so the user info is not
+/// locked while the tunnel is initialized
+struct TunnelUserLimits {
+    expired_time: Option<DateTime<Utc>>,
+    destination_acl: Option<DestinationAcl>,
+    bandwidth_limit: Option<BandwidthLimit>,
+    traffic_quota: Option<TrafficQuota>,
+    connection_limit: Option<ConnectionLimit>,
+}
+
+impl TunnelUserLimits {
+    fn new(user_info: &UserInfo) -> Self {
+        Self {
+            expired_time: user_info
+                .get_additional_info::<DateTime<Utc>>(USER_INFO_ADDITION_INFO_EXPIRED_DATE_TIME)
+                .copied(),
+            destination_acl: user_info
+                .get_additional_info::<DestinationAcl>(USER_INFO_ADDITION_INFO_DESTINATION_ACL)
+                .cloned(),
+            bandwidth_limit: user_info
+                .get_additional_info::<BandwidthLimit>(USER_INFO_ADDITION_INFO_BANDWIDTH_LIMIT)
+                .copied(),
+            traffic_quota: user_info
+                .get_additional_info::<TrafficQuota>(USER_INFO_ADDITION_INFO_TRAFFIC_QUOTA)
+                .copied(),
+            connection_limit: user_info
+                .get_additional_info::<ConnectionLimit>(USER_INFO_ADDITION_INFO_CONNECTION_LIMIT)
+                .copied(),
+        }
+    }
+}
 
 pub struct Tunnel {
     config: Arc<ProxyConfig>,
@@ -30,17 +129,38 @@ impl Tunnel {
         server_state: Arc<ServerState>,
         agent_tcp_stream: TcpStream,
         agent_socket_address: SocketAddr,
-    ) -> Result<Self, CommonError> {
+    ) -> Result<Self, AgentHandshakeError> {
         let user_repo = server_state
             .get_value::<Arc<FileSystemUserInfoRepository>>()
             .ok_or(CommonError::Other(format!(
                 "Fail to get user crypto repository for agent: {agent_socket_address}"
             )))?;
+        let Some(replay_guard) = server_state.get_value::<Arc<ReplayGuard>>() else {
+            return Err(CommonError::Other(format!(
+                "Fail to get handshake replay guard for agent: {agent_socket_address}"
+            ))
+            .into());
+        };
+        let Some(rsa_pool) = server_state.get_value::<Arc<RsaPool>>() else {
+            return Err(CommonError::Other(format!(
+                "Fail to get RSA pool for agent: {agent_socket_address}"
+            ))
+            .into());
+        };
+        let Some(compression_counter) = server_state.get_value::<Arc<CompressionCounter>>() else {
+            return Err(CommonError::Other(format!(
+                "Fail to get compression counter for agent: {agent_socket_address}"
+            ))
+            .into());
+        };
         let agent_tcp_connection = FramedConnection::<AgentTcpConnectionNewState>::create(
             agent_tcp_stream,
             agent_socket_address,
             user_repo.as_ref(),
-            config.agent_frame_buffer_size(),
+            replay_guard.as_ref(),
+            rsa_pool.as_ref(),
+            compression_counter,
+            config.as_ref(),
         )
         .await?;
         Ok(Self {
@@ -51,9 +171,42 @@ impl Tunnel {
         })
     }
 
+    /// Check the destination with the access control list of the user,
+    /// the resolved addresses are checked one by one when there are.
+    fn check_destination_acl(
+        username: &str,
+        destination_acl: Option<&DestinationAcl>,
+        destination_address: &UnifiedAddress,
+        destination_socket_addresses: &[SocketAddr],
+    ) -> Result<(), CommonError> {
+        let Some(destination_acl) = destination_acl else {
+            return Ok(());
+        };
+        let (domain, port) = match destination_address {
+            UnifiedAddress::Domain { host, port } => (Some(host.as_str()), *port),
+            UnifiedAddress::SocketAddress(socket_address) => (None, socket_address.port()),
+        };
+        let allowed = if destination_socket_addresses.is_empty() {
+            destination_acl.is_allowed(domain, None, port)
+        } else {
+            destination_socket_addresses.iter().all(|socket_address| {
+                destination_acl.is_allowed(domain, Some(socket_address.ip()), port)
+            })
+        };
+        if !allowed {
+            return Err(CommonError::ForbiddenByPolicy(
+                username.to_owned(),
+                destination_address.to_string(),
+            ));
+        }
+        Ok(())
+    }
+
     async fn initialize_tunnel(
         tunnel_init_request: TunnelInitRequest,
         agent_socket_address: SocketAddr,
+        username: &str,
+        user_limits: &TunnelUserLimits,
         config: &ProxyConfig,
         server_state: &ServerState,
     ) -> Result<DestinationEdge, CommonError> {
@@ -61,14 +214,42 @@ impl Tunnel {
             destination_address,
             keep_alive,
         } = tunnel_init_request;
+        // The agent connection may be kept in pool long after the handshake
+        if let Some(user_expired_time) = user_limits.expired_time
+            && Utc::now() > user_expired_time
+        {
+            return Err(CommonError::UserExpired(username.to_owned()));
+        }
+        if let Some(traffic_quota_manager) = server_state.get_value::<Arc<TrafficQuotaManager>>() {
+            traffic_quota_manager.check(username, user_limits.traffic_quota.as_ref())?;
+        }
+        let destination_acl = user_limits.destination_acl.as_ref();
         match config.forward() {
             None => {
                 debug!(
                     "[START TCP] Begin to initialize tunnel for agent: {agent_socket_address:?}"
                 );
+                let dns_resolver = server_state
+                    .get_value::<Arc<dyn DnsResolver>>()
+                    .ok_or(CommonError::Other("Can not find dns resolver".to_owned()))?;
+                // Connect to the checked addresses only, the domain is not resolved again
+                let destination_socket_addresses =
+                    resolve_unified_address(dns_resolver.as_ref(), &destination_address).await?;
+                check_destination_addresses(
+                    config.destination_guard(),
+                    &destination_socket_addresses,
+                )?;
+                Self::check_destination_acl(
+                    username,
+                    destination_acl,
+                    &destination_address,
+                    &destination_socket_addresses,
+                )?;
                 let destination_edge = DestinationEdge::start_direct(
                     destination_address,
+                    destination_socket_addresses,
                     keep_alive,
+                    config.destination_happy_eyeballs(),
                     config.destination_connect_timeout(),
                 )
                 .await?;
@@ -78,6 +259,26 @@ impl Tunnel {
                 debug!(
                     "[START FORWARD] Begin to initialize tunnel for agent: {agent_socket_address:?}"
                 );
+                // The destination is resolved by the forward proxy, the domain is resolved
+                // here only to check the network rules of the ACL
+                let destination_socket_addresses = match &destination_address {
+                    UnifiedAddress::SocketAddress(socket_address) => vec![*socket_address],
+                    UnifiedAddress::Domain { .. }
+                        if destination_acl.is_some_and(DestinationAcl::has_network_rules) =>
+                    {
+                        let dns_resolver = server_state
+                            .get_value::<Arc<dyn DnsResolver>>()
+                            .ok_or(CommonError::Other("Can not find dns resolver".to_owned()))?;
+                        resolve_unified_address(dns_resolver.as_ref(), &destination_address).await?
+                    }
+                    UnifiedAddress::Domain { .. } => Vec::new(),
+                };
+                Self::check_destination_acl(
+                    username,
+                    destination_acl,
+                    &destination_address,
+                    &destination_socket_addresses,
+                )?;
                 let destination_edge = DestinationEdge::start_forward(
                     server_state,
                     forward_config,
@@ -90,62 +291,145 @@ impl Tunnel {
     }
 
     pub async fn run(mut self) -> Result<(), CommonError> {
-        let tunnel_init_request = self.agent_tcp_connection.wait_tunnel_init().await?;
-        match Self::initialize_tunnel(
-            tunnel_init_request,
-            self.agent_socket_address,
-            self.config.as_ref(),
-            self.server_state.as_ref(),
-        )
-        .await
+        let tunnel_init_request = match self
+            .agent_tcp_connection
+            .wait_tunnel_init(self.config.agent_idle_timeout())
+            .await
         {
+            Ok(tunnel_init_request) => tunnel_init_request,
+            Err(CommonError::IdleTimeout(agent_socket_address, idle_timeout)) => {
+                info!(
+                    "Close agent connection [{agent_socket_address}] because of no tunnel init in {idle_timeout} seconds"
+                );
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+        // The user info is not locked while the tunnel is initialized
+        let user_limits =
+            TunnelUserLimits::new(&*self.agent_tcp_connection.user_info().read().await);
+        // The count is held until the tunnel finish
+        let connection_limit_guard = self
+            .server_state
+            .get_value::<Arc<ConnectionLimiter>>()
+            .map(|connection_limiter| {
+                connection_limiter.acquire(
+                    self.agent_tcp_connection.username(),
+                    self.agent_socket_address.ip(),
+                    user_limits.connection_limit,
+                )
+            })
+            .transpose();
+        let initialize_result = match connection_limit_guard {
+            Ok(connection_limit_guard) => Self::initialize_tunnel(
+                tunnel_init_request,
+                self.agent_socket_address,
+                self.agent_tcp_connection.username(),
+                &user_limits,
+                self.config.as_ref(),
+                self.server_state.as_ref(),
+            )
+            .await
+            .map(|destination_edge| (destination_edge, connection_limit_guard)),
+            Err(e) => Err(e),
+        };
+        match initialize_result {
             Err(e) => {
+                let failure_reason = tunnel_init_failure_reason(&e);
+                debug!(
+                    "Fail to initialize tunnel for agent [{}], reason: {failure_reason:?}, error: {e:?}",
+                    self.agent_socket_address
+                );
                 self.agent_tcp_connection
-                    .response_tunnel_init(TunnelInitResponse::Failure(
-                        TunnelInitFailureReason::InitWithDestinationFail,
-                    ))
+                    .response_tunnel_init(TunnelInitResponse::Failure(failure_reason))
                     .await?;
                 Err(e)
             }
-            Ok(destination_edge) => match destination_edge {
-                DestinationEdge::Direct(destination_tcp_endpoint) => {
-                    let mut agent_tcp_connection = self
-                        .agent_tcp_connection
-                        .response_tunnel_init(TunnelInitResponse::Success)
-                        .await?;
-
-                    let destination_tcp_endpoint = StreamReader::new(destination_tcp_endpoint);
-                    let mut destination_tcp_connection = SinkWriter::new(destination_tcp_endpoint);
-
-                    let (agent_data_size, destination_data_size) = copy_bidirectional_with_sizes(
-                        &mut agent_tcp_connection,
-                        &mut destination_tcp_connection,
-                        self.config.proxy_to_destination_data_relay_buffer_size(),
-                        self.config.destination_to_proxy_data_relay_buffer_size(),
-                    )
+            Ok((destination_edge, _connection_limit_guard)) => {
+                let username = self.agent_tcp_connection.username().to_owned();
+                // All the tunnels of the user share the same bandwidth
+                let user_bandwidth_guard = self
+                    .server_state
+                    .get_value::<Arc<BandwidthManager>>()
+                    .map(|bandwidth_manager| {
+                        bandwidth_manager.acquire(&username, user_limits.bandwidth_limit)
+                    });
+                let upload_limiter = user_bandwidth_guard
+                    .as_ref()
+                    .and_then(|guard| guard.upload_limiter());
+                let download_limiter = user_bandwidth_guard
+                    .as_ref()
+                    .and_then(|guard| guard.download_limiter());
+                let agent_tcp_connection = self
+                    .agent_tcp_connection
+                    .response_tunnel_init(TunnelInitResponse::Success)
                     .await?;
-                    debug!(
-                        "[PROXYING] Copy data between agent and destination, agent data size: {agent_data_size}, destination data size: {destination_data_size}"
+                let mut agent_tcp_connection =
+                    BandwidthLimitedStream::new(agent_tcp_connection, upload_limiter);
+                let (relay_result, relay_error) = match destination_edge {
+                    DestinationEdge::Direct(destination_tcp_endpoint) => {
+                        let destination_tcp_endpoint = StreamReader::new(destination_tcp_endpoint);
+                        let mut destination_tcp_connection = BandwidthLimitedStream::new(
+                            SinkWriter::new(destination_tcp_endpoint),
+                            download_limiter,
+                        );
+
+                        let (relay_result, relay_error) = copy_bidirectional_with_idle_timeout(
+                            &mut agent_tcp_connection,
+                            &mut destination_tcp_connection,
+                            self.config.proxy_to_destination_data_relay_buffer_size(),
+                            self.config.destination_to_proxy_data_relay_buffer_size(),
+                            self.config.relay_idle_timeout(),
+                        )
+                        .await;
+                        debug!(
+                            "[PROXYING] Copy data between agent and destination, agent data size: {}, destination data size: {}",
+                            relay_result.a_data_size, relay_result.b_data_size
+                        );
+                        (relay_result, relay_error)
+                    }
+                    DestinationEdge::Forward(forward_proxy_tcp_connection) => {
+                        let mut forward_proxy_tcp_connection = BandwidthLimitedStream::new(
+                            *forward_proxy_tcp_connection,
+                            download_limiter,
+                        );
+                        let (relay_result, relay_error) = copy_bidirectional_with_idle_timeout(
+                            &mut agent_tcp_connection,
+                            &mut forward_proxy_tcp_connection,
+                            DEFAULT_RELAY_BUFFER_SIZE,
+                            DEFAULT_RELAY_BUFFER_SIZE,
+                            self.config.relay_idle_timeout(),
+                        )
+                        .await;
+                        debug!(
+                            "[FORWARDING] Copy data between agent and proxy, agent data size: {}, proxy data size: {}",
+                            relay_result.a_data_size, relay_result.b_data_size
+                        );
+                        (relay_result, relay_error)
+                    }
+                };
+                if relay_result.idle_timeout {
+                    info!(
+                        "Close tunnel of agent [{}] because of no data in {} seconds",
+                        self.agent_socket_address,
+                        self.config.relay_idle_timeout()
                     );
-                    Ok(())
                 }
-                DestinationEdge::Forward(mut forward_proxy_tcp_connection) => {
-                    let mut agent_tcp_connection = self
-                        .agent_tcp_connection
-                        .response_tunnel_init(TunnelInitResponse::Success)
-                        .await?;
-
-                    let (agent_data_size, proxy_data_size) = copy_bidirectional(
-                        &mut agent_tcp_connection,
-                        &mut forward_proxy_tcp_connection,
-                    )
-                    .await?;
-                    debug!(
-                        "[FORWARDING] Copy data between agent and proxy, agent data size: {agent_data_size}, proxy data size: {proxy_data_size}"
+                if let Some(traffic_quota_manager) =
+                    self.server_state.get_value::<Arc<TrafficQuotaManager>>()
+                {
+                    traffic_quota_manager.record(
+                        &username,
+                        user_limits.traffic_quota.as_ref(),
+                        relay_result.a_data_size + relay_result.b_data_size,
                     );
-                    Ok(())
                 }
-            },
+                // The data relayed before the error is recorded into the quota
+                if let Some(e) = relay_error {
+                    return Err(e.into());
+                }
+                Ok(())
+            }
         }
     }
 }
@@ -156,6 +440,44 @@ pub async fn handle_agent_connection(
     agent_tcp_stream: TcpStream,
     agent_socket_address: SocketAddr,
 ) -> Result<(), CommonError> {
-    let tunnel = Tunnel::new(config, server_state, agent_tcp_stream, agent_socket_address).await?;
+    let tunnel = match Tunnel::new(
+        config.clone(),
+        server_state.clone(),
+        agent_tcp_stream,
+        agent_socket_address,
+    )
+    .await
+    {
+        Ok(tunnel) => tunnel,
+        Err(AgentHandshakeError {
+            error,
+            rejected_connection,
+        }) => {
+            let fallback = config.fallback_address().zip(rejected_connection);
+            if is_handshake_failure(&error, fallback.is_some())
+                && let Some(ban_tracker) = server_state.get_value::<Arc<BanTracker>>()
+            {
+                ban_tracker.record_failure(agent_socket_address.ip());
+            }
+            if let Some((fallback_address, (agent_tcp_stream, received_bytes))) = fallback {
+                debug!(
+                    "Hand over agent connection [{agent_socket_address}] to fallback server [{fallback_address}] because of handshake failure: {error:?}"
+                );
+                if let Err(e) = splice_to_fallback(
+                    agent_tcp_stream,
+                    received_bytes,
+                    fallback_address,
+                    config.as_ref(),
+                )
+                .await
+                {
+                    debug!(
+                        "Fail to relay agent connection [{agent_socket_address}] to fallback server [{fallback_address}]: {e:?}"
+                    );
+                }
+            }
+            return Err(error);
+        }
+    };
     tunnel.run().await
 }